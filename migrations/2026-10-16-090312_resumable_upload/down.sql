@@ -0,0 +1,2 @@
+ALTER TABLE file DROP COLUMN upload_offset;
+ALTER TABLE file DROP COLUMN upload_length;
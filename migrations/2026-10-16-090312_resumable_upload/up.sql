@@ -0,0 +1,4 @@
+-- only set for resumable (tus) uploads, where the file is sent in several
+-- requests and the total length is known upfront.
+ALTER TABLE file ADD COLUMN upload_length INTEGER;
+ALTER TABLE file ADD COLUMN upload_offset INTEGER;
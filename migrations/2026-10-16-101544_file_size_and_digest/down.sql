@@ -0,0 +1,2 @@
+ALTER TABLE file DROP COLUMN sha256;
+ALTER TABLE file RENAME COLUMN size_bytes TO size_mib;
@@ -0,0 +1,4 @@
+-- size_mib was never filled, store the exact size in bytes instead
+ALTER TABLE file RENAME COLUMN size_mib TO size_bytes;
+-- hex encoded sha256 of the content of the file
+ALTER TABLE file ADD COLUMN sha256 TEXT;
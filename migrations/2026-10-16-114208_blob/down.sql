@@ -0,0 +1,2 @@
+ALTER TABLE file DROP COLUMN blob_sha256;
+DROP TABLE blob;
@@ -0,0 +1,10 @@
+-- content addressed storage: files with the same content share a single
+-- blob on disk. A blob can be deleted once no file references it anymore.
+CREATE TABLE IF NOT EXISTS blob (
+  sha256 TEXT PRIMARY KEY NOT NULL,
+  path TEXT NOT NULL,
+  size_bytes INTEGER NOT NULL,
+  created_at DATETIME NOT NULL DEFAULT (datetime('now'))
+);
+
+ALTER TABLE file ADD COLUMN blob_sha256 TEXT REFERENCES blob(sha256);
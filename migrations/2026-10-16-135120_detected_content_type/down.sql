@@ -0,0 +1 @@
+ALTER TABLE file DROP COLUMN detected_content_type;
@@ -0,0 +1,3 @@
+-- content type detected from the content of the file, as opposed to
+-- content_type which is whatever the client declared.
+ALTER TABLE file ADD COLUMN detected_content_type TEXT;
@@ -0,0 +1 @@
+ALTER TABLE token DROP COLUMN allowed_types;
@@ -0,0 +1,3 @@
+-- comma separated list of content types accepted for this token, like
+-- `application/pdf,image/*`. NULL to accept anything.
+ALTER TABLE token ADD COLUMN allowed_types TEXT;
@@ -0,0 +1,23 @@
+CREATE TABLE token_old (
+  id INTEGER PRIMARY KEY NOT NULL,
+  path TEXT NOT NULL,
+  status TEXT CHECK(status in ('FRESH', 'USED', 'DELETED')) NOT NULL,
+  max_size_mib INTEGER,
+  created_at DATETIME NOT NULL DEFAULT (datetime('now')),
+  token_expires_at DATETIME NOT NULL,
+  content_expires_at DATETIME,
+  content_expires_after_hours INTEGER,
+  deleted_at DATETIME,
+  allowed_types TEXT
+);
+
+INSERT INTO token_old (id, path, status, max_size_mib, created_at, token_expires_at,
+  content_expires_at, content_expires_after_hours, deleted_at, allowed_types)
+SELECT id, path,
+  CASE status WHEN 'OPEN' THEN 'USED' WHEN 'CLOSED' THEN 'USED' ELSE status END,
+  max_size_mib, created_at, token_expires_at,
+  content_expires_at, content_expires_after_hours, deleted_at, allowed_types
+FROM token;
+
+DROP TABLE token;
+ALTER TABLE token_old RENAME TO token;
@@ -0,0 +1,25 @@
+-- open tokens accept uploads until they expire or until they hold
+-- max_files files, they are then closed.
+-- sqlite cannot change a CHECK constraint, so the table is rebuilt.
+CREATE TABLE token_new (
+  id INTEGER PRIMARY KEY NOT NULL,
+  path TEXT NOT NULL,
+  status TEXT CHECK(status in ('FRESH', 'USED', 'OPEN', 'CLOSED', 'DELETED')) NOT NULL,
+  max_size_mib INTEGER,
+  created_at DATETIME NOT NULL DEFAULT (datetime('now')),
+  token_expires_at DATETIME NOT NULL,
+  content_expires_at DATETIME,
+  content_expires_after_hours INTEGER,
+  deleted_at DATETIME,
+  allowed_types TEXT,
+  max_files INTEGER
+);
+
+INSERT INTO token_new (id, path, status, max_size_mib, created_at, token_expires_at,
+  content_expires_at, content_expires_after_hours, deleted_at, allowed_types)
+SELECT id, path, status, max_size_mib, created_at, token_expires_at,
+  content_expires_at, content_expires_after_hours, deleted_at, allowed_types
+FROM token;
+
+DROP TABLE token;
+ALTER TABLE token_new RENAME TO token;
@@ -0,0 +1,2 @@
+ALTER TABLE blob DROP COLUMN compression;
+ALTER TABLE file DROP COLUMN compression;
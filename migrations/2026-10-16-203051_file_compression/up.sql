@@ -0,0 +1,5 @@
+-- how the content is stored on disk, NULL when stored as is.
+-- For content addressed storage, the blob holds the same information so
+-- that new files reusing the blob know how to read it.
+ALTER TABLE file ADD COLUMN compression TEXT CHECK(compression IN ('ZSTD'));
+ALTER TABLE blob ADD COLUMN compression TEXT CHECK(compression IN ('ZSTD'));
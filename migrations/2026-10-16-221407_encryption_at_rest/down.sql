@@ -0,0 +1,2 @@
+ALTER TABLE blob DROP COLUMN wrapped_key;
+ALTER TABLE file DROP COLUMN wrapped_key;
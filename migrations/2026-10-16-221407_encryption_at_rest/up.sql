@@ -0,0 +1,4 @@
+-- the key encrypting the content, itself encrypted with the master key of
+-- the server, base64 encoded. NULL when the content is stored in plaintext.
+ALTER TABLE file ADD COLUMN wrapped_key TEXT;
+ALTER TABLE blob ADD COLUMN wrapped_key TEXT;
@@ -0,0 +1,3 @@
+-- the content was encrypted by the browser before the upload, the server
+-- only ever sees the ciphertext, and the name is encrypted as well.
+ALTER TABLE file ADD COLUMN encrypted BOOLEAN NOT NULL DEFAULT 0;
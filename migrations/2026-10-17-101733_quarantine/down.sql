@@ -0,0 +1,33 @@
+-- quarantined files must never be served, so they go back to the started state
+CREATE TABLE file_new (
+  id INTEGER PRIMARY KEY NOT NULL,
+  token_id INTEGER NOT NULL,
+  name TEXT,
+  path TEXT NOT NULL,
+  content_type TEXT,
+  size_bytes INTEGER,
+  created_at DATETIME NOT NULL DEFAULT (datetime('now')),
+  deleted_at DATETIME,
+  file_upload_status TEXT CHECK(file_upload_status in ('STARTED', 'COMPLETED')) NOT NULL,
+  upload_length INTEGER,
+  upload_offset INTEGER,
+  sha256 TEXT,
+  blob_sha256 TEXT REFERENCES blob(sha256),
+  detected_content_type TEXT,
+  compression TEXT CHECK(compression IN ('ZSTD')),
+  wrapped_key TEXT,
+  encrypted BOOLEAN NOT NULL DEFAULT 0,
+  FOREIGN KEY(token_id) REFERENCES token(id)
+);
+
+INSERT INTO file_new (id, token_id, name, path, content_type, size_bytes, created_at,
+  deleted_at, file_upload_status, upload_length, upload_offset, sha256, blob_sha256,
+  detected_content_type, compression, wrapped_key, encrypted)
+SELECT id, token_id, name, path, content_type, size_bytes, created_at,
+  deleted_at, REPLACE(file_upload_status, 'QUARANTINED', 'STARTED'), upload_length,
+  upload_offset, sha256, blob_sha256, detected_content_type, compression, wrapped_key,
+  encrypted
+FROM file;
+
+DROP TABLE file;
+ALTER TABLE file_new RENAME TO file;
@@ -0,0 +1,4 @@
+ALTER TABLE file DROP COLUMN original_wrapped_key;
+ALTER TABLE file DROP COLUMN original_path;
+ALTER TABLE file DROP COLUMN sanitized;
+ALTER TABLE token DROP COLUMN strip_metadata;
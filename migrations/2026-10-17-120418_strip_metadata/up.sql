@@ -0,0 +1,9 @@
+-- remove the EXIF, XMP and GPS data of the pictures uploaded with this token
+ALTER TABLE token ADD COLUMN strip_metadata BOOLEAN NOT NULL DEFAULT 0;
+-- the metadata of the picture was removed after the upload
+ALTER TABLE file ADD COLUMN sanitized BOOLEAN NOT NULL DEFAULT 0;
+-- where the picture as uploaded is kept for the admin, if it is
+ALTER TABLE file ADD COLUMN original_path TEXT;
+-- the key encrypting the original, wrapped like wrapped_key. The content
+-- of the file may end up encrypted with the key of an existing blob.
+ALTER TABLE file ADD COLUMN original_wrapped_key TEXT;
@@ -0,0 +1,18 @@
+-- the files and directories inside the uploaded zip and tar files
+CREATE TABLE archive_entry (
+  id INTEGER PRIMARY KEY NOT NULL,
+  file_id INTEGER NOT NULL REFERENCES file(id),
+  path TEXT NOT NULL,
+  size_bytes BIGINT NOT NULL,
+  modified_at DATETIME,
+  is_dir BOOLEAN NOT NULL,
+  -- where the entry starts in the archive: its local header in a zip
+  -- file, its content in a tar file
+  entry_offset BIGINT NOT NULL,
+  -- how many bytes the content takes in the archive
+  packed_size BIGINT NOT NULL,
+  -- the compression method of a zip entry, NULL for tar files
+  method INTEGER
+);
+
+CREATE INDEX archive_entry_file_id ON archive_entry(file_id);
@@ -0,0 +1,3 @@
+ALTER TABLE file DROP COLUMN download_count;
+ALTER TABLE token DROP COLUMN download_count;
+ALTER TABLE token DROP COLUMN max_downloads;
@@ -0,0 +1,7 @@
+-- the token is unavailable once its files have been downloaded that many
+-- times, and its content is removed by the next cleanup
+ALTER TABLE token ADD COLUMN max_downloads INTEGER;
+-- how many times the files of the token have been downloaded, each file
+-- or each archive of all the files counting once
+ALTER TABLE token ADD COLUMN download_count INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE file ADD COLUMN download_count INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1 @@
+ALTER TABLE archive_entry DROP COLUMN crc32;
@@ -0,0 +1,3 @@
+-- the checksum of a zip entry, to check what's extracted. NULL for tar
+-- files and for the entries listed before it was recorded
+ALTER TABLE archive_entry ADD COLUMN crc32 BIGINT;
@@ -0,0 +1,986 @@
+//! Zip and tar archives. All the files of a token can be downloaded as one,
+//! written as it's sent to the client: nothing is built on disk. The zip
+//! files are stored without compression, each entry followed by its
+//! checksum once its content went through.
+//! The entries of the uploaded archives are listed, so each one can be
+//! downloaded on its own.
+
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+
+use chrono::{Datelike, NaiveDateTime, Timelike};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Zip,
+    Tar,
+}
+
+impl Format {
+    pub fn content_type(&self) -> (&'static str, &'static str) {
+        match self {
+            Format::Zip => ("application", "zip"),
+            Format::Tar => ("application", "x-tar"),
+        }
+    }
+
+    /// the format of an uploaded file, from its detected content type
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        match content_type {
+            "application/zip" => Some(Format::Zip),
+            "application/x-tar" => Some(Format::Tar),
+            _ => None,
+        }
+    }
+}
+
+/// only that many entries of an archive are listed
+pub const MAX_ENTRIES: usize = 5000;
+/// the central directory of a zip file is read in memory, the archives
+/// with a bigger one are not listed.
+const MAX_DIRECTORY_SIZE: u64 = 16 * 1024 * 1024;
+/// the end of a zip file: the end of central directory record with its
+/// comment, and the zip64 records before it.
+const MAX_END_SIZE: u64 = 22 + 0xFFFF + 56 + 20;
+
+/// A file or a directory in an archive
+#[derive(Debug)]
+pub struct Entry {
+    /// as found in the archive, not necessarily valid
+    pub path: String,
+    pub size: u64,
+    pub modified: Option<NaiveDateTime>,
+    pub is_dir: bool,
+    /// where the entry starts in the archive: its local header in a zip
+    /// file, its content in a tar file.
+    pub offset: u64,
+    /// how many bytes the content takes in the archive
+    pub packed_size: u64,
+    /// how the content is compressed in a zip file, see
+    /// [`Entry::is_extractable`]. Always `None` in a tar file.
+    pub method: Option<u16>,
+    /// the checksum of the content in a zip file
+    pub crc32: Option<u32>,
+}
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATE: u16 = 8;
+
+impl Entry {
+    /// whether the content of the entry can be extracted
+    pub fn is_extractable(&self, format: Format) -> bool {
+        !self.is_dir
+            && match format {
+                Format::Zip => matches!(self.method, Some(METHOD_STORED | METHOD_DEFLATE)),
+                Format::Tar => true,
+            }
+    }
+}
+
+/// List the entries of an archive of `len` bytes, up to [`MAX_ENTRIES`].
+/// `open` gives the content of the archive from the given offset.
+pub fn list_entries<R, F>(format: Format, len: u64, open: F) -> io::Result<Vec<Entry>>
+where
+    R: Read,
+    F: Fn(u64) -> io::Result<R>,
+{
+    match format {
+        Format::Zip => list_zip(len, open),
+        Format::Tar => list_tar(open(0)?),
+    }
+}
+
+/// The content of an entry. `open` gives the content of the archive from
+/// the given offset.
+pub fn extract<R, F>(format: Format, entry: &Entry, open: F) -> io::Result<Box<dyn Read + Send>>
+where
+    R: Read + Send + 'static,
+    F: Fn(u64) -> io::Result<R>,
+{
+    if !entry.is_extractable(format) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Cannot extract {}", entry.path),
+        ));
+    }
+    let mut reader = open(entry.offset)?;
+    if format == Format::Tar {
+        return Ok(Box::new(reader.take(entry.size)));
+    }
+
+    let mut header = [0; 30];
+    reader.read_exact(&mut header)?;
+    let mut fields = Fields::new(&header);
+    if fields.u32()? != LOCAL_HEADER_SIGNATURE {
+        return Err(invalid_zip("no local header where expected"));
+    }
+    let mut fields = Fields::new(&header[26..]);
+    let skip = fields.u16()? as u64 + fields.u16()? as u64;
+    io::copy(&mut (&mut reader).take(skip), &mut io::sink())?;
+    let content = reader.take(entry.packed_size);
+    let content: Box<dyn Read + Send> = if entry.method == Some(METHOD_DEFLATE) {
+        Box::new(flate2::read::DeflateDecoder::new(content))
+    } else {
+        Box::new(content)
+    };
+    Ok(Box::new(CheckedContent {
+        // one more byte to tell when there is more than announced
+        inner: content.take(entry.size + 1),
+        size: entry.size,
+        read: 0,
+        hasher: crc32fast::Hasher::new(),
+        crc32: entry.crc32,
+        checked: false,
+    }))
+}
+
+/// The content of a zip entry, checked as it's read. It can't be bigger
+/// than the size from the central directory, a small compressed entry
+/// could otherwise expand to anything. It must also match that size and
+/// the checksum, which is known before the last bytes are given.
+struct CheckedContent<R> {
+    inner: io::Take<R>,
+    size: u64,
+    read: u64,
+    hasher: crc32fast::Hasher,
+    crc32: Option<u32>,
+    checked: bool,
+}
+
+impl<R: Read> Read for CheckedContent<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        self.hasher.update(&buf[..n]);
+        if n == 0 && !buf.is_empty() && self.read < self.size {
+            return Err(invalid_zip("entry smaller than its announced size"));
+        }
+        if self.read >= self.size && !self.checked {
+            if self.read > self.size || self.inner.read(&mut [0])? > 0 {
+                return Err(invalid_zip("entry bigger than its announced size"));
+            }
+            if let Some(crc32) = self.crc32 {
+                if self.hasher.clone().finalize() != crc32 {
+                    return Err(invalid_zip("entry doesn't match its checksum"));
+                }
+            }
+            self.checked = true;
+        }
+        Ok(n)
+    }
+}
+
+/// Reads the central directory at the end of the file, without going
+/// through the content of the entries.
+fn list_zip<R, F>(len: u64, open: F) -> io::Result<Vec<Entry>>
+where
+    R: Read,
+    F: Fn(u64) -> io::Result<R>,
+{
+    let tail_len = len.min(MAX_DIRECTORY_SIZE + MAX_END_SIZE);
+    let tail_start = len - tail_len;
+    let mut tail = Vec::with_capacity(tail_len as usize);
+    open(tail_start)?.take(tail_len).read_to_end(&mut tail)?;
+
+    // the end record is followed by a comment of any size
+    let end_pos = (0..=tail.len().saturating_sub(22))
+        .rev()
+        .find(|&i| tail[i..].starts_with(&END_SIGNATURE.to_le_bytes()))
+        .ok_or_else(|| invalid_zip("no end of central directory"))?;
+    let mut end = Fields::new(&tail[end_pos + 10..]);
+    let mut count = end.u16()? as u64;
+    let mut directory_size = end.u32()? as u64;
+    let mut directory_offset = end.u32()? as u64;
+
+    let has_locator =
+        end_pos >= 20 && Fields::new(&tail[end_pos - 20..]).u32()? == ZIP64_LOCATOR_SIGNATURE;
+    if has_locator {
+        let zip64_end_offset = Fields::new(&tail[end_pos - 12..]).u64()?;
+        let zip64_end = zip64_end_offset
+            .checked_sub(tail_start)
+            .and_then(|pos| tail.get(pos as usize..))
+            .ok_or_else(|| invalid_zip("zip64 end of central directory out of bounds"))?;
+        let mut zip64_end = Fields::new(zip64_end);
+        if zip64_end.u32()? != ZIP64_END_SIGNATURE {
+            return Err(invalid_zip("no zip64 end of central directory"));
+        }
+        // the record size, the versions, the disk numbers and the number of
+        // entries on this disk
+        zip64_end.skip(28)?;
+        count = zip64_end.u64()?;
+        directory_size = zip64_end.u64()?;
+        directory_offset = zip64_end.u64()?;
+    }
+
+    let directory = directory_offset
+        .checked_sub(tail_start)
+        .and_then(|start| Some((start, start.checked_add(directory_size)?)))
+        .and_then(|(start, end)| tail.get(start as usize..end as usize))
+        .ok_or_else(|| invalid_zip("central directory too big or out of bounds"))?;
+    let mut directory = Fields::new(directory);
+    let mut entries = Vec::new();
+    for _ in 0..count.min(MAX_ENTRIES as u64) {
+        if directory.u32()? != CENTRAL_HEADER_SIGNATURE {
+            return Err(invalid_zip("bad central directory header"));
+        }
+        directory.skip(4)?;
+        let flags = directory.u16()?;
+        let method = directory.u16()?;
+        let time = directory.u16()?;
+        let date = directory.u16()?;
+        let crc32 = directory.u32()?;
+        let mut packed_size = directory.u32()? as u64;
+        let mut size = directory.u32()? as u64;
+        let name_len = directory.u16()? as usize;
+        let extra_len = directory.u16()? as usize;
+        let comment_len = directory.u16()? as usize;
+        directory.skip(8)?;
+        let mut offset = directory.u32()? as u64;
+        let name = String::from_utf8_lossy(directory.bytes(name_len)?).into_owned();
+        let mut extra = Fields::new(directory.bytes(extra_len)?);
+        directory.skip(comment_len)?;
+
+        // the values too big for their field are in the zip64 extra field
+        while let (Ok(id), Ok(len)) = (extra.u16(), extra.u16()) {
+            let mut data = Fields::new(extra.bytes(len as usize)?);
+            if id != ZIP64_EXTRA_ID {
+                continue;
+            }
+            if size == ZIP64_LIMIT {
+                size = data.u64()?;
+            }
+            if packed_size == ZIP64_LIMIT {
+                packed_size = data.u64()?;
+            }
+            if offset == ZIP64_LIMIT {
+                offset = data.u64()?;
+            }
+        }
+
+        const FLAG_ENCRYPTED: u16 = 0x0001;
+        entries.push(Entry {
+            is_dir: name.ends_with('/'),
+            path: name,
+            size,
+            modified: from_dos_date_time(time, date),
+            offset,
+            packed_size,
+            method: (flags & FLAG_ENCRYPTED == 0).then_some(method),
+            crc32: Some(crc32),
+        });
+    }
+    Ok(entries)
+}
+
+/// Goes through the whole archive, there is no index in tar files.
+fn list_tar<R: Read>(reader: R) -> io::Result<Vec<Entry>> {
+    let mut archive = tar::Archive::new(reader);
+    let mut entries = Vec::new();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let header = entry.header();
+        let is_dir = match header.entry_type() {
+            tar::EntryType::Regular | tar::EntryType::Continuous => false,
+            tar::EntryType::Directory => true,
+            // links, devices and the like have no content
+            _ => continue,
+        };
+        entries.push(Entry {
+            path: String::from_utf8_lossy(&entry.path_bytes()).into_owned(),
+            size: entry.size(),
+            modified: header
+                .mtime()
+                .ok()
+                .and_then(|t| NaiveDateTime::from_timestamp_opt(t as i64, 0)),
+            is_dir,
+            offset: entry.raw_file_position(),
+            packed_size: entry.size(),
+            method: None,
+            crc32: None,
+        });
+        if entries.len() == MAX_ENTRIES {
+            break;
+        }
+    }
+    Ok(entries)
+}
+
+fn invalid_zip(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid zip file: {msg}"),
+    )
+}
+
+/// reads the little endian fields of a zip record one after the other
+struct Fields<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Fields { buf }
+    }
+
+    fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(invalid_zip("truncated record"));
+        }
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> io::Result<()> {
+        self.bytes(len).map(|_| ())
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+}
+
+/// Writes the files one after the other into an archive
+pub struct ArchiveWriter<W: Write> {
+    inner: Inner<W>,
+    /// lowercased, some file systems ignore the case
+    names: HashSet<String>,
+}
+
+enum Inner<W: Write> {
+    Zip(ZipWriter<W>),
+    Tar(tar::Builder<W>),
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(format: Format, out: W) -> Self {
+        let inner = match format {
+            Format::Zip => Inner::Zip(ZipWriter::new(out)),
+            Format::Tar => Inner::Tar(tar::Builder::new(out)),
+        };
+        ArchiveWriter {
+            inner,
+            names: HashSet::new(),
+        }
+    }
+
+    /// Add a file of `size` bytes, its content is read from `content`.
+    /// The name is made unique among the files of the archive.
+    pub fn append(
+        &mut self,
+        name: &str,
+        size: u64,
+        modified: NaiveDateTime,
+        content: &mut dyn Read,
+    ) -> io::Result<()> {
+        let name = self.unique_name(name);
+        match &mut self.inner {
+            Inner::Zip(zip) => zip.append(name, size, modified, content),
+            Inner::Tar(tar) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_size(size);
+                header.set_mode(0o644);
+                header.set_mtime(modified.timestamp().max(0) as u64);
+                let mut content = content.take(size);
+                tar.append_data(&mut header, &name, &mut content)?;
+                if content.limit() > 0 {
+                    return Err(unexpected_size(&name));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// write what comes after the last file, and returns the output
+    pub fn finish(self) -> io::Result<W> {
+        match self.inner {
+            Inner::Zip(zip) => zip.finish(),
+            Inner::Tar(tar) => tar.into_inner(),
+        }
+    }
+
+    /// Names come from the uploaders, they are reduced to a file name
+    /// without directories, and numbered when already taken:
+    /// `photo.jpg`, `photo (1).jpg`…
+    fn unique_name(&mut self, name: &str) -> String {
+        let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+        let name: String = name.chars().filter(|c| !c.is_control()).collect();
+        let name = match name.trim() {
+            "" | "." | ".." => "file",
+            name => name,
+        };
+        let (stem, ext) = match name.rfind('.') {
+            Some(i) if i > 0 => name.split_at(i),
+            _ => (name, ""),
+        };
+        let mut candidate = name.to_string();
+        let mut n = 0;
+        while !self.names.insert(candidate.to_lowercase()) {
+            n += 1;
+            candidate = format!("{stem} ({n}){ext}");
+        }
+        candidate
+    }
+}
+
+fn unexpected_size(name: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{name} isn't the size it should be"),
+    )
+}
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x08074b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x02014b50;
+const ZIP64_END_SIGNATURE: u32 = 0x06064b50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const END_SIGNATURE: u32 = 0x06054b50;
+
+/// the sizes and checksum follow the content
+const FLAG_DATA_DESCRIPTOR: u16 = 0x0008;
+const FLAG_UTF8_NAME: u16 = 0x0800;
+/// bigger sizes and offsets go in the zip64 extra field
+const ZIP64_LIMIT: u64 = u32::MAX as u64;
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+const VERSION_DEFAULT: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+/// made on unix, so the permissions are taken into account
+const VERSION_MADE_BY: u16 = (3 << 8) | VERSION_ZIP64;
+
+/// A zip file written in one go, without seeking back
+struct ZipWriter<W> {
+    out: W,
+    /// how many bytes were written so far
+    offset: u64,
+    entries: Vec<ZipEntry>,
+}
+
+struct ZipEntry {
+    name: String,
+    crc: u32,
+    size: u64,
+    offset: u64,
+    /// MS-DOS format
+    time: u16,
+    date: u16,
+}
+
+impl ZipEntry {
+    /// the local header and the data descriptor have the 64 bits sizes
+    fn is_zip64(&self) -> bool {
+        self.size >= ZIP64_LIMIT
+    }
+
+    fn version_needed(&self) -> u16 {
+        if self.is_zip64() || self.offset >= ZIP64_LIMIT {
+            VERSION_ZIP64
+        } else {
+            VERSION_DEFAULT
+        }
+    }
+}
+
+impl<W: Write> ZipWriter<W> {
+    fn new(out: W) -> Self {
+        ZipWriter {
+            out,
+            offset: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.out.write_all(buf)?;
+        self.offset += buf.len() as u64;
+        Ok(())
+    }
+
+    fn append(
+        &mut self,
+        name: String,
+        size: u64,
+        modified: NaiveDateTime,
+        content: &mut dyn Read,
+    ) -> io::Result<()> {
+        let (time, date) = dos_date_time(modified);
+        let mut entry = ZipEntry {
+            name,
+            crc: 0,
+            size,
+            offset: self.offset,
+            time,
+            date,
+        };
+
+        let mut header = Vec::with_capacity(30 + entry.name.len() + 20);
+        put_u32(&mut header, LOCAL_HEADER_SIGNATURE);
+        put_u16(&mut header, entry.version_needed());
+        put_u16(&mut header, FLAG_DATA_DESCRIPTOR | FLAG_UTF8_NAME);
+        // stored, no compression
+        put_u16(&mut header, 0);
+        put_u16(&mut header, entry.time);
+        put_u16(&mut header, entry.date);
+        // the checksum and sizes are in the data descriptor
+        put_u32(&mut header, 0);
+        let sizes = if entry.is_zip64() { u32::MAX } else { 0 };
+        put_u32(&mut header, sizes);
+        put_u32(&mut header, sizes);
+        put_u16(&mut header, entry.name.len() as u16);
+        if entry.is_zip64() {
+            put_u16(&mut header, 20);
+            header.extend_from_slice(entry.name.as_bytes());
+            put_u16(&mut header, ZIP64_EXTRA_ID);
+            put_u16(&mut header, 16);
+            put_u64(&mut header, 0);
+            put_u64(&mut header, 0);
+        } else {
+            put_u16(&mut header, 0);
+            header.extend_from_slice(entry.name.as_bytes());
+        }
+        self.write(&header)?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        let mut written = 0;
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = content.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            self.write(&buf[..n])?;
+            written += n as u64;
+        }
+        if written != size {
+            return Err(unexpected_size(&entry.name));
+        }
+        entry.crc = hasher.finalize();
+
+        let mut descriptor = Vec::with_capacity(24);
+        put_u32(&mut descriptor, DATA_DESCRIPTOR_SIGNATURE);
+        put_u32(&mut descriptor, entry.crc);
+        if entry.is_zip64() {
+            put_u64(&mut descriptor, entry.size);
+            put_u64(&mut descriptor, entry.size);
+        } else {
+            put_u32(&mut descriptor, entry.size as u32);
+            put_u32(&mut descriptor, entry.size as u32);
+        }
+        self.write(&descriptor)?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// write the central directory, listing all the entries
+    fn finish(mut self) -> io::Result<W> {
+        let directory_offset = self.offset;
+        let mut directory = Vec::new();
+        for entry in &self.entries {
+            let mut extra = Vec::new();
+            if entry.is_zip64() {
+                put_u64(&mut extra, entry.size);
+                put_u64(&mut extra, entry.size);
+            }
+            if entry.offset >= ZIP64_LIMIT {
+                put_u64(&mut extra, entry.offset);
+            }
+            let extra_len = if extra.is_empty() { 0 } else { 4 + extra.len() };
+
+            put_u32(&mut directory, CENTRAL_HEADER_SIGNATURE);
+            put_u16(&mut directory, VERSION_MADE_BY);
+            put_u16(&mut directory, entry.version_needed());
+            put_u16(&mut directory, FLAG_DATA_DESCRIPTOR | FLAG_UTF8_NAME);
+            put_u16(&mut directory, 0);
+            put_u16(&mut directory, entry.time);
+            put_u16(&mut directory, entry.date);
+            put_u32(&mut directory, entry.crc);
+            put_u32(&mut directory, entry.size.min(ZIP64_LIMIT) as u32);
+            put_u32(&mut directory, entry.size.min(ZIP64_LIMIT) as u32);
+            put_u16(&mut directory, entry.name.len() as u16);
+            put_u16(&mut directory, extra_len as u16);
+            // comment length, disk number, internal attributes
+            put_u16(&mut directory, 0);
+            put_u16(&mut directory, 0);
+            put_u16(&mut directory, 0);
+            // regular file, rw-r--r--
+            put_u32(&mut directory, 0o100644 << 16);
+            put_u32(&mut directory, entry.offset.min(ZIP64_LIMIT) as u32);
+            directory.extend_from_slice(entry.name.as_bytes());
+            if !extra.is_empty() {
+                put_u16(&mut directory, ZIP64_EXTRA_ID);
+                put_u16(&mut directory, extra.len() as u16);
+                directory.extend_from_slice(&extra);
+            }
+        }
+        self.write(&directory)?;
+        let directory_size = directory.len() as u64;
+        let count = self.entries.len() as u64;
+
+        let mut end = Vec::new();
+        if count >= u16::MAX as u64
+            || directory_offset >= ZIP64_LIMIT
+            || directory_size >= ZIP64_LIMIT
+        {
+            let zip64_end_offset = self.offset;
+            put_u32(&mut end, ZIP64_END_SIGNATURE);
+            // size of the rest of this record
+            put_u64(&mut end, 44);
+            put_u16(&mut end, VERSION_MADE_BY);
+            put_u16(&mut end, VERSION_ZIP64);
+            put_u32(&mut end, 0);
+            put_u32(&mut end, 0);
+            put_u64(&mut end, count);
+            put_u64(&mut end, count);
+            put_u64(&mut end, directory_size);
+            put_u64(&mut end, directory_offset);
+
+            put_u32(&mut end, ZIP64_LOCATOR_SIGNATURE);
+            put_u32(&mut end, 0);
+            put_u64(&mut end, zip64_end_offset);
+            put_u32(&mut end, 1);
+        }
+        put_u32(&mut end, END_SIGNATURE);
+        put_u16(&mut end, 0);
+        put_u16(&mut end, 0);
+        put_u16(&mut end, count.min(u16::MAX as u64) as u16);
+        put_u16(&mut end, count.min(u16::MAX as u64) as u16);
+        put_u32(&mut end, directory_size.min(ZIP64_LIMIT) as u32);
+        put_u32(&mut end, directory_offset.min(ZIP64_LIMIT) as u32);
+        // comment length
+        put_u16(&mut end, 0);
+        self.write(&end)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// zip files don't know about time zones, nor about anything before 1980
+/// or after 2107
+fn dos_date_time(datetime: NaiveDateTime) -> (u16, u16) {
+    if !(1980..=2107).contains(&datetime.year()) {
+        return (0, (1 << 5) | 1);
+    }
+    let time = (datetime.hour() << 11) | (datetime.minute() << 5) | (datetime.second() / 2);
+    let date = (((datetime.year() - 1980) as u32) << 9) | (datetime.month() << 5) | datetime.day();
+    (time as u16, date as u16)
+}
+
+fn from_dos_date_time(time: u16, date: u16) -> Option<NaiveDateTime> {
+    let date = chrono::NaiveDate::from_ymd_opt(
+        1980 + (date >> 9) as i32,
+        ((date >> 5) & 0xF) as u32,
+        (date & 0x1F) as u32,
+    )?;
+    date.and_hms_opt(
+        (time >> 11) as u32,
+        ((time >> 5) & 0x3F) as u32,
+        (time & 0x1F) as u32 * 2,
+    )
+}
+
+fn put_u16(buf: &mut Vec<u8>, n: u16) {
+    buf.extend_from_slice(&n.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, n: u32) {
+    buf.extend_from_slice(&n.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, n: u64) {
+    buf.extend_from_slice(&n.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn date(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    /// list the zip file in `bytes`, as if it came `base` bytes into a
+    /// bigger file
+    fn list_at(base: u64, bytes: &[u8]) -> Vec<Entry> {
+        list_zip(base + bytes.len() as u64, |pos| {
+            let padding = io::repeat(0).take(base.saturating_sub(pos));
+            let skip = pos.saturating_sub(base) as usize;
+            Ok(padding.chain(Cursor::new(bytes[skip..].to_vec())))
+        })
+        .unwrap()
+    }
+
+    fn extract_all(format: Format, entry: &Entry, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let bytes = bytes.to_vec();
+        let mut content = extract(format, entry, |pos| {
+            Ok(Cursor::new(bytes[pos as usize..].to_vec()))
+        })?;
+        let mut out = Vec::new();
+        content.read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    /// a zip entry compressed with deflate, its local header first
+    fn deflated(content: &[u8]) -> (Vec<u8>, u64) {
+        let mut encoder =
+            flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(content).unwrap();
+        let packed = encoder.finish().unwrap();
+        let mut zip = Vec::new();
+        put_u32(&mut zip, LOCAL_HEADER_SIGNATURE);
+        zip.extend_from_slice(&[0; 26]);
+        zip.extend_from_slice(&packed);
+        (zip, packed.len() as u64)
+    }
+
+    fn deflated_entry(size: u64, packed_size: u64, crc32: u32) -> Entry {
+        Entry {
+            path: "a.txt".to_string(),
+            size,
+            modified: None,
+            is_dir: false,
+            offset: 0,
+            packed_size,
+            method: Some(METHOD_DEFLATE),
+            crc32: Some(crc32),
+        }
+    }
+
+    #[test]
+    fn zip_round_trip() {
+        let modified = date("2021-06-03 14:25:38");
+        let files: [(&str, &[u8]); 3] = [
+            ("a.txt", b"hello"),
+            ("empty", b""),
+            ("b.bin", &[0xAB; 100_000]),
+        ];
+        let mut writer = ArchiveWriter::new(Format::Zip, Vec::new());
+        for (name, content) in files {
+            writer
+                .append(name, content.len() as u64, modified, &mut &content[..])
+                .unwrap();
+        }
+        let zip = writer.finish().unwrap();
+
+        let entries = list_entries(Format::Zip, zip.len() as u64, |pos| {
+            Ok(Cursor::new(&zip[pos as usize..]))
+        })
+        .unwrap();
+        assert_eq!(entries.len(), files.len());
+        for ((name, content), entry) in files.iter().zip(&entries) {
+            assert_eq!(entry.path, *name);
+            assert_eq!(entry.size, content.len() as u64);
+            assert_eq!(entry.crc32, Some(crc32fast::hash(content)));
+            assert!(entry.is_extractable(Format::Zip));
+            assert_eq!(extract_all(Format::Zip, entry, &zip).unwrap(), *content);
+        }
+    }
+
+    #[test]
+    fn tar_round_trip() {
+        let modified = date("2021-06-03 14:25:38");
+        let mut writer = ArchiveWriter::new(Format::Tar, Vec::new());
+        writer
+            .append("a.txt", 5, modified, &mut &b"hello"[..])
+            .unwrap();
+        writer
+            .append("b.txt", 3, modified, &mut &b"bye"[..])
+            .unwrap();
+        let tar = writer.finish().unwrap();
+
+        let entries = list_entries(Format::Tar, tar.len() as u64, |pos| {
+            Ok(Cursor::new(&tar[pos as usize..]))
+        })
+        .unwrap();
+        let names: Vec<_> = entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(names, ["a.txt", "b.txt"]);
+        assert_eq!(entries[0].modified, Some(modified));
+        assert_eq!(extract_all(Format::Tar, &entries[1], &tar).unwrap(), b"bye");
+    }
+
+    #[test]
+    fn deflated_entry_is_extracted() {
+        let content = b"hello hello hello hello";
+        let (zip, packed_size) = deflated(content);
+        let entry = deflated_entry(content.len() as u64, packed_size, crc32fast::hash(content));
+        assert_eq!(extract_all(Format::Zip, &entry, &zip).unwrap(), content);
+    }
+
+    #[test]
+    fn deflated_entry_cannot_expand_past_its_size() {
+        let content = vec![0; 10 * 1024 * 1024];
+        let (zip, packed_size) = deflated(&content);
+        let entry = deflated_entry(1024, packed_size, crc32fast::hash(&content[..1024]));
+        let err = extract_all(Format::Zip, &entry, &zip).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn entry_smaller_than_announced_is_refused() {
+        let content = b"hello";
+        let (zip, packed_size) = deflated(content);
+        let entry = deflated_entry(6, packed_size, crc32fast::hash(content));
+        let err = extract_all(Format::Zip, &entry, &zip).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn entry_not_matching_its_checksum_is_refused() {
+        let content = b"hello";
+        let (zip, packed_size) = deflated(content);
+        let entry = deflated_entry(5, packed_size, crc32fast::hash(b"hellO"));
+        let err = extract_all(Format::Zip, &entry, &zip).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn zip64_offsets() {
+        // the entries after the first 4 GiB are found through the zip64
+        // extra field, and the directory through the zip64 end records
+        let base = ZIP64_LIMIT - 10;
+        let mut zip = ZipWriter {
+            out: Vec::new(),
+            offset: base,
+            entries: Vec::new(),
+        };
+        let modified = date("2021-06-03 14:25:38");
+        zip.append("before".to_string(), 3, modified, &mut &b"abc"[..])
+            .unwrap();
+        zip.append("after".to_string(), 3, modified, &mut &b"def"[..])
+            .unwrap();
+        let out = zip.finish().unwrap();
+
+        let entries = list_at(base, &out);
+        let offsets: Vec<_> = entries.iter().map(|e| e.offset).collect();
+        assert_eq!(offsets, [base, base + 30 + 6 + 3 + 16]);
+        assert_eq!(entries[1].size, 3);
+    }
+
+    #[test]
+    fn zip64_directory_out_of_bounds() {
+        let base = ZIP64_LIMIT;
+        let zip = ZipWriter {
+            out: Vec::new(),
+            offset: base,
+            entries: Vec::new(),
+        };
+        let mut out = zip.finish().unwrap();
+        // the size of the directory in the zip64 end record, followed by
+        // the locator and the end record
+        let size_pos = out.len() - 22 - 20 - 56 + 40;
+        out[size_pos..size_pos + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+
+        let listed = list_zip(base + out.len() as u64, |pos| {
+            let padding = io::repeat(0).take(base.saturating_sub(pos));
+            let skip = pos.saturating_sub(base) as usize;
+            Ok(padding.chain(Cursor::new(out[skip..].to_vec())))
+        });
+        assert_eq!(listed.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn zip64_sizes() {
+        // too big to be written here, only the central directory is
+        let entry = |name: &str, size, offset| ZipEntry {
+            name: name.to_string(),
+            crc: 0,
+            size,
+            offset,
+            time: 0,
+            date: (1 << 5) | 1,
+        };
+        let base = 12 << 30;
+        let zip = ZipWriter {
+            out: Vec::new(),
+            offset: base,
+            entries: vec![
+                entry("below", ZIP64_LIMIT - 1, 0),
+                entry("limit", ZIP64_LIMIT, ZIP64_LIMIT - 1),
+                entry("above", 4 << 30, 8 << 30),
+            ],
+        };
+        let out = zip.finish().unwrap();
+
+        let entries = list_at(base, &out);
+        let found: Vec<_> = entries
+            .iter()
+            .map(|e| (e.path.as_str(), e.size, e.packed_size, e.offset))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("below", ZIP64_LIMIT - 1, ZIP64_LIMIT - 1, 0),
+                ("limit", ZIP64_LIMIT, ZIP64_LIMIT, ZIP64_LIMIT - 1),
+                ("above", 4 << 30, 4 << 30, 8 << 30),
+            ]
+        );
+    }
+
+    #[test]
+    fn unique_names() {
+        let mut writer = ArchiveWriter::new(Format::Tar, io::sink());
+        let names = [
+            ("photo.jpg", "photo.jpg"),
+            ("Photo.JPG", "Photo (1).JPG"),
+            ("photo.jpg", "photo (2).jpg"),
+            ("photo (1).jpg", "photo (1) (1).jpg"),
+            ("dir/photo.jpg", "photo (3).jpg"),
+            ("..\\..\\notes", "notes"),
+            ("..", "file"),
+            ("", "file (1)"),
+            (".bashrc", ".bashrc"),
+            (".bashrc", ".bashrc (1)"),
+            ("a\nb.txt", "ab.txt"),
+        ];
+        for (name, expected) in names {
+            assert_eq!(writer.unique_name(name), expected);
+        }
+    }
+
+    #[test]
+    fn zip_entries_are_listed() {
+        let modified = date("2021-06-03 14:25:38");
+        let mut writer = ArchiveWriter::new(Format::Zip, Vec::new());
+        writer
+            .append("a.txt", 5, modified, &mut &b"hello"[..])
+            .unwrap();
+        writer
+            .append("dir/A.txt", 0, modified, &mut io::empty())
+            .unwrap();
+        let zip = writer.finish().unwrap();
+
+        let entries = list_at(0, &zip);
+        let names: Vec<_> = entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(names, ["a.txt", "A (1).txt"]);
+        assert_eq!(entries[0].size, 5);
+        assert_eq!(entries[0].packed_size, 5);
+        assert_eq!(entries[0].offset, 0);
+        assert_eq!(entries[0].modified, Some(modified));
+        assert_eq!(entries[0].method, Some(METHOD_STORED));
+        assert_eq!(entries[1].size, 0);
+        // local header, name, content and data descriptor of the first one
+        assert_eq!(entries[1].offset, 30 + 5 + 5 + 16);
+    }
+
+    #[test]
+    fn content_of_the_wrong_size_is_refused() {
+        let modified = date("2021-06-03 14:25:38");
+        for format in [Format::Zip, Format::Tar] {
+            let mut writer = ArchiveWriter::new(format, io::sink());
+            let err = writer
+                .append("a.txt", 10, modified, &mut &b"hello"[..])
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
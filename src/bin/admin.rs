@@ -1,8 +1,12 @@
 use clap::Parser;
+use std::path::PathBuf;
 use std::{env::VarError, error::Error};
 
 use vrac::cleanup;
 use vrac::db;
+use vrac::encoding;
+use vrac::encryption::{self, MasterKey};
+use vrac::storage::Storage;
 
 /// Utility binary to manage the users, files and other useful stuff like that.
 #[derive(Debug, Parser)]
@@ -19,6 +23,63 @@ enum SubCommand {
         /// defaults to DATABASE_URL env variable if not provided
         #[clap(short, long)]
         database_url: Option<String>,
+
+        /// where the files are stored, defaults to the root_path of the
+        /// server configuration (Rocket.toml or ROCKET_ROOT_PATH)
+        #[clap(short, long)]
+        root_path: Option<PathBuf>,
+    },
+    /// Print a new random key, to use as master_key
+    GenKey,
+    /// Encrypt the keys of the encrypted files with a new master key.
+    /// The content of the files is not touched. Stop the server first,
+    /// and restart it with the new key afterward.
+    RotateKey {
+        /// defaults to DATABASE_URL env variable if not provided
+        #[clap(short, long)]
+        database_url: Option<String>,
+
+        /// the current master key, defaults to the master_key of the
+        /// server configuration (Rocket.toml or ROCKET_MASTER_KEY)
+        #[clap(long)]
+        old_key: Option<String>,
+
+        /// the master key replacing the current one, see gen-key
+        #[clap(long)]
+        new_key: String,
+    },
+    /// List the files flagged by the malware scanner, which are never served.
+    /// They are removed along the other files of their token.
+    Quarantined {
+        /// defaults to DATABASE_URL env variable if not provided
+        #[clap(short, long)]
+        database_url: Option<String>,
+    },
+    /// List the tokens which can still be used, with how many times their
+    /// files have been downloaded.
+    Downloads {
+        /// defaults to DATABASE_URL env variable if not provided
+        #[clap(short, long)]
+        database_url: Option<String>,
+    },
+    /// Write a picture as it was uploaded, before its metadata was removed.
+    /// Only kept with `keep_originals` in the server configuration.
+    Original {
+        /// the id of the file, as in its download link
+        file_id: i32,
+
+        /// where to write the picture
+        #[clap(short, long)]
+        output: PathBuf,
+
+        /// defaults to DATABASE_URL env variable if not provided
+        #[clap(short, long)]
+        database_url: Option<String>,
+
+        /// where the files are stored, defaults to the root_path of the
+        /// server configuration (Rocket.toml or ROCKET_ROOT_PATH)
+        #[clap(short, long)]
+        root_path: Option<PathBuf>,
     },
     GenUser {
         #[clap(short, long)]
@@ -39,7 +100,27 @@ fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
 
     match Opts::parse().cmd {
-        SubCommand::Cleanup { database_url } => cleanup(database_url),
+        SubCommand::Cleanup {
+            database_url,
+            root_path,
+        } => cleanup(database_url, root_path),
+        SubCommand::GenKey => {
+            println!("{}", encryption::generate_key());
+            Ok(())
+        }
+        SubCommand::RotateKey {
+            database_url,
+            old_key,
+            new_key,
+        } => rotate_key(database_url, old_key, new_key),
+        SubCommand::Quarantined { database_url } => quarantined(database_url),
+        SubCommand::Downloads { database_url } => downloads(database_url),
+        SubCommand::Original {
+            file_id,
+            output,
+            database_url,
+            root_path,
+        } => original(database_url, root_path, file_id, output),
         SubCommand::GenUser {
             username,
             password,
@@ -48,10 +129,97 @@ fn main() -> Result<(), Box<dyn Error>> {
     }
 }
 
-fn cleanup(database_url: Option<String>) -> Result<(), Box<dyn Error>> {
+fn cleanup(database_url: Option<String>, root_path: Option<PathBuf>) -> Result<(), Box<dyn Error>> {
     let db_url = get_db_url(database_url)?;
+    let root_path = get_root_path(root_path)?;
     let conn = db::connect(&db_url)?;
-    cleanup::cleanup_once(&conn)?;
+    let storage = Storage::new(&root_path)?;
+    cleanup::cleanup_once(&conn, &storage)?;
+    Ok(())
+}
+
+fn rotate_key(
+    database_url: Option<String>,
+    old_key: Option<String>,
+    new_key: String,
+) -> Result<(), Box<dyn Error>> {
+    let db_url = get_db_url(database_url)?;
+    let old_key = match old_key {
+        Some(k) => k,
+        None => rocket::Config::figment().extract_inner("master_key")?,
+    };
+    let old_key = MasterKey::from_base64(&old_key)?;
+    let new_key = MasterKey::from_base64(&new_key)?;
+    let conn = db::connect(&db_url)?;
+    let count = db::rewrap_keys(&conn, |wrapped| old_key.rewrap(wrapped, &new_key))?;
+    println!("Rotated the keys of {count} files, originals and blobs");
+    Ok(())
+}
+
+fn quarantined(database_url: Option<String>) -> Result<(), Box<dyn Error>> {
+    let db_url = get_db_url(database_url)?;
+    let conn = db::connect(&db_url)?;
+    for (file, token) in db::get_quarantined_files(&conn)? {
+        println!(
+            "/f/{}\t{}\t{}\t{}",
+            token.path,
+            file.name.as_deref().unwrap_or("<no name>"),
+            file.path,
+            file.quarantine_reason.as_deref().unwrap_or("")
+        );
+    }
+    Ok(())
+}
+
+fn downloads(database_url: Option<String>) -> Result<(), Box<dyn Error>> {
+    let db_url = get_db_url(database_url)?;
+    let conn = db::connect(&db_url)?;
+    for token in db::get_valid_tokens(&conn)? {
+        let limit = match token.max_downloads {
+            Some(max) => format!("{}/{max}", token.download_count),
+            None => token.download_count.to_string(),
+        };
+        println!("/f/{}\t{limit}", token.path);
+        for file in db::get_files(&conn, &token)? {
+            println!(
+                "\t{}\t{}\t{}",
+                file.id,
+                file.name.as_deref().unwrap_or("<no name>"),
+                file.download_count
+            );
+        }
+    }
+    Ok(())
+}
+
+fn original(
+    database_url: Option<String>,
+    root_path: Option<PathBuf>,
+    file_id: i32,
+    output: PathBuf,
+) -> Result<(), Box<dyn Error>> {
+    let db_url = get_db_url(database_url)?;
+    let conn = db::connect(&db_url)?;
+    let file = db::get_file_by_id(&conn, file_id)?.ok_or("No file with this id")?;
+    let original_path = file
+        .original_path
+        .ok_or("The original of this file wasn't kept")?;
+    let key = match &file.original_wrapped_key {
+        Some(wrapped_key) => {
+            let master_key: String = rocket::Config::figment().extract_inner("master_key")?;
+            Some(MasterKey::from_base64(&master_key)?.unwrap(wrapped_key)?)
+        }
+        None => None,
+    };
+    let storage = Storage::new(&get_root_path(root_path)?)?;
+    // the original is never compressed
+    let mut reader = encoding::decoder(storage.open(&original_path)?, key.as_ref(), None)?;
+    let mut out = std::fs::File::create(&output)?;
+    std::io::copy(&mut reader, &mut out)?;
+    println!(
+        "Wrote the original of file {file_id} to {}",
+        output.display()
+    );
     Ok(())
 }
 
@@ -66,6 +234,13 @@ fn gen_user(
     Ok(())
 }
 
+fn get_root_path(root_path: Option<PathBuf>) -> Result<PathBuf, Box<dyn Error>> {
+    match root_path {
+        Some(p) => Ok(p),
+        None => Ok(rocket::Config::figment().extract_inner("root_path")?),
+    }
+}
+
 fn get_db_url(database_url: Option<String>) -> Result<String, Box<dyn Error>> {
     match database_url {
         Some(x) => Ok(x),
@@ -4,39 +4,169 @@ use rocket::form::{Form, FromForm};
 use rocket::outcome::Outcome;
 use rocket::request::FlashMessage;
 use rocket::response::{Flash, Redirect, Responder};
+use rocket::serde::json::Json;
 use rocket::serde::{de::Error, Deserialize, Deserializer, Serialize};
 use rocket::tokio::sync::Mutex;
-use rocket::tokio::{fs, io::AsyncWriteExt};
+use rocket::tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
 use rocket::{http, request, response};
 use rocket_dyn_templates::Template;
 use rocket_sync_db_pools::database;
 use scrypt::password_hash::{PasswordHash, PasswordVerifier};
 use scrypt::Scrypt;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
 use tokio_util::codec;
 
-use multer::{Constraints, Field, Multipart, SizeLimit};
+use multer::{Constraints, Multipart, SizeLimit};
 
 use anyhow::Context;
 
+use vrac::archive::{self, ArchiveWriter};
 use vrac::cleanup;
+use vrac::content_type;
 use vrac::db;
+use vrac::encoding;
+use vrac::encryption::{self, DataKey, Encryptor, MasterKey};
 use vrac::errors;
+use vrac::preview::{self, Highlighter};
+use vrac::range::{self, ByteRange};
+use vrac::sanitize;
+use vrac::scanner::{ScannerConfig, Verdict};
+use vrac::storage::{self, Storage};
+use vrac::thumbnail;
 
 #[derive(Debug, Deserialize)]
 #[serde(crate = "rocket::serde")]
 struct VracConfig {
     root_path: PathBuf,
+    #[serde(default)]
+    storage: StorageMode,
+    /// files with these extensions are refused, whatever the token
+    #[serde(default = "default_blocked_extensions")]
+    blocked_extensions: Vec<String>,
+    /// files detected as one of these types are refused whatever their
+    /// name, `notes.txt` may well be a web page.
+    #[serde(default = "default_blocked_types")]
+    blocked_types: Vec<String>,
+    /// store files zstd compressed when that saves space
+    #[serde(default)]
+    compress: bool,
+    /// when set, new files are stored encrypted
+    #[serde(default, deserialize_with = "deserialize_master_key")]
+    master_key: Option<MasterKey>,
+    /// when set, new files are scanned before they can be downloaded
+    #[serde(default)]
+    scanner: Option<ScannerConfig>,
+    /// remove the metadata of the pictures uploaded with any token
+    #[serde(default)]
+    strip_metadata: bool,
+    /// keep the pictures as uploaded, metadata included, for the admin
+    #[serde(default)]
+    keep_originals: bool,
 }
 
 impl Default for VracConfig {
     fn default() -> Self {
         Self {
             root_path: std::env::current_dir().expect("Cannot access current dir???"),
+            storage: StorageMode::default(),
+            blocked_extensions: default_blocked_extensions(),
+            blocked_types: default_blocked_types(),
+            compress: false,
+            master_key: None,
+            scanner: None,
+            strip_metadata: false,
+            keep_originals: false,
         }
     }
 }
 
+impl VracConfig {
+    /// a key to encrypt a new file, along its wrapped form to store in the
+    /// DB, or `None` when encryption isn't enabled.
+    fn new_file_key(&self) -> errors::Result<Option<(DataKey, String)>> {
+        match &self.master_key {
+            Some(master_key) => Ok(Some(master_key.new_data_key()?)),
+            None => Ok(None),
+        }
+    }
+
+    /// the key to read an encrypted file
+    fn file_key(&self, wrapped_key: &str) -> errors::Result<DataKey> {
+        match &self.master_key {
+            Some(master_key) => Ok(master_key.unwrap(wrapped_key)?),
+            None => Err(anyhow::anyhow!("Encrypted file, but no master key configured").into()),
+        }
+    }
+
+    fn is_blocked(&self, file_name: &str) -> bool {
+        // windows ignores trailing dots and spaces, so `foo.exe.` is still an exe
+        let file_name = file_name.trim_end_matches(['.', ' ']);
+        match Path::new(file_name).extension() {
+            Some(ext) => {
+                let ext = ext.to_string_lossy();
+                self.blocked_extensions
+                    .iter()
+                    .any(|blocked| blocked.trim_start_matches('.').eq_ignore_ascii_case(&ext))
+            }
+            None => false,
+        }
+    }
+
+    fn is_blocked_type(&self, content_type: &str) -> bool {
+        self.blocked_types
+            .iter()
+            .any(|blocked| content_type::matches(blocked, content_type))
+    }
+}
+
+fn deserialize_master_key<'de, D>(deserializer: D) -> Result<Option<MasterKey>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let encoded: Option<String> = Deserialize::deserialize(deserializer)?;
+    encoded
+        .map(|k| MasterKey::from_base64(&k).map_err(D::Error::custom))
+        .transpose()
+}
+
+fn default_blocked_extensions() -> Vec<String> {
+    [
+        "exe", "scr", "com", "bat", "cmd", "msi", "pif", "vbs", "html", "htm", "xhtml",
+    ]
+    .iter()
+    .map(|ext| ext.to_string())
+    .collect()
+}
+
+/// the types of the blocked extensions, as detected
+fn default_blocked_types() -> Vec<String> {
+    [
+        "text/html",
+        "application/xhtml+xml",
+        "application/vnd.microsoft.portable-executable",
+    ]
+    .iter()
+    .map(|t| t.to_string())
+    .collect()
+}
+
+/// How uploaded files are laid out under `root_path`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+enum StorageMode {
+    /// each file is stored in the directory of its token
+    #[default]
+    PerToken,
+    /// files are stored under `blobs/` and named after their sha256, so
+    /// the same content uploaded several times is only stored once.
+    ContentAddressed,
+}
+
 #[rocket::get("/")]
 fn index<'r>() -> impl Responder<'r, 'static> {
     Template::render("index", &())
@@ -54,6 +184,26 @@ struct TokenInput<'r> {
     content_expires_after_hours: Option<u64>,
     #[field(name = "token-valid-for")]
     token_valid_for: u64,
+    /// patterns like `image/*`, several can be given in one value,
+    /// separated by commas. Anything is accepted if empty.
+    #[field(name = "allowed-types")]
+    #[serde(default)]
+    allowed_types: Vec<String>,
+    /// accept uploads until the token expires, instead of a single one
+    #[serde(default)]
+    open: bool,
+    #[field(name = "max-files")]
+    #[serde(default)]
+    max_files: Option<u32>,
+    /// remove the EXIF, XMP and GPS data of the pictures
+    #[field(name = "strip-metadata")]
+    #[serde(default)]
+    strip_metadata: bool,
+    /// the link stops working once its files have been downloaded that
+    /// many times
+    #[field(name = "max-downloads")]
+    #[serde(default)]
+    max_downloads: Option<u32>,
 }
 
 #[rocket::get("/gen")]
@@ -105,6 +255,17 @@ async fn gen_token_post<'a, 'o>(
         max_size_in_mib: form_input.max_size,
         token_expires_at,
         content_expires_after_hours,
+        allowed_types: form_input
+            .allowed_types
+            .iter()
+            .flat_map(|types| types.split(','))
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+            .collect(),
+        open: form_input.open,
+        max_files: form_input.max_files,
+        strip_metadata: form_input.strip_metadata,
+        max_downloads: form_input.max_downloads,
     };
     let new_token = {
         let _guard = write_lock.0.lock().await;
@@ -133,20 +294,115 @@ struct FileView {
     name: Option<String>,
     content_type: Option<String>,
     dl_uri: String,
-    is_image: bool,
+    /// to save the file rather than showing it
+    attachment_uri: String,
+    /// set for pictures
+    thumbnail_uri: Option<String>,
+    /// set for text files small enough
+    preview_uri: Option<String>,
+    /// shown with a player
+    video: bool,
+    audio: bool,
+    size: Option<String>,
+    sha256: Option<String>,
+    /// the name is encrypted as well, only the browser can show it
+    encrypted: bool,
+    /// what's inside zip and tar files
+    entries: Vec<EntryView>,
+    downloads: i32,
+}
+
+/// a file or a directory in an archive
+#[derive(Serialize, Default)]
+struct EntryView {
+    name: String,
+    is_dir: bool,
+    size: Option<String>,
+    modified: Option<String>,
+    /// to download a file on its own, when it can be extracted
+    dl_uri: Option<String>,
+    children: Vec<EntryView>,
+}
+
+/// Arrange the entries of an archive in a tree, following their paths.
+/// Archives don't always have entries for their directories, only for
+/// the files in them.
+fn entries_tree(
+    path: &str,
+    file: &db::File,
+    format: archive::Format,
+    entries: Vec<db::ArchiveEntry>,
+) -> Vec<EntryView> {
+    #[derive(Default)]
+    struct Node {
+        view: EntryView,
+        children: BTreeMap<String, Node>,
+    }
+
+    fn into_view(node: Node) -> EntryView {
+        let mut children: Vec<_> = node.children.into_values().map(into_view).collect();
+        // directories first, then sorted by name
+        children.sort_by_key(|c| !c.is_dir);
+        EntryView {
+            children,
+            ..node.view
+        }
+    }
+
+    let mut root = Node::default();
+    for entry in entries {
+        let components: Vec<&str> = entry
+            .path
+            .split('/')
+            .filter(|c| !c.is_empty() && *c != ".")
+            .collect();
+        let mut node = &mut root;
+        for (i, component) in components.iter().enumerate() {
+            node = node.children.entry(component.to_string()).or_default();
+            node.view.name = component.to_string();
+            node.view.is_dir |= entry.is_dir || i + 1 < components.len();
+        }
+        if entry.is_dir || components.is_empty() {
+            continue;
+        }
+        node.view.size = Some((entry.size_bytes as u64).bytes().to_string());
+        node.view.modified = entry
+            .modified_at
+            .map(|t| t.format("%Y-%m-%d %H:%M").to_string());
+        node.view.dl_uri = entry.entry().is_extractable(format).then(|| {
+            rocket::uri!(download_entry(path, file.id, entry.id, entry.name())).to_string()
+        });
+    }
+    into_view(root).children
 }
 
 #[derive(Serialize)]
 struct GetFilesView<'a> {
     tok_str: &'a str,
     files: Vec<FileView>,
+    /// set for open tokens still accepting uploads
+    upload: Option<UploadFilesData>,
+    /// an open token which doesn't accept uploads anymore
+    closed: bool,
+    /// to download all the files at once, when some can be
+    archive: Option<ArchiveView>,
+    /// downloads of all the files, and how many are allowed
+    downloads: i32,
+    max_downloads: Option<i32>,
     flash: Option<FlashData>,
 }
 
+#[derive(Serialize)]
+struct ArchiveView {
+    zip_uri: String,
+    tar_uri: String,
+}
+
 #[rocket::get("/f/<tok>")]
 async fn get_file(
     tok: &str,
     conn: VracDbConn,
+    highlighter: &rocket::State<Highlighter>,
     flash: Option<FlashMessage<'_>>,
 ) -> errors::Result<Option<Template>> {
     let tokstr = tok.to_string();
@@ -156,7 +412,9 @@ async fn get_file(
         None => Ok(None),
         Some(tok) => match &tok.status {
             db::TokenStatus::Fresh => Ok(Some(get_file_upload(tok, flash).await)),
-            db::TokenStatus::Used => get_files_view(tok, conn, flash).await,
+            db::TokenStatus::Used | db::TokenStatus::Open | db::TokenStatus::Closed => {
+                get_files_view(tok, conn, highlighter, flash).await
+            }
             db::TokenStatus::Deleted => unreachable!("valid token cannot be deleted"),
         },
     }
@@ -165,361 +423,2798 @@ async fn get_file(
 async fn get_files_view(
     token: db::Token,
     conn: VracDbConn,
+    highlighter: &Highlighter,
     flash: Option<FlashMessage<'_>>,
 ) -> errors::Result<Option<Template>> {
     let path = token.path.clone();
-    let files = conn.run(move |c| db::get_files(c, &token)).await?;
+    let (token, files, entries) = conn
+        .run(move |c| {
+            let files = db::get_files(c, &token)?;
+            let entries = db::get_archive_entries(c, &files)?;
+            Ok::<_, errors::VracError>((token, files, entries))
+        })
+        .await?;
+    let upload = if token.accepts_uploads() {
+        Some(upload_form_data(&token, files.len(), None))
+    } else {
+        None
+    };
+    let closed = upload.is_none()
+        && matches!(
+            token.status,
+            db::TokenStatus::Open | db::TokenStatus::Closed
+        );
+    // the files encrypted in the browser are left out of the archives
+    let archive = files.iter().any(|f| !f.encrypted).then(|| ArchiveView {
+        zip_uri: rocket::uri!(download_zip(path.clone())).to_string(),
+        tar_uri: rocket::uri!(download_tar(path.clone())).to_string(),
+    });
     // TODO: check that each file exists, and if not, display something
     // different so it's not a broken link.
     let ctx = GetFilesView {
         tok_str: &path,
         files: files
             .into_iter()
-            .map(|f| {
-                let is_image = f
-                    .content_type
-                    .as_ref()
-                    .map(|ct| ct.starts_with("image"))
-                    .unwrap_or(false);
+            .zip(entries)
+            .map(|(f, entries)| {
+                let thumbnail_uri = has_thumbnail(&f)
+                    .then(|| rocket::uri!(get_thumbnail(path.clone(), f.id)).to_string());
+                let preview_uri = has_preview(highlighter, &f)
+                    .then(|| rocket::uri!(view_file(path.clone(), f.id)).to_string());
+                let is_media = |pattern| {
+                    !f.encrypted
+                        && f.detected_content_type
+                            .as_deref()
+                            .is_some_and(|ct| content_type::matches(pattern, ct))
+                };
+                let video = is_media("video/*");
+                let audio = is_media("audio/*");
+                let entries = match f
+                    .detected_content_type
+                    .as_deref()
+                    .and_then(archive::Format::from_content_type)
+                {
+                    Some(format) => entries_tree(&path, &f, format, entries),
+                    None => Vec::new(),
+                };
 
                 FileView {
                     id: f.id,
                     name: f.name,
-                    content_type: f.content_type,
-                    dl_uri: rocket::uri!(download_file(path.clone(), f.id)).to_string(),
-                    is_image,
+                    content_type: f.detected_content_type,
+                    dl_uri: rocket::uri!(download_file(path.clone(), f.id, _, _)).to_string(),
+                    attachment_uri: rocket::uri!(download_file(path.clone(), f.id, _, Some(true)))
+                        .to_string(),
+                    thumbnail_uri,
+                    preview_uri,
+                    video,
+                    audio,
+                    size: f.size_bytes.map(|s| (s as u64).bytes().to_string()),
+                    sha256: f.sha256,
+                    encrypted: f.encrypted,
+                    entries,
+                    downloads: f.download_count,
                 }
             })
             .collect(),
+        upload,
+        closed,
+        archive,
+        downloads: token.download_count,
+        max_downloads: token.max_downloads,
         flash: flash.map(|f| f.into()),
     };
     Ok(Some(Template::render("get_files", &ctx)))
 }
 
-#[rocket::get("/f/<tok_id>/<f_id>")]
-async fn download_file(
-    tok_id: String,
-    f_id: i32,
-    conn: VracDbConn,
-) -> errors::Result<Option<(http::ContentType, fs::File)>> {
-    let file: Option<db::File> = conn
-        .run(move |c| {
-            let token = db::get_valid_token(c, tok_id)?;
-            let token = match token {
-                Some(t) => t,
-                None => return Ok(None),
-            };
-            let file = db::get_file(c, &token, f_id)?;
-            let r: errors::Result<Option<db::File>> = Ok(file);
-            r
-        })
-        .await?;
-
-    let file = match file {
-        Some(f) => f,
-        None => return Ok(None),
-    };
-
-    let fd = fs::File::open(file.path).await?;
-    // box & dyn don't play well with the Responder implementations, so
-    // default to a content type instead of returning different type of response
-    // depending on the match on file.content_type
-    let content_type = file
-        .content_type
-        .and_then(|ct| http::ContentType::parse_flexible(&ct))
-        .unwrap_or(http::ContentType::Binary);
-    Ok(Some((content_type, fd)))
+/// The content of a stored file. Compressed files are sent as is to clients
+/// accepting the encoding, and decompressed on the fly for the others.
+/// Encrypted files are always decrypted on the fly.
+struct FileDownload {
+    content_type: http::ContentType,
+    /// the content is compressed, and sent as stored if `encoded`
+    compression: Option<db::Compression>,
+    encoded: bool,
+    body: DownloadBody,
+    /// set for the stored files, see [`send_file`]
+    stored: Option<StoredHeaders>,
 }
 
-#[derive(Serialize)]
-struct UploadFilesData {
-    form_action: String,
-    max_size_in_mib: Option<i32>,
-    token_expires_at_human: String,
-    content_expires_after_human: Option<String>,
-    flash: Option<FlashData>,
+/// the stored files can be cached, and sent in parts
+struct StoredHeaders {
+    status: http::Status,
+    /// for a single range, or an unsatisfiable one
+    content_range: Option<String>,
+    validators: Validators,
+    cache_control: String,
+    content_disposition: String,
 }
 
-#[derive(Serialize)]
-struct FlashData {
-    color: &'static str,
-    message: String,
+/// Identify the content of a file, to know whether the copy a client
+/// already has is still good, or whether a part of it can be added to it.
+struct Validators {
+    /// strong, derived from the hash of the content, or from the size and
+    /// modification time of the stored file for the files uploaded before
+    /// it was recorded
+    etag: String,
+    last_modified: chrono::NaiveDateTime,
 }
 
-impl<'f> std::convert::From<FlashMessage<'f>> for FlashData {
-    fn from(flash: FlashMessage) -> Self {
-        let color = match flash.kind() {
-            "success" => "limegreen",
-            "error" => "red",
-            "warning" => "orange",
-            _ => "default",
+impl Validators {
+    fn new(file: &db::File, metadata: &std::fs::Metadata) -> Self {
+        let etag = match &file.sha256 {
+            Some(hash) => format!("\"{hash}\""),
+            None => {
+                let modified = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                    .unwrap_or_default();
+                format!("\"{:x}-{:x}\"", metadata.len(), modified.as_secs())
+            }
         };
-        FlashData {
-            color,
-            message: flash.message().to_string(),
+        Validators {
+            etag,
+            last_modified: file.created_at,
         }
     }
-}
 
-async fn get_file_upload(tok: db::Token, flash: Option<FlashMessage<'_>>) -> Template {
-    let ctx = UploadFilesData {
-        form_action: rocket::uri!(get_file(tok.path)).to_string(),
-        max_size_in_mib: tok.max_size_in_mib,
-        token_expires_at_human: tok.token_expires_at.format("%F %r").to_string(),
-        content_expires_after_human: tok
-            .content_expires_after_hours
-            .map(|h| chrono_humanize::HumanTime::from(chrono::Duration::hours(h as _)).to_string()),
-        flash: flash.map(|f| f.into()),
-    };
-    Template::render("upload_files", &ctx)
+    /// The tag of the content sent with the given `Content-Encoding`, the
+    /// encoded content is a different representation.
+    fn etag(&self, encoding: Option<&str>) -> String {
+        match encoding {
+            Some(encoding) => format!("{}-{}\"", self.etag.trim_end_matches('"'), encoding),
+            None => self.etag.clone(),
+        }
+    }
+
+    /// whether an `If-Range` header designates the content as it is now
+    fn matches(&self, if_range: &str) -> bool {
+        let if_range = if_range.trim();
+        if if_range.starts_with('"') || if_range.starts_with("W/") {
+            // weak tags never match
+            return self.etag == if_range;
+        }
+        parse_http_date(if_range).is_some_and(|date| date == self.last_modified.timestamp())
+    }
+
+    /// Whether the copy of the client, sent with the given encoding, is
+    /// still good. `If-Modified-Since` is only looked at without
+    /// `If-None-Match`.
+    fn not_modified(&self, headers: &RequestHeaders<'_>, encoding: Option<&str>) -> bool {
+        let mut if_none_match = headers.0.get("If-None-Match").peekable();
+        if if_none_match.peek().is_some() {
+            let etag = self.etag(encoding);
+            // the weak comparison, W/ is ignored
+            return if_none_match.flat_map(|v| v.split(',')).any(|tag| {
+                let tag = tag.trim();
+                tag == "*" || tag.trim_start_matches("W/") == etag
+            });
+        }
+        headers
+            .0
+            .get_one("If-Modified-Since")
+            .and_then(parse_http_date)
+            .is_some_and(|date| self.last_modified.timestamp() <= date)
+    }
 }
 
-#[derive(Debug)]
-struct MultipartBoundary<'r>(&'r str);
+const HTTP_DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S GMT";
 
-#[rocket::async_trait]
-impl<'r> request::FromRequest<'r> for MultipartBoundary<'r> {
-    type Error = std::convert::Infallible;
+/// as a unix timestamp
+fn parse_http_date(date: &str) -> Option<i64> {
+    chrono::NaiveDateTime::parse_from_str(date.trim(), HTTP_DATE_FORMAT)
+        .ok()
+        .map(|d| d.timestamp())
+}
 
-    async fn from_request(request: &'r rocket::Request<'_>) -> request::Outcome<Self, Self::Error> {
-        let ct = request.guard::<&http::ContentType>().await;
-        ct.and_then(|ct| match ct.media_type().param("boundary").as_ref() {
-            Some(boundary) => request::Outcome::Success(MultipartBoundary(boundary)),
-            None => request::Outcome::Forward(()),
+/// The `Content-Disposition` of a download, with the name of the file as
+/// uploaded, without any directory. Names which aren't plain ASCII get
+/// an approximation for old clients, and the exact name percent encoded
+/// (RFC 6266 and RFC 5987).
+fn content_disposition(attachment: bool, name: Option<&str>) -> String {
+    let kind = if attachment { "attachment" } else { "inline" };
+    let name = match name.and_then(|n| n.rsplit(['/', '\\']).next()) {
+        Some(name) if !name.is_empty() => name,
+        _ => return kind.to_string(),
+    };
+    let fallback: String = name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
         })
+        .collect();
+    if fallback == name {
+        return format!("{kind}; filename=\"{name}\"");
+    }
+    let mut encoded = String::with_capacity(name.len() * 3);
+    for byte in name.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => encoded.push(byte as char),
+            b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
     }
+    format!("{kind}; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
 }
 
-struct AdminUser;
+/// Browsers can keep the files for a day, but not after the content
+/// expires. Shared caches must not keep them, the links are private.
+fn cache_control(token: &db::Token) -> String {
+    const MAX_AGE: i64 = 24 * 3600;
+    let max_age = match token.content_expires_at {
+        Some(expires_at) => (expires_at - chrono::Utc::now().naive_utc())
+            .num_seconds()
+            .clamp(0, MAX_AGE),
+        // it only expires once the uploads are done
+        None if token.content_expires_after_hours.is_some() => 0,
+        None => MAX_AGE,
+    };
+    format!("private, max-age={max_age}")
+}
 
-#[rocket::async_trait]
-impl<'r> request::FromRequest<'r> for AdminUser {
-    type Error = std::convert::Infallible;
+enum DownloadBody {
+    File(fs::File),
+    /// the size isn't known ahead of time
+    Stream(tokio::io::DuplexStream),
+    SizedStream(tokio::io::DuplexStream, u64),
+    /// only the headers, with the size of the content when it's known
+    Empty(Option<u64>),
+}
 
-    async fn from_request(request: &'r rocket::Request<'_>) -> request::Outcome<Self, Self::Error> {
-        match request.headers().get_one("Authorization") {
-            Some(auth) => {
-                if let Some(encoded_creds) = auth.strip_prefix("Basic ") {
-                    let conn = match request.guard::<VracDbConn>().await {
-                        Outcome::Success(conn) => conn,
-                        Outcome::Failure(_) | Outcome::Forward(_) => return Outcome::Forward(()),
-                    };
-                    if is_basic_auth_valid(conn, encoded_creds).await {
-                        log::debug!("auth is valid!");
-                        Outcome::Success(AdminUser {})
-                    } else {
-                        log::debug!("auth is invalid!");
-                        Outcome::Forward(())
-                    }
-                } else {
-                    request::Outcome::Forward(())
-                }
+impl<'r> Responder<'r, 'static> for FileDownload {
+    fn respond_to(self, _request: &'r rocket::Request<'_>) -> response::Result<'static> {
+        let mut builder = response::Response::build();
+        let not_modified = self
+            .stored
+            .as_ref()
+            .is_some_and(|s| s.status == http::Status::NotModified);
+        // a 304 only has the headers related to caching
+        if !not_modified {
+            builder.header(self.content_type);
+        }
+        let encoding = self
+            .compression
+            .filter(|_| self.encoded)
+            .map(|c| c.encoding());
+        if self.compression.is_some() {
+            // the response depends on the request headers, caches must know
+            builder.raw_header("Vary", "Accept-Encoding");
+        }
+        if let Some(encoding) = encoding.filter(|_| !not_modified) {
+            builder.raw_header("Content-Encoding", encoding);
+        }
+        if let Some(stored) = self.stored {
+            builder.status(stored.status);
+            builder.raw_header("Accept-Ranges", "bytes");
+            if let Some(content_range) = stored.content_range {
+                builder.raw_header("Content-Range", content_range);
+            }
+            let validators = stored.validators;
+            builder.raw_header("ETag", validators.etag(encoding));
+            let last_modified = validators.last_modified.format(HTTP_DATE_FORMAT);
+            builder.raw_header("Last-Modified", last_modified.to_string());
+            builder.raw_header("Cache-Control", stored.cache_control);
+            if !not_modified {
+                builder.raw_header("Content-Disposition", stored.content_disposition);
             }
-            None => request::Outcome::Forward(()),
         }
+        match self.body {
+            DownloadBody::File(fd) => builder.sized_body(None, fd),
+            DownloadBody::Stream(stream) => builder.streamed_body(stream),
+            DownloadBody::SizedStream(stream, size) => builder
+                .raw_header("Content-Length", size.to_string())
+                .streamed_body(stream),
+            // rocket only sends the size of a body, even an empty one
+            DownloadBody::Empty(Some(size)) => {
+                builder.sized_body(size as usize, std::io::Cursor::new([]))
+            }
+            DownloadBody::Empty(None) => &mut builder,
+        };
+        builder.ok()
     }
 }
 
-#[rocket::post("/f/<tok>", data = "<data>")]
-async fn upload_files<'a, 'o>(
-    tok: &str,
+/// decode the file in a blocking task, the returned stream gets the
+/// decoded content, see [`encoding::decoder`].
+fn decoded_stream(
+    fd: std::fs::File,
+    key: Option<DataKey>,
+    compression: Option<db::Compression>,
+) -> tokio::io::DuplexStream {
+    blocking_stream("Error decoding file", move |writer| {
+        let mut decoder = encoding::decoder(fd, key.as_ref(), compression)?;
+        std::io::copy(&mut decoder, writer)?;
+        Ok(())
+    })
+}
+
+/// Run `write` in a blocking task, the returned stream gets what it
+/// writes. Errors are logged with the given message.
+fn blocking_stream<F>(msg: &'static str, write: F) -> tokio::io::DuplexStream
+where
+    F: FnOnce(&mut dyn std::io::Write) -> std::io::Result<()> + Send + 'static,
+{
+    let (reader, writer) = tokio::io::duplex(64 * 1024);
+    tokio::task::spawn_blocking(move || {
+        let mut writer = tokio_util::io::SyncIoBridge::new(writer);
+        match write(&mut writer) {
+            // the client went away
+            Err(err) if err.kind() == std::io::ErrorKind::BrokenPipe => (),
+            r => log_err(msg, r),
+        }
+    });
+    reader
+}
+
+/// the completed file with the given id, if the token is still valid
+async fn find_file(
+    conn: &VracDbConn,
+    tok_id: String,
+    f_id: i32,
+) -> errors::Result<Option<db::File>> {
+    conn.run(move |c| {
+        let token = match db::get_valid_token(c, tok_id)? {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+        db::get_file(c, &token, f_id)
+    })
+    .await
+}
+
+/// Like [`find_file`], with the token of the file, to count a download.
+/// Once the token reached its `max_downloads`, the file is still found
+/// for the clients already counted, so they can finish their download.
+async fn find_downloaded_file(
+    conn: &VracDbConn,
+    downloader: &Downloader<'_>,
+    tok_id: String,
+    f_id: i32,
+) -> errors::Result<Option<(db::Token, db::File)>> {
+    let found = conn
+        .run(move |c| {
+            let token = match db::get_downloadable_token(c, tok_id)? {
+                Some(t) => t,
+                None => return Ok::<_, errors::VracError>(None),
+            };
+            let file = db::get_file(c, &token, f_id)?;
+            Ok(file.map(|f| (token, f)))
+        })
+        .await?;
+    Ok(found
+        .filter(|(token, file)| !token.download_limit_reached() || downloader.is_counted(file.id)))
+}
+
+/// Files are shown in the browser when it can, `?attachment` makes it save
+/// them instead, `?inline` is the default.
+#[rocket::get("/f/<tok_id>/<f_id>?<inline>&<attachment>", rank = 2)]
+#[allow(clippy::too_many_arguments)]
+async fn download_file(
+    tok_id: String,
+    f_id: i32,
+    inline: Option<bool>,
+    attachment: Option<bool>,
     conn: VracDbConn,
-    data: Data<'_>,
-    boundary: MultipartBoundary<'_>,
-    write_lock: &rocket::State<WriteLock>,
     vrac_config: &rocket::State<VracConfig>,
-) -> errors::Result<Option<Flash<Redirect>>> {
-    log::info!("vrac config is: {vrac_config:?}");
-    let tokstr = tok.to_string();
-    let dbtoken: db::Token = match conn.run(|c| db::get_valid_token(c, tokstr)).await? {
-        // TODO would be better to redirect to get_file or something along these lines?
-        // may not work for API usage though
-        None => return Ok(None),
-        Some(tok) => tok,
-    };
+    storage: &rocket::State<Storage>,
+    headers: RequestHeaders<'_>,
+    downloader: Downloader<'_>,
+) -> errors::Result<Option<FileDownload>> {
+    let attachment = is_attachment(inline, attachment);
+    send_file(
+        tok_id,
+        f_id,
+        conn,
+        vrac_config,
+        storage,
+        headers,
+        downloader,
+        attachment,
+        false,
+    )
+    .await
+}
 
-    log::debug!("token: {:#?}", dbtoken);
+/// the headers of a download, without decoding anything
+#[rocket::head("/f/<tok_id>/<f_id>?<inline>&<attachment>", rank = 2)]
+#[allow(clippy::too_many_arguments)]
+async fn head_file(
+    tok_id: String,
+    f_id: i32,
+    inline: Option<bool>,
+    attachment: Option<bool>,
+    conn: VracDbConn,
+    vrac_config: &rocket::State<VracConfig>,
+    storage: &rocket::State<Storage>,
+    headers: RequestHeaders<'_>,
+    downloader: Downloader<'_>,
+) -> errors::Result<Option<FileDownload>> {
+    let attachment = is_attachment(inline, attachment);
+    send_file(
+        tok_id,
+        f_id,
+        conn,
+        vrac_config,
+        storage,
+        headers,
+        downloader,
+        attachment,
+        true,
+    )
+    .await
+}
 
-    let max_stream_size = match dbtoken.max_size_in_mib {
-        // add 10 kiB (generous) to account for the boundaries in the actual form
-        Some(s) => s.mebibytes() + ByteUnit::Kibibyte(10),
-        None => usize::MAX.mebibytes(),
-    };
-    log::info!("streaming at most {} mebibytes", max_stream_size);
+/// `?attachment` wins when both are given
+fn is_attachment(inline: Option<bool>, attachment: Option<bool>) -> bool {
+    attachment.unwrap_or(inline == Some(false))
+}
 
-    // open(size) will close the connection after the limit. This result in a broken pipe
-    // for the client, on a browser you get a page "connectio was reset" which isn't ideal
-    // TODO: perhaps, when the limit is reached, continue reading but discard everything
-    // and return the correct error? That could be used to use a lot of network resource though.
-    // Also, figure out how to clean up stuff already uploaded
-    let stream =
-        codec::FramedRead::new(data.open(usize::MAX.mebibytes()), codec::BytesCodec::new());
+/// A stored file, or only its headers for a `HEAD` request. Clients with
+/// a copy still good get a 304, and `Range` requests get parts of the
+/// content, see [`ranged_download`].
+/// Each client is counted once, whether it gets the whole content or
+/// ranges, see [`Downloader`].
+#[allow(clippy::too_many_arguments)]
+async fn send_file(
+    tok_id: String,
+    f_id: i32,
+    conn: VracDbConn,
+    vrac_config: &VracConfig,
+    storage: &Storage,
+    headers: RequestHeaders<'_>,
+    downloader: Downloader<'_>,
+    attachment: bool,
+    head: bool,
+) -> errors::Result<Option<FileDownload>> {
+    let (token, file) = match find_downloaded_file(&conn, &downloader, tok_id, f_id).await? {
+        Some(found) => found,
+        None => return Ok(None),
+    };
 
-    // TODO allow more files
-    let constraints = Constraints::new()
-        .allowed_fields(vec!["file-1"])
-        .size_limit(SizeLimit::new().whole_stream(max_stream_size.as_u64()));
-    let mut multipart = Multipart::with_constraints(stream, boundary.0.to_string(), constraints);
-
-    // TODO: use cap_std to prevent an attacker to escape the root path with
-    // some chosen value of tok.path
-    // This is fairly minimal though since only admins/owner should have the
-    // ability to generate tokens.
-    let dest_path = vrac_config.root_path.as_path().join(&dbtoken.path);
-    fs::create_dir_all(&dest_path)
-        .await
-        .context("Cannot create temporary file")?;
+    let fd = storage.open(&file.path)?;
+    let metadata = fd.metadata()?;
+    // box & dyn don't play well with the Responder implementations, so
+    // default to a content type instead of returning different type of response
+    // depending on the match on file.detected_content_type
+    // The type declared by the client is never used, it could be anything.
+    let content_type = file
+        .detected_content_type
+        .as_deref()
+        .map(content_type::served)
+        .and_then(http::ContentType::parse_flexible)
+        .unwrap_or(http::ContentType::Binary);
+    let key = file
+        .wrapped_key
+        .as_deref()
+        .map(|k| vrac_config.file_key(k))
+        .transpose()?;
+    let validators = Validators::new(&file, &metadata);
+    let cache_control = cache_control(&token);
+    // the name of files encrypted in the browser is encrypted as well
+    let name = file.name.as_deref().filter(|_| !file.encrypted);
+    let content_disposition = content_disposition(attachment, name);
+    let size = match file.size_bytes {
+        Some(size) => size as u64,
+        // files from before the size was recorded are stored as is
+        None => metadata.len(),
+    };
+    let encoded = file
+        .compression
+        .is_some_and(|c| headers.accepts_encoding(c.encoding()));
+    let encoding = file.compression.filter(|_| encoded).map(|c| c.encoding());
+
+    if validators.not_modified(&headers, encoding) {
+        return Ok(Some(FileDownload {
+            content_type,
+            compression: file.compression,
+            encoded,
+            body: DownloadBody::Empty(None),
+            stored: Some(StoredHeaders {
+                status: http::Status::NotModified,
+                content_range: None,
+                validators,
+                cache_control,
+                content_disposition,
+            }),
+        }));
+    }
 
-    while let Some(mut field) = multipart.next_field().await.context("multipart issue")? {
-        match upload_file(&conn, write_lock, &mut field, &dest_path, dbtoken.id).await {
-            Ok(_) => (),
-            Err(errors::VracError::FileSizeExceeded) => {
-                let redir = Redirect::to(rocket::uri!(get_file(&tok)));
-                let msg = format!(
-                    "File size exceeded, the maximum total size is {}",
-                    max_stream_size
-                );
-                return Ok(Some(Flash::error(redir, msg)));
+    // a range can only be sent from an unchanged content, and there are
+    // no ranges for HEAD requests
+    let range = headers.0.get_one("Range").filter(|_| !head).filter(|_| {
+        match headers.0.get_one("If-Range") {
+            Some(if_range) => validators.matches(if_range),
+            None => true,
+        }
+    });
+    if let Some(range) = range {
+        match range::parse(range, size) {
+            range::Ranges::Full => (),
+            ranges => {
+                let partial = matches!(ranges, range::Ranges::Partial(_));
+                if partial && !downloader.record(&conn, &token, file.id).await? {
+                    return Ok(None);
+                }
+                let stored = StoredContent {
+                    storage: storage.clone(),
+                    path: file.path,
+                    key,
+                    compression: file.compression,
+                    size,
+                    decoded: None,
+                };
+                let headers = StoredHeaders {
+                    status: http::Status::PartialContent,
+                    content_range: None,
+                    validators,
+                    cache_control,
+                    content_disposition,
+                };
+                return Ok(Some(ranged_download(stored, content_type, ranges, headers)));
             }
-            Err(err) => return Err(err),
         }
     }
 
-    let tok_path = dbtoken.path.clone();
-    {
-        let _guard = write_lock.0.lock().await;
-        log::info!("Consumming token {tok:?}");
-        conn.run(move |c| db::consume_token(c, dbtoken)).await?;
+    if !head && !downloader.record(&conn, &token, file.id).await? {
+        return Ok(None);
     }
-    let redir = Redirect::to(rocket::uri!(get_file(&tok_path)));
-    Ok(Some(Flash::success(redir, "File uploaded.")))
-}
 
-async fn upload_file<'a>(
-    conn: &VracDbConn,
-    write_lock: &rocket::State<WriteLock>,
-    field: &mut Field<'a>,
-    dest_path: &Path,
-    token_id: i32,
-) -> errors::Result<()> {
-    let mut file_path = dest_path.to_path_buf();
-    match field.name().or_else(|| field.file_name()) {
-        Some(file_name) => {
-            if file_name.is_empty() {
-                // avoid creating empty files
-                return Ok(());
-            } else {
-                file_path.push(format!("file-{token_id:04}"));
-            }
+    let to_undo = if encoded { None } else { file.compression };
+    let as_stored = key.is_none() && to_undo.is_none();
+    let length = match (as_stored, encoded) {
+        (true, _) => Some(metadata.len()),
+        (false, false) => Some(size),
+        // only decrypted, the size of the compressed content isn't recorded
+        (false, true) => None,
+    };
+    let body = if head {
+        DownloadBody::Empty(length)
+    } else if as_stored {
+        DownloadBody::File(fs::File::from_std(fd))
+    } else {
+        let stream = decoded_stream(fd, key, to_undo);
+        match length {
+            Some(length) => DownloadBody::SizedStream(stream, length),
+            None => DownloadBody::Stream(stream),
         }
-        None => return Ok(()),
     };
+    Ok(Some(FileDownload {
+        content_type,
+        compression: file.compression,
+        encoded,
+        body,
+        stored: Some(StoredHeaders {
+            status: http::Status::Ok,
+            content_range: None,
+            validators,
+            cache_control,
+            content_disposition,
+        }),
+    }))
+}
 
-    log::info!(
-        "going to write some bytes to {}",
-        &file_path.to_string_lossy(),
-    );
+/// Count a download of the given files, false if the token reached its
+/// `max_downloads` since it was looked up.
+async fn record_download(
+    conn: &VracDbConn,
+    write_lock: &WriteLock,
+    token: &db::Token,
+    file_ids: Vec<i32>,
+) -> errors::Result<bool> {
+    let token_id = token.id;
+    let _guard = write_lock.0.lock().await;
+    conn.run(move |c| db::record_download(c, token_id, &file_ids))
+        .await
+}
 
-    let db_file = {
-        let _guard = write_lock.0.lock().await;
-        let create_file = db::CreateFile {
-            token_id,
-            name: field.file_name().map(|s| s.to_string()),
-            path: file_path.clone(),
-            content_type: field.content_type().map(|ct| ct.to_string()),
-        };
-        conn.run(move |c| db::create_file(c, create_file)).await?
-    };
+/// where and how a file is stored, to read parts of it
+struct StoredContent {
+    storage: Storage,
+    path: String,
+    key: Option<DataKey>,
+    compression: Option<db::Compression>,
+    /// once decoded
+    size: u64,
+    /// Compressed content can only be decoded from the start, it's done
+    /// once for all the ranges of a request. The decoder is kept after a
+    /// range, with the position it reached.
+    decoded: Option<(Box<dyn std::io::Read + Send>, u64)>,
+}
+
+impl StoredContent {
+    /// Copy the decoded content of the range to `writer`. The ranges of a
+    /// request are copied in order. This is blocking.
+    fn copy_range(
+        &mut self,
+        range: &ByteRange,
+        writer: &mut dyn std::io::Write,
+    ) -> std::io::Result<()> {
+        let decoded = self
+            .decoded
+            .take()
+            .filter(|(_, position)| *position <= range.start);
+        let mut decoder = match decoded {
+            Some((mut decoder, position)) => {
+                let skip = range.start - position;
+                let mut to_skip = std::io::Read::take(&mut decoder, skip);
+                let skipped = std::io::copy(&mut to_skip, &mut std::io::sink())?;
+                if skipped < skip {
+                    return Err(std::io::ErrorKind::UnexpectedEof.into());
+                }
+                decoder
+            }
+            None => {
+                let fd = self.storage.open(&self.path)?;
+                encoding::decoder_at(fd, self.key.as_ref(), self.compression, range.start)?
+            }
+        };
+        let copied = std::io::copy(
+            &mut std::io::Read::take(&mut decoder, range.length()),
+            writer,
+        )?;
+        if copied < range.length() {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        if self.compression.is_some() {
+            self.decoded = Some((decoder, range.end + 1));
+        }
+        Ok(())
+    }
+}
+
+/// The answer to a `Range` request: a single part of the content, or
+/// several in a `multipart/byteranges` body. The parts are always decoded,
+/// the ranges are over the content as uploaded.
+fn ranged_download(
+    mut stored: StoredContent,
+    content_type: http::ContentType,
+    ranges: range::Ranges,
+    headers: StoredHeaders,
+) -> FileDownload {
+    let size = stored.size;
+    let ranges = match ranges {
+        range::Ranges::Partial(ranges) => ranges,
+        _ => {
+            return FileDownload {
+                content_type,
+                compression: None,
+                encoded: false,
+                body: DownloadBody::Empty(None),
+                stored: Some(StoredHeaders {
+                    status: http::Status::RangeNotSatisfiable,
+                    content_range: Some(range::unsatisfied_range(size)),
+                    ..headers
+                }),
+            };
+        }
+    };
+
+    if let [range] = ranges[..] {
+        let body = blocking_stream("Error sending range", move |writer| {
+            stored.copy_range(&range, writer)
+        });
+        return FileDownload {
+            content_type,
+            compression: None,
+            encoded: false,
+            body: DownloadBody::SizedStream(body, range.length()),
+            stored: Some(StoredHeaders {
+                content_range: Some(range.content_range(size)),
+                ..headers
+            }),
+        };
+    }
+
+    let multipart = range::Multipart::new();
+    let part_type = content_type.to_string();
+    let body_size = multipart.body_size(&part_type, &ranges, size);
+    let multipart_type = http::ContentType::parse_flexible(&multipart.content_type())
+        .unwrap_or(http::ContentType::Binary);
+    let body = blocking_stream("Error sending ranges", move |writer| {
+        for range in &ranges {
+            writer.write_all(multipart.part_header(&part_type, range, size).as_bytes())?;
+            stored.copy_range(range, writer)?;
+        }
+        writer.write_all(multipart.end().as_bytes())
+    });
+    FileDownload {
+        content_type: multipart_type,
+        compression: None,
+        encoded: false,
+        body: DownloadBody::SizedStream(body, body_size),
+        stored: Some(headers),
+    }
+}
+
+#[rocket::get("/f/<tok_id>/archive.zip")]
+async fn download_zip(
+    tok_id: String,
+    conn: VracDbConn,
+    write_lock: &rocket::State<WriteLock>,
+    vrac_config: &rocket::State<VracConfig>,
+    storage: &rocket::State<Storage>,
+) -> errors::Result<Option<FileDownload>> {
+    download_archive(
+        tok_id,
+        archive::Format::Zip,
+        conn,
+        write_lock,
+        vrac_config,
+        storage,
+    )
+    .await
+}
+
+#[rocket::get("/f/<tok_id>/archive.tar")]
+async fn download_tar(
+    tok_id: String,
+    conn: VracDbConn,
+    write_lock: &rocket::State<WriteLock>,
+    vrac_config: &rocket::State<VracConfig>,
+    storage: &rocket::State<Storage>,
+) -> errors::Result<Option<FileDownload>> {
+    download_archive(
+        tok_id,
+        archive::Format::Tar,
+        conn,
+        write_lock,
+        vrac_config,
+        storage,
+    )
+    .await
+}
+
+/// All the completed files of a token in one archive, written in a
+/// blocking task as it's sent. The files encrypted in the browser are
+/// left out, the server cannot read them.
+async fn download_archive(
+    tok_id: String,
+    format: archive::Format,
+    conn: VracDbConn,
+    write_lock: &WriteLock,
+    vrac_config: &VracConfig,
+    storage: &Storage,
+) -> errors::Result<Option<FileDownload>> {
+    let found = conn
+        .run(move |c| match db::get_valid_token(c, tok_id)? {
+            Some(token) => db::get_files(c, &token).map(|files| Some((token, files))),
+            None => Ok(None),
+        })
+        .await?;
+    let (token, files) = match found {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    let mut entries = Vec::with_capacity(files.len());
+    for file in files.into_iter().filter(|f| !f.encrypted) {
+        let key = file
+            .wrapped_key
+            .as_deref()
+            .map(|k| vrac_config.file_key(k))
+            .transpose()?;
+        entries.push((file, key));
+    }
+    let file_ids = entries.iter().map(|(f, _)| f.id).collect();
+    if !record_download(&conn, write_lock, &token, file_ids).await? {
+        return Ok(None);
+    }
+
+    let storage = storage.clone();
+    let body = blocking_stream("Error writing archive", move |writer| {
+        write_archive(&storage, ArchiveWriter::new(format, writer), entries)
+    });
+    let (top, sub) = format.content_type();
+    Ok(Some(FileDownload {
+        content_type: http::ContentType::new(top, sub),
+        compression: None,
+        encoded: false,
+        body: DownloadBody::Stream(body),
+        stored: None,
+    }))
+}
+
+/// A single file out of an uploaded archive, as its own download. The name
+/// is only there for the browser to save it under.
+#[rocket::get("/f/<tok_id>/<f_id>/entries/<entry_id>/<_name>")]
+#[allow(clippy::too_many_arguments)]
+async fn download_entry(
+    tok_id: String,
+    f_id: i32,
+    entry_id: i32,
+    _name: &str,
+    conn: VracDbConn,
+    vrac_config: &rocket::State<VracConfig>,
+    storage: &rocket::State<Storage>,
+    downloader: Downloader<'_>,
+) -> errors::Result<Option<FileDownload>> {
+    let (token, file) = match find_downloaded_file(&conn, &downloader, tok_id, f_id).await? {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    let (file, entry) = conn
+        .run(move |c| db::get_archive_entry(c, &file, entry_id).map(|e| (file, e)))
+        .await?;
+    let entry = match entry {
+        Some(e) => e,
+        None => return Ok(None),
+    };
+    let format = file
+        .detected_content_type
+        .as_deref()
+        .and_then(archive::Format::from_content_type);
+    let entry = entry.entry();
+    let format = match format {
+        Some(format) if entry.is_extractable(format) => format,
+        _ => return Ok(None),
+    };
+    // counted as a download of the archive
+    if !downloader.record(&conn, &token, file.id).await? {
+        return Ok(None);
+    }
+    let key = file
+        .wrapped_key
+        .as_deref()
+        .map(|k| vrac_config.file_key(k))
+        .transpose()?;
+
+    let storage = storage.inner().clone();
+    let body = blocking_stream("Error extracting archive entry", move |writer| {
+        let mut content = archive::extract(format, &entry, |offset| {
+            encoding::decoder_at(
+                storage.open(&file.path)?,
+                key.as_ref(),
+                file.compression,
+                offset,
+            )
+        })?;
+        std::io::copy(&mut content, writer)?;
+        Ok(())
+    });
+    // whatever is inside, it's never shown by the browser
+    Ok(Some(FileDownload {
+        content_type: http::ContentType::Binary,
+        compression: None,
+        encoded: false,
+        body: DownloadBody::Stream(body),
+        stored: None,
+    }))
+}
+
+/// add the decoded content of the files to the archive, one after the other
+fn write_archive<W: std::io::Write>(
+    storage: &Storage,
+    mut archive: ArchiveWriter<W>,
+    entries: Vec<(db::File, Option<DataKey>)>,
+) -> std::io::Result<()> {
+    for (file, key) in entries {
+        let fd = storage.open(&file.path)?;
+        let size = match file.size_bytes {
+            Some(size) => size as u64,
+            // files from before the size was recorded are stored as is
+            None => fd.metadata()?.len(),
+        };
+        let mut content = encoding::decoder(fd, key.as_ref(), file.compression)?;
+        let name = file.name.as_deref().unwrap_or_default();
+        archive.append(name, size, file.created_at, &mut content)?;
+    }
+    archive.finish()?;
+    Ok(())
+}
+
+/// A small jpeg preview of a picture. It's usually generated after the
+/// upload, and otherwise on the fly.
+#[rocket::get("/f/<tok_id>/<f_id>/thumbnail")]
+async fn get_thumbnail(
+    tok_id: String,
+    f_id: i32,
+    conn: VracDbConn,
+    vrac_config: &rocket::State<VracConfig>,
+    storage: &rocket::State<Storage>,
+) -> errors::Result<Option<(http::ContentType, Vec<u8>)>> {
+    let file = match find_file(&conn, tok_id, f_id).await? {
+        Some(f) if has_thumbnail(&f) => f,
+        _ => return Ok(None),
+    };
+    let key = file
+        .wrapped_key
+        .as_deref()
+        .map(|k| vrac_config.file_key(k))
+        .transpose()?;
+    let storage = storage.inner().clone();
+    let jpeg = tokio::task::spawn_blocking(move || {
+        thumbnail::generate(&storage, &file.path, key.as_ref(), file.compression)?;
+        thumbnail::read(&storage, &file.path, key.as_ref())
+    })
+    .await
+    .context("Thumbnail task failed")?;
+    match jpeg {
+        Ok(jpeg) => Ok(Some((http::ContentType::JPEG, jpeg))),
+        // not a picture after all, or a broken one
+        Err(err) if err.kind() == std::io::ErrorKind::InvalidData => {
+            log::warn!("No thumbnail for file {f_id}: {err}");
+            Ok(None)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// the content of files encrypted in the browser cannot be looked at
+fn has_thumbnail(file: &db::File) -> bool {
+    !file.encrypted
+        && file
+            .detected_content_type
+            .as_deref()
+            .is_some_and(thumbnail::supports)
+}
+
+#[derive(Serialize)]
+struct PreviewView {
+    name: String,
+    files_uri: String,
+    dl_uri: String,
+    /// highlighted text, one line each
+    lines: Vec<PreviewLine>,
+    hex_lines: Vec<HexLineView>,
+    /// only the start of the hex dump is shown
+    truncated: bool,
+}
+
+#[derive(Serialize)]
+struct PreviewLine {
+    number: usize,
+    html: String,
+}
+
+#[derive(Serialize)]
+struct HexLineView {
+    offset: String,
+    hex: String,
+    ascii: String,
+}
+
+/// A page showing a text file with its syntax highlighted, or a hex dump
+/// if it's not valid UTF-8.
+#[rocket::get("/f/<tok_id>/<f_id>/view")]
+async fn view_file(
+    tok_id: String,
+    f_id: i32,
+    conn: VracDbConn,
+    vrac_config: &rocket::State<VracConfig>,
+    storage: &rocket::State<Storage>,
+    highlighter: &rocket::State<Highlighter>,
+    downloader: Downloader<'_>,
+) -> errors::Result<Option<Template>> {
+    let (token, file) = match find_downloaded_file(&conn, &downloader, tok_id.clone(), f_id).await?
+    {
+        Some((token, f)) if has_preview(highlighter, &f) => (token, f),
+        _ => return Ok(None),
+    };
+    // the preview shows most of the content, or all of it
+    if !downloader.record(&conn, &token, file.id).await? {
+        return Ok(None);
+    }
+    let key = file
+        .wrapped_key
+        .as_deref()
+        .map(|k| vrac_config.file_key(k))
+        .transpose()?;
+    let file_id = file.id;
+    let name = file.name.clone().unwrap_or_default();
+    let content_type = file.detected_content_type.clone().unwrap_or_default();
+
+    let storage = storage.inner().clone();
+    let highlighter = highlighter.inner().clone();
+    let preview_name = name.clone();
+    let preview = tokio::task::spawn_blocking(move || {
+        let content = preview::read(&storage, &file.path, key.as_ref(), file.compression)?;
+        Ok::<_, std::io::Error>(highlighter.preview(&preview_name, &content_type, &content))
+    })
+    .await
+    .context("Preview task failed")??;
+
+    let (lines, hex_lines, truncated) = match preview {
+        preview::Preview::Text(lines) => {
+            let lines = lines
+                .into_iter()
+                .enumerate()
+                .map(|(i, html)| PreviewLine {
+                    number: i + 1,
+                    html,
+                })
+                .collect();
+            (lines, Vec::new(), false)
+        }
+        preview::Preview::Hex { lines, truncated } => {
+            let lines = lines
+                .into_iter()
+                .map(|l| HexLineView {
+                    offset: l.offset,
+                    hex: l.hex,
+                    ascii: l.ascii,
+                })
+                .collect();
+            (Vec::new(), lines, truncated)
+        }
+    };
+    let ctx = PreviewView {
+        name,
+        files_uri: rocket::uri!(get_file(&tok_id)).to_string(),
+        dl_uri: rocket::uri!(download_file(&tok_id, file_id, _, _)).to_string(),
+        lines,
+        hex_lines,
+        truncated,
+    };
+    Ok(Some(Template::render("view_file", &ctx)))
+}
+
+/// Text files small enough to be shown in a page. The content of files
+/// encrypted in the browser cannot be looked at.
+fn has_preview(highlighter: &Highlighter, file: &db::File) -> bool {
+    let content_type = match &file.detected_content_type {
+        Some(ct) => ct,
+        None => return false,
+    };
+    !file.encrypted
+        && file
+            .size_bytes
+            .is_some_and(|s| (s as u64) <= preview::MAX_SIZE)
+        && highlighter.supports(content_type, file.name.as_deref().unwrap_or_default())
+}
+
+#[derive(Serialize)]
+struct MetadataView {
+    files: Vec<FileMetadataView>,
+}
+
+#[derive(Serialize)]
+struct FileMetadataView {
+    id: i32,
+    /// as uploaded, so encrypted for end to end encrypted files
+    name: Option<String>,
+    content_type: Option<String>,
+    size_bytes: Option<i64>,
+    sha256: Option<String>,
+    /// UTC, RFC 3339
+    created_at: String,
+    encrypted: bool,
+    url: String,
+}
+
+/// the completed files of a token, as json. Used by the browser to decrypt
+/// end to end encrypted files, or by any script wanting to fetch them.
+#[rocket::get("/f/<tok>/metadata")]
+async fn get_metadata(tok: &str, conn: VracDbConn) -> errors::Result<Option<Json<MetadataView>>> {
+    let tokstr = tok.to_string();
+    let files = conn
+        .run(move |c| match db::get_valid_token(c, tokstr)? {
+            Some(token) => db::get_files(c, &token).map(Some),
+            None => Ok(None),
+        })
+        .await?;
+    let files = match files {
+        Some(files) => files,
+        None => return Ok(None),
+    };
+
+    let files = files
+        .into_iter()
+        .map(|f| FileMetadataView {
+            id: f.id,
+            url: rocket::uri!(download_file(tok, f.id, _, _)).to_string(),
+            name: f.name,
+            content_type: f.detected_content_type,
+            size_bytes: f.size_bytes,
+            sha256: f.sha256,
+            created_at: f.created_at.format("%FT%TZ").to_string(),
+            encrypted: f.encrypted,
+        })
+        .collect();
+    Ok(Some(Json(MetadataView { files })))
+}
+
+#[derive(Serialize)]
+struct ProgressView {
+    uploads: Vec<FileProgressView>,
+}
+
+#[derive(Serialize)]
+struct FileProgressView {
+    id: i32,
+    name: Option<String>,
+    written_bytes: u64,
+    total_bytes: Option<u64>,
+    /// only known while the file is receiving data
+    bytes_per_second: Option<u64>,
+    eta_seconds: Option<u64>,
+}
+
+/// the uploads in progress for a token, as json
+#[rocket::get("/f/<tok>/progress")]
+async fn get_progress(
+    tok: &str,
+    conn: VracDbConn,
+    progress: &rocket::State<UploadProgress>,
+) -> errors::Result<Option<Json<ProgressView>>> {
+    let tokstr = tok.to_string();
+    let files = conn
+        .run(move |c| match db::get_valid_token(c, tokstr)? {
+            Some(token) => db::get_started_files(c, &token).map(Some),
+            None => Ok(None),
+        })
+        .await?;
+    let files = match files {
+        Some(files) => files,
+        None => return Ok(None),
+    };
+
+    let uploads = files
+        .into_iter()
+        .map(|f| match progress.get(f.id) {
+            Some(p) => FileProgressView {
+                id: f.id,
+                name: f.name,
+                written_bytes: p.written,
+                total_bytes: p.total,
+                bytes_per_second: p.bytes_per_second(),
+                eta_seconds: p.eta_seconds(),
+            },
+            // a resumable upload waiting for its next PATCH
+            None => FileProgressView {
+                id: f.id,
+                name: f.name,
+                written_bytes: f.upload_offset.unwrap_or(0) as u64,
+                total_bytes: f.upload_length.map(|l| l as u64),
+                bytes_per_second: None,
+                eta_seconds: None,
+            },
+        })
+        .collect();
+    Ok(Some(Json(ProgressView { uploads })))
+}
+
+#[derive(Serialize)]
+struct UploadFilesData {
+    form_action: String,
+    progress_url: String,
+    max_size_in_mib: Option<i32>,
+    token_expires_at_human: String,
+    content_expires_after_human: Option<String>,
+    allowed_types: Option<String>,
+    /// several uploads can be made with this token
+    open: bool,
+    files_left: Option<usize>,
+    flash: Option<FlashData>,
+}
+
+#[derive(Serialize)]
+struct FlashData {
+    color: &'static str,
+    message: String,
+}
+
+impl<'f> std::convert::From<FlashMessage<'f>> for FlashData {
+    fn from(flash: FlashMessage) -> Self {
+        let color = match flash.kind() {
+            "success" => "limegreen",
+            "error" => "red",
+            "warning" => "orange",
+            _ => "default",
+        };
+        FlashData {
+            color,
+            message: flash.message().to_string(),
+        }
+    }
+}
+
+async fn get_file_upload(tok: db::Token, flash: Option<FlashMessage<'_>>) -> Template {
+    let ctx = upload_form_data(&tok, 0, flash);
+    Template::render("upload_files", &ctx)
+}
+
+fn upload_form_data(
+    tok: &db::Token,
+    n_files: usize,
+    flash: Option<FlashMessage<'_>>,
+) -> UploadFilesData {
+    UploadFilesData {
+        form_action: rocket::uri!(get_file(&tok.path)).to_string(),
+        progress_url: rocket::uri!(get_progress(&tok.path)).to_string(),
+        max_size_in_mib: tok.max_size_in_mib,
+        token_expires_at_human: tok.token_expires_at.format("%F %r").to_string(),
+        content_expires_after_human: tok
+            .content_expires_after_hours
+            .map(|h| chrono_humanize::HumanTime::from(chrono::Duration::hours(h as _)).to_string()),
+        allowed_types: tok.allowed_types.clone(),
+        open: tok.status == db::TokenStatus::Open,
+        files_left: tok
+            .max_files
+            .map(|max| (max as usize).saturating_sub(n_files)),
+        flash: flash.map(|f| f.into()),
+    }
+}
+
+#[derive(Debug)]
+struct MultipartBoundary<'r>(&'r str);
+
+#[rocket::async_trait]
+impl<'r> request::FromRequest<'r> for MultipartBoundary<'r> {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let ct = request.guard::<&http::ContentType>().await;
+        ct.and_then(|ct| match ct.media_type().param("boundary").as_ref() {
+            Some(boundary) => request::Outcome::Success(MultipartBoundary(boundary)),
+            None => request::Outcome::Forward(()),
+        })
+    }
+}
+
+struct AdminUser;
+
+#[rocket::async_trait]
+impl<'r> request::FromRequest<'r> for AdminUser {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match request.headers().get_one("Authorization") {
+            Some(auth) => {
+                if let Some(encoded_creds) = auth.strip_prefix("Basic ") {
+                    let conn = match request.guard::<VracDbConn>().await {
+                        Outcome::Success(conn) => conn,
+                        Outcome::Failure(_) | Outcome::Forward(_) => return Outcome::Forward(()),
+                    };
+                    if is_basic_auth_valid(conn, encoded_creds).await {
+                        log::debug!("auth is valid!");
+                        Outcome::Success(AdminUser {})
+                    } else {
+                        log::debug!("auth is invalid!");
+                        Outcome::Forward(())
+                    }
+                } else {
+                    request::Outcome::Forward(())
+                }
+            }
+            None => request::Outcome::Forward(()),
+        }
+    }
+}
+
+/// When an upload is rejected, the rest of the request body is read and
+/// discarded up to this limit. Otherwise the connection is closed before the
+/// browser is done sending the body and it shows "connection was reset"
+/// instead of the error message.
+const MAX_DISCARDED_SIZE: ByteUnit = ByteUnit::Mebibyte(64);
+
+/// the field used by the upload form for files encrypted in the browser
+const ENCRYPTED_FILES_FIELD: &str = "encrypted-files";
+
+#[rocket::post("/f/<tok>", data = "<data>")]
+#[allow(clippy::too_many_arguments)]
+async fn upload_files<'a, 'o>(
+    tok: &str,
+    conn: VracDbConn,
+    data: Data<'_>,
+    boundary: MultipartBoundary<'_>,
+    headers: RequestHeaders<'_>,
+    write_lock: &rocket::State<WriteLock>,
+    progress: &rocket::State<UploadProgress>,
+    vrac_config: &rocket::State<VracConfig>,
+    storage: &rocket::State<Storage>,
+) -> errors::Result<Option<Flash<Redirect>>> {
+    log::info!("vrac config is: {vrac_config:?}");
+    let tokstr = tok.to_string();
+    let dbtoken: db::Token = match conn.run(|c| db::get_valid_token(c, tokstr)).await? {
+        // TODO would be better to redirect to get_file or something along these lines?
+        // may not work for API usage though
+        None => return Ok(None),
+        Some(tok) => tok,
+    };
+
+    log::debug!("token: {:#?}", dbtoken);
+
+    let max_stream_size = match dbtoken.max_size_in_mib {
+        // add 10 kiB (generous) to account for the boundaries in the actual form
+        Some(s) => s.mebibytes() + ByteUnit::Kibibyte(10),
+        None => usize::MAX.mebibytes(),
+    };
+    log::info!("streaming at most {} mebibytes", max_stream_size);
+    let size_exceeded = || {
+        let redir = Redirect::to(rocket::uri!(get_file(&tok)));
+        let msg = format!(
+            "File size exceeded, the maximum total size is {}",
+            max_stream_size
+        );
+        Flash::error(redir, msg)
+    };
+
+    let mut stream =
+        codec::FramedRead::new(data.open(usize::MAX.mebibytes()), codec::BytesCodec::new());
+
+    if !dbtoken.accepts_uploads() {
+        discard_body(&mut stream).await;
+        let redir = Redirect::to(rocket::uri!(get_file(&tok)));
+        return Ok(Some(Flash::error(
+            redir,
+            "This link doesn't accept uploads anymore.",
+        )));
+    }
+
+    // browsers always send the size of the form, so most of the time
+    // there is no need to look at the content to reject it.
+    if let Some(content_length) = headers.get_u64("Content-Length") {
+        if content_length > max_stream_size.as_u64() {
+            log::info!("rejecting upload of {content_length} bytes for token {tok}");
+            discard_body(&mut stream).await;
+            return Ok(Some(size_exceeded()));
+        }
+    }
+
+    let constraints = Constraints::new()
+        .allowed_fields(vec!["files", ENCRYPTED_FILES_FIELD])
+        .size_limit(SizeLimit::new().whole_stream(max_stream_size.as_u64()));
+    let mut multipart =
+        Multipart::with_constraints(&mut stream, boundary.0.to_string(), constraints);
+
+    let dest_path = PathBuf::from(&dbtoken.path);
+    storage
+        .create_dir_all(&dest_path)
+        .context("Cannot create token directory")?;
+
+    let mut uploaded = Vec::new();
+    let upload_result: errors::Result<()> = async {
+        while let Some(mut field) = multipart.next_field().await? {
+            let name = match field.file_name() {
+                // an empty file input is still sent as a part without a file name,
+                // avoid creating empty files for these.
+                Some(file_name) if !file_name.is_empty() => file_name.to_string(),
+                _ => continue,
+            };
+            let incoming = IncomingFile {
+                name,
+                declared_type: field.content_type().map(|ct| ct.to_string()),
+                size: None,
+                encrypted: field.name() == Some(ENCRYPTED_FILES_FIELD),
+            };
+            let db_file = upload_file(
+                &conn,
+                write_lock,
+                progress,
+                vrac_config,
+                storage,
+                &dbtoken,
+                incoming,
+                &mut field,
+            )
+            .await?;
+            uploaded.push(db_file);
+        }
+        Ok(())
+    }
+    .await;
+    drop(multipart);
+
+    if let Err(err) = upload_result {
+        // all or nothing, don't leave the token with only some of the files
+        rollback_uploads(&conn, write_lock, storage, uploaded).await;
+        return match err {
+            errors::VracError::FileSizeExceeded => {
+                discard_body(&mut stream).await;
+                Ok(Some(size_exceeded()))
+            }
+            err @ errors::VracError::FileTypeNotAllowed(_) => {
+                discard_body(&mut stream).await;
+                let redir = Redirect::to(rocket::uri!(get_file(&tok)));
+                let msg = match &dbtoken.allowed_types {
+                    Some(types) => format!("{err}, the accepted types are: {types}"),
+                    None => format!("{err}"),
+                };
+                Ok(Some(Flash::error(redir, msg)))
+            }
+            err @ (errors::VracError::TooManyFiles(_) | errors::VracError::Quarantined(_)) => {
+                discard_body(&mut stream).await;
+                let redir = Redirect::to(rocket::uri!(get_file(&tok)));
+                Ok(Some(Flash::error(redir, format!("{err}"))))
+            }
+            err => Err(err),
+        };
+    }
+
+    let tok_path = dbtoken.path.clone();
+    {
+        let _guard = write_lock.0.lock().await;
+        log::info!("Consumming token {tok:?}");
+        conn.run(move |c| db::consume_token(c, dbtoken)).await?;
+    }
+    let redir = Redirect::to(rocket::uri!(get_file(&tok_path)));
+    Ok(Some(Flash::success(redir, "File uploaded.")))
+}
+
+/// upload a single file with the body of the request as its content, like
+/// `curl -T big.iso https://host/f/<tok>/big.iso`. The response is the url
+/// to download the file.
+#[rocket::put("/f/<tok>/<filename>", data = "<data>")]
+#[allow(clippy::too_many_arguments)]
+async fn put_file(
+    tok: &str,
+    filename: &str,
+    conn: VracDbConn,
+    data: Data<'_>,
+    headers: RequestHeaders<'_>,
+    write_lock: &rocket::State<WriteLock>,
+    progress: &rocket::State<UploadProgress>,
+    vrac_config: &rocket::State<VracConfig>,
+    storage: &rocket::State<Storage>,
+) -> errors::Result<Option<(http::Status, String)>> {
+    use futures::StreamExt;
+    let tokstr = tok.to_string();
+    let dbtoken = match conn.run(|c| db::get_valid_token(c, tokstr)).await? {
+        Some(tok) if tok.accepts_uploads() => tok,
+        _ => return Ok(None),
+    };
+
+    let max_size = match dbtoken.max_size_in_mib {
+        Some(s) => s.mebibytes().as_u64(),
+        None => u64::MAX,
+    };
+    let content_length = headers.get_u64("Content-Length");
+    if let Some(content_length) = content_length {
+        if content_length > max_size {
+            // no need to discard the body, clients like curl wait for a
+            // `100 Continue` before sending it.
+            log::info!("rejecting upload of {content_length} bytes for token {tok}");
+            return Err(errors::VracError::FileSizeExceeded);
+        }
+    }
+
+    storage
+        .create_dir_all(&dbtoken.path)
+        .context("Cannot create token directory")?;
+
+    // read one byte more than allowed to tell a body of exactly the maximum
+    // size from a bigger one.
+    let reader = data.open(max_size.saturating_add(1).bytes());
+    let mut received = 0;
+    let content = codec::FramedRead::new(reader, codec::BytesCodec::new()).map(move |chunk| {
+        let chunk = chunk?;
+        received += chunk.len() as u64;
+        if received > max_size {
+            Err(errors::VracError::FileSizeExceeded)
+        } else {
+            Ok(chunk)
+        }
+    });
+    let incoming = IncomingFile {
+        name: filename.to_string(),
+        declared_type: headers.0.get_one("Content-Type").map(|ct| ct.to_string()),
+        size: content_length,
+        encrypted: false,
+    };
+    let db_file = upload_file(
+        &conn,
+        write_lock,
+        progress,
+        vrac_config,
+        storage,
+        &dbtoken,
+        incoming,
+        content,
+    )
+    .await?;
+
+    let tok_path = dbtoken.path.clone();
+    {
+        let _guard = write_lock.0.lock().await;
+        log::info!("Consumming token {tok:?}");
+        conn.run(move |c| db::consume_token(c, dbtoken)).await?;
+    }
+    let url = headers.absolute_url(rocket::uri!(download_file(tok_path, db_file.id, _, _)));
+    Ok(Some((http::Status::Created, format!("{url}\n"))))
+}
+
+/// read and throw away what's left of a request body, up to [`MAX_DISCARDED_SIZE`].
+async fn discard_body<S, B>(stream: &mut S)
+where
+    S: futures::Stream<Item = std::io::Result<B>> + Unpin,
+    B: AsRef<[u8]>,
+{
+    use futures::StreamExt;
+    let mut discarded = 0;
+    while let Some(Ok(chunk)) = stream.next().await {
+        discarded += chunk.as_ref().len();
+        if discarded.bytes() > MAX_DISCARDED_SIZE {
+            log::info!("request body too big to be discarded, giving up");
+            break;
+        }
+    }
+}
+
+/// delete the files, and the associated rows, written as part of a failed upload.
+async fn rollback_uploads(
+    conn: &VracDbConn,
+    write_lock: &rocket::State<WriteLock>,
+    storage: &Storage,
+    files: Vec<db::File>,
+) {
+    for file in files {
+        log::info!("rolling back upload of file {} at {}", file.id, file.path);
+        // an upload being finalized may find the blob and rely on it
+        // before linking it, so the blobs are removed under the lock too
+        let _guard = write_lock.0.lock().await;
+        let r = conn.run(move |c| db::abort_upload(c, file.id)).await;
+        log_err("Error deleting file in the DB", r);
+        if file.blob_sha256.is_some() {
+            // the blob may be used by other files
+            let storage = storage.clone();
+            let r = conn
+                .run(move |c| {
+                    cleanup::remove_unreferenced_blobs(c, &storage).map_err(|e| e.to_string())
+                })
+                .await;
+            log_err("Error deleting unused blobs", r);
+        } else {
+            log_err(
+                &format!("Error deleting the file at {}", file.path),
+                storage.remove_file(&file.path),
+            );
+        }
+    }
+}
+
+/// a file about to be uploaded, as announced by the client
+struct IncomingFile {
+    name: String,
+    declared_type: Option<String>,
+    /// only known when the file is the whole body of the request
+    size: Option<u64>,
+    /// encrypted by the browser, see [`db::File::encrypted`]
+    encrypted: bool,
+}
+
+/// write the given content as a new file for the token, the content can
+/// be a part of a multipart form or the whole body of a request.
+#[allow(clippy::too_many_arguments)]
+async fn upload_file<S, B, E>(
+    conn: &VracDbConn,
+    write_lock: &rocket::State<WriteLock>,
+    progress: &UploadProgress,
+    vrac_config: &VracConfig,
+    storage: &Storage,
+    token: &db::Token,
+    incoming: IncomingFile,
+    content: S,
+) -> errors::Result<db::File>
+where
+    S: futures::Stream<Item = Result<B, E>> + Unpin,
+    B: AsRef<[u8]>,
+    E: Into<errors::VracError>,
+{
+    if incoming.encrypted {
+        // neither the name nor the content can be checked, so only the
+        // tokens accepting anything take encrypted files.
+        if token.allowed_types.is_some() {
+            log::info!("rejecting an encrypted file for token {}", token.path);
+            return Err(errors::VracError::FileTypeNotAllowed(
+                "an encrypted file".to_string(),
+            ));
+        }
+    } else {
+        check_file_type(
+            vrac_config,
+            token,
+            &incoming.name,
+            incoming.declared_type.as_deref(),
+        )?;
+    }
+
+    let db_file = {
+        let _guard = write_lock.0.lock().await;
+        let create_file = db::CreateFile {
+            token_id: token.id,
+            name: Some(incoming.name),
+            dir: PathBuf::from(&token.path),
+            content_type: incoming.declared_type,
+            upload_length: None,
+            encrypted: incoming.encrypted,
+            wrapped_key: None,
+        };
+        conn.run(move |c| db::create_file(c, create_file)).await?
+    };
+
+    // the content only lands at db_file.path once the upload is complete,
+    // so a partially written file is never served.
+    let file_path = db_file.partial_path();
+    log::info!("going to write some bytes to {}", file_path.display());
 
     let file_path_string = file_path.to_string_lossy().to_string();
-    let file_size = match write_file(field, file_path).await {
-        Ok(size) => size,
+    let file_key = vrac_config.new_file_key()?;
+    let encoder = encoding::Encoder::new(vrac_config.compress, file_key.as_ref().map(|k| &k.0));
+    let tracker = ProgressGuard::start(progress, db_file.id, 0, incoming.size);
+    let written = write_file(storage, content, file_path, encoder, &tracker)
+        .await
+        .and_then(|written| {
+            if incoming.encrypted {
+                // whatever is detected, it can only be noise
+                Ok(WrittenFile {
+                    detected_content_type: content_type::BINARY.to_string(),
+                    ..written
+                })
+            } else {
+                check_detected_type(vrac_config, token, &db_file, written)
+            }
+        });
+    drop(tracker);
+    let mut written = match written {
+        Ok(written) => written,
         Err(err) => {
             // something went wrong, attempt to cleanup everything before
             // returning this error.
             let r = conn.run(move |c| db::abort_upload(c, db_file.id)).await;
             log_err("Error deleting file in the DB", r);
 
-            log_err(
-                &format!("Error deleting the file at {file_path_string}"),
-                tokio::fs::remove_file(&file_path_string).await,
-            );
+            log_err(
+                &format!("Error deleting the file at {file_path_string}"),
+                storage.remove_file(&file_path_string),
+            );
+
+            return Err(err);
+        }
+    };
+
+    log::info!(
+        "for file {} wrote {} - sha256: {}",
+        file_path_string,
+        written.size,
+        written.sha256
+    );
+
+    let (key, wrapped_key) = file_key.unzip();
+    // nothing to look for in the content encrypted by the browser
+    if !incoming.encrypted {
+        if let Err(err) = sanitize_upload(
+            vrac_config,
+            storage,
+            token,
+            &db_file,
+            key.clone(),
+            &mut written,
+        )
+        .await
+        {
+            discard_upload(conn, write_lock, storage, &db_file).await;
+            return Err(err);
+        }
+        let path = db_file.partial_path();
+        if let Some(reason) =
+            scan_upload(vrac_config, storage, path, key.clone(), written.compression).await
+        {
+            let _guard = write_lock.0.lock().await;
+            quarantine_upload(conn, storage, &db_file, written, wrapped_key, reason).await?;
+            let name = db_file.name.unwrap_or_default();
+            return Err(errors::VracError::Quarantined(name));
+        }
+        index_upload(storage, &db_file, key, &mut written).await;
+    }
+
+    let _guard = write_lock.0.lock().await;
+    finalize_upload(conn, vrac_config, storage, &db_file, written, wrapped_key).await
+}
+
+/// reject a file before writing anything based on its name and the type
+/// announced by the client.
+fn check_file_type(
+    vrac_config: &VracConfig,
+    token: &db::Token,
+    file_name: &str,
+    declared_type: Option<&str>,
+) -> errors::Result<()> {
+    if vrac_config.is_blocked(file_name) {
+        log::info!("rejecting {file_name:?}, its extension is blocked");
+        return Err(errors::VracError::FileTypeNotAllowed(file_name.to_string()));
+    }
+    match declared_type {
+        // clients send that when they don't know, the detected type will tell
+        None | Some(content_type::BINARY) => Ok(()),
+        Some(declared_type)
+            if token.accepts(declared_type) && !vrac_config.is_blocked_type(declared_type) =>
+        {
+            Ok(())
+        }
+        Some(declared_type) => {
+            log::info!("rejecting {file_name:?}, declared as {declared_type}");
+            Err(errors::VracError::FileTypeNotAllowed(file_name.to_string()))
+        }
+    }
+}
+
+/// the declared type can be anything, so check the token accepts what
+/// was actually uploaded, and that it's not blocked.
+fn check_detected_type(
+    vrac_config: &VracConfig,
+    token: &db::Token,
+    db_file: &db::File,
+    written: WrittenFile,
+) -> errors::Result<WrittenFile> {
+    let detected_type = &written.detected_content_type;
+    if token.accepts(detected_type) && !vrac_config.is_blocked_type(detected_type) {
+        Ok(written)
+    } else {
+        let name = db_file.name.clone().unwrap_or_default();
+        log::info!(
+            "rejecting {name:?}, detected as {}",
+            written.detected_content_type
+        );
+        Err(errors::VracError::FileTypeNotAllowed(name))
+    }
+}
+
+/// Resumable uploads are written as is, since they come in several
+/// pieces. Once complete, replace the content at the partial path with
+/// its compressed and encrypted version, as configured.
+/// Returns the wrapped key if the content is now encrypted.
+async fn encode_upload(
+    vrac_config: &VracConfig,
+    storage: &Storage,
+    db_file: &db::File,
+    written: &mut WrittenFile,
+) -> errors::Result<Option<String>> {
+    // resumable uploads are already encrypted, with their own key
+    let existing_key = db_file
+        .wrapped_key
+        .as_deref()
+        .map(|k| vrac_config.file_key(k))
+        .transpose()?;
+    if !vrac_config.compress && (vrac_config.master_key.is_none() || existing_key.is_some()) {
+        return Ok(db_file.wrapped_key.clone());
+    }
+    let partial_path = db_file.partial_path();
+    // still ends with the partial extension, so it's swept on restart
+    // if the server stops in the middle.
+    let encoded_path = PathBuf::from(format!("{}.enc.{}", db_file.path, db::PARTIAL_EXTENSION));
+    let (file_key, wrapped_key) = match &existing_key {
+        Some(key) => (Some(key.clone()), db_file.wrapped_key.clone()),
+        None => vrac_config.new_file_key()?.unzip(),
+    };
+    let compression = {
+        let storage = storage.clone();
+        let compress = vrac_config.compress;
+        let from = partial_path.clone();
+        let to = encoded_path.clone();
+        tokio::task::spawn_blocking(move || {
+            encoding::encode_file(
+                &storage,
+                &from,
+                existing_key.as_ref(),
+                &to,
+                compress,
+                file_key.as_ref(),
+            )
+        })
+        .await
+        .context("Encoding task failed")?
+        .with_context(|| format!("Cannot encode {}", partial_path.display()))?
+    };
+    storage
+        .rename(&encoded_path, &partial_path)
+        .with_context(|| format!("Cannot move {} in place", encoded_path.display()))?;
+    written.compression = compression;
+    Ok(wrapped_key)
+}
+
+/// move the content of the file from its partial path into place, and mark
+/// the file as completed. When using content addressed storage, the
+/// content is moved to its blob, or discarded if the blob already exists.
+/// Returns the completed file. Must be called with the write lock held.
+async fn finalize_upload(
+    conn: &VracDbConn,
+    vrac_config: &VracConfig,
+    storage: &Storage,
+    db_file: &db::File,
+    written: WrittenFile,
+    wrapped_key: Option<String>,
+) -> errors::Result<db::File> {
+    let file_id = db_file.id;
+    let size_bytes = written.size.as_u64() as i64;
+
+    let partial_path = db_file.partial_path();
+    let mut compression = written.compression;
+    let mut wrapped_key = wrapped_key;
+    let mut path = db_file.path.clone();
+    if vrac_config.storage == StorageMode::ContentAddressed {
+        let sha256 = written.sha256.clone();
+        let existing_blob = conn.run(move |c| db::get_blob(c, &sha256)).await?;
+        let blob_path = match existing_blob {
+            Some(blob) => {
+                log::info!(
+                    "content of file {} already stored at {}",
+                    db_file.path,
+                    blob.path
+                );
+                storage.remove_file(&partial_path).with_context(|| {
+                    format!("Cannot remove duplicate {}", partial_path.display())
+                })?;
+                blob.path
+            }
+            None => {
+                let blob_dir = Path::new(storage::BLOBS_DIR).join(&written.sha256[..2]);
+                storage
+                    .create_dir_all(&blob_dir)
+                    .context("Cannot create blob directory")?;
+                let blob_path = blob_dir.join(&written.sha256);
+                storage.rename(&partial_path, &blob_path).with_context(|| {
+                    format!("Cannot move {} to its blob", partial_path.display())
+                })?;
+                blob_path.to_string_lossy().to_string()
+            }
+        };
+        path = blob_path.clone();
+        let sha256 = written.sha256.clone();
+        let blob = conn
+            .run(move |c| {
+                db::link_blob(
+                    c,
+                    file_id,
+                    sha256,
+                    blob_path,
+                    size_bytes,
+                    compression,
+                    wrapped_key,
+                )
+            })
+            .await?;
+        // an existing blob may not be stored like this upload was
+        compression = blob.compression;
+        wrapped_key = blob.wrapped_key;
+    } else {
+        storage
+            .rename(&partial_path, &db_file.path)
+            .with_context(|| format!("Cannot move {} into place", partial_path.display()))?;
+    }
+
+    let key = wrapped_key
+        .as_deref()
+        .map(|k| vrac_config.file_key(k))
+        .transpose()?;
+    let has_thumbnail = thumbnail::supports(&written.detected_content_type);
+    let content = db::UploadedContent {
+        size_bytes,
+        sha256: written.sha256,
+        detected_content_type: written.detected_content_type,
+        compression,
+        wrapped_key,
+        sanitized: written.sanitized,
+        original_path: written.original_path,
+        original_wrapped_key: written.original_wrapped_key,
+    };
+    let entries = written.archive_entries;
+    let file = conn
+        .run(move |c| {
+            let file = db::complete_upload(c, file_id, content)?;
+            db::add_archive_entries(c, file_id, &entries)?;
+            Ok::<_, errors::VracError>(file)
+        })
+        .await?;
+    if has_thumbnail {
+        spawn_thumbnail(storage, path, key, compression);
+    }
+    Ok(file)
+}
+
+/// generate the thumbnail of a new picture in the background, so it's
+/// ready by the time someone looks at the list of files.
+fn spawn_thumbnail(
+    storage: &Storage,
+    path: String,
+    key: Option<DataKey>,
+    compression: Option<db::Compression>,
+) {
+    let storage = storage.clone();
+    tokio::task::spawn_blocking(move || {
+        log_err(
+            &format!("Cannot generate the thumbnail of {path}"),
+            thumbnail::generate(&storage, &path, key.as_ref(), compression),
+        )
+    });
+}
+
+/// Remove the metadata of a new picture at the partial path, when the
+/// configuration or the token asks for it. The size and digest then
+/// describe the rewritten picture. The original can be kept aside for the
+/// admin, next to the file. A picture whose metadata can't be removed is
+/// refused.
+async fn sanitize_upload(
+    vrac_config: &VracConfig,
+    storage: &Storage,
+    token: &db::Token,
+    db_file: &db::File,
+    key: Option<DataKey>,
+    written: &mut WrittenFile,
+) -> errors::Result<()> {
+    if !(vrac_config.strip_metadata || token.strip_metadata)
+        || !sanitize::supports(&written.detected_content_type)
+    {
+        return Ok(());
+    }
+    let original_path = format!("{}.original", db_file.path);
+    let (original_key, original_wrapped_key) = if vrac_config.keep_originals {
+        vrac_config.new_file_key()?.unzip()
+    } else {
+        (None, None)
+    };
+
+    let sanitized = {
+        let storage = storage.clone();
+        let path = db_file.partial_path();
+        let content_type = written.detected_content_type.clone();
+        let compression = written.compression;
+        let compress = vrac_config.compress;
+        let keep_originals = vrac_config.keep_originals;
+        let original_path = original_path.clone();
+        tokio::task::spawn_blocking(move || {
+            let original =
+                keep_originals.then(|| (Path::new(&original_path), original_key.as_ref()));
+            sanitize::sanitize_file(
+                &storage,
+                &path,
+                &content_type,
+                key.as_ref(),
+                compression,
+                compress,
+                original,
+            )
+        })
+        .await
+        .context("Sanitize task failed")?
+        .with_context(|| format!("Cannot remove the metadata of {}", db_file.path))?
+    };
+    match sanitized {
+        Some(sanitized) => {
+            written.size = sanitized.size.bytes();
+            written.sha256 = sanitized.sha256;
+            written.compression = sanitized.compression;
+            written.sanitized = true;
+            if sanitized.kept_original {
+                written.original_path = Some(original_path);
+                written.original_wrapped_key = original_wrapped_key;
+            }
+        }
+        None => {
+            // publishing it as is would leak what was asked to be removed
+            log::warn!(
+                "Metadata of {} not removed, it's not a well formed {} or it's too big",
+                db_file.path,
+                written.detected_content_type
+            );
+            let name = db_file.name.clone().unwrap_or_default();
+            return Err(errors::VracError::MetadataNotRemoved(name));
+        }
+    }
+    Ok(())
+}
+
+/// Abort an upload whose content was received but is refused, and remove
+/// what was written of it.
+async fn discard_upload(
+    conn: &VracDbConn,
+    write_lock: &rocket::State<WriteLock>,
+    storage: &Storage,
+    db_file: &db::File,
+) {
+    let file_id = db_file.id;
+    {
+        let _guard = write_lock.0.lock().await;
+        let r = conn.run(move |c| db::abort_upload(c, file_id)).await;
+        log_err("Error deleting file in the DB", r);
+    }
+    log_err(
+        "Error deleting rejected upload",
+        storage.remove_file(db_file.partial_path()),
+    );
+}
+
+/// List the entries of a new zip or tar file at the partial path, so the
+/// recipients can see what's inside before downloading it. A broken
+/// archive is still accepted, it's only not listed.
+async fn index_upload(
+    storage: &Storage,
+    db_file: &db::File,
+    key: Option<DataKey>,
+    written: &mut WrittenFile,
+) {
+    let format = match archive::Format::from_content_type(&written.detected_content_type) {
+        Some(format) => format,
+        None => return,
+    };
+    let storage = storage.clone();
+    let path = db_file.partial_path();
+    let len = written.size.as_u64();
+    let compression = written.compression;
+    let entries = tokio::task::spawn_blocking(move || {
+        archive::list_entries(format, len, |offset| {
+            encoding::decoder_at(storage.open(&path)?, key.as_ref(), compression, offset)
+        })
+    })
+    .await;
+    match entries {
+        Ok(Ok(entries)) => written.archive_entries = entries,
+        Ok(Err(err)) => log::warn!("Cannot list the entries of {}: {err}", db_file.path),
+        Err(err) => log::error!("Listing task failed: {err:?}"),
+    }
+}
+
+/// Run the configured scanner on the content of a new file at the given
+/// path, and returns why it must be quarantined, if it must.
+/// When the content cannot be checked, it's quarantined as well.
+async fn scan_upload(
+    vrac_config: &VracConfig,
+    storage: &Storage,
+    path: PathBuf,
+    key: Option<DataKey>,
+    compression: Option<db::Compression>,
+) -> Option<String> {
+    let scanner_config = vrac_config.scanner.clone()?;
+    let storage = storage.clone();
+    let verdict = tokio::task::spawn_blocking(move || {
+        let scanner = scanner_config.build()?;
+        let fd = storage.open(&path)?;
+        let mut content = encoding::decoder(fd, key.as_ref(), compression)?;
+        log::debug!("scanning {} with {scanner:?}", path.display());
+        scanner.scan(&mut content)
+    })
+    .await;
+    match verdict {
+        Ok(Ok(Verdict::Clean)) => None,
+        Ok(Ok(Verdict::Flagged(reason))) => {
+            log::warn!("file flagged by the scanner: {reason}");
+            Some(reason)
+        }
+        Ok(Err(err)) => {
+            log::error!("Cannot scan file: {err:?}");
+            Some(format!("cannot be scanned: {err}"))
+        }
+        Err(err) => {
+            log::error!("Scan task failed: {err:?}");
+            Some(format!("cannot be scanned: {err}"))
+        }
+    }
+}
+
+/// Move the content of a file flagged by the scanner into place, like
+/// [`finalize_upload`], but mark it as quarantined so it's never served.
+/// It's kept for the admin to look at, and removed with the other files of
+/// the token. Must be called with the write lock held.
+async fn quarantine_upload(
+    conn: &VracDbConn,
+    storage: &Storage,
+    db_file: &db::File,
+    written: WrittenFile,
+    wrapped_key: Option<String>,
+    reason: String,
+) -> errors::Result<()> {
+    let partial_path = db_file.partial_path();
+    storage
+        .rename(&partial_path, &db_file.path)
+        .with_context(|| format!("Cannot move {} into place", partial_path.display()))?;
+    let file_id = db_file.id;
+    let content = db::UploadedContent {
+        size_bytes: written.size.as_u64() as i64,
+        sha256: written.sha256,
+        detected_content_type: written.detected_content_type,
+        compression: written.compression,
+        wrapped_key,
+        sanitized: written.sanitized,
+        original_path: written.original_path,
+        original_wrapped_key: written.original_wrapped_key,
+    };
+    conn.run(move |c| db::quarantine_file(c, file_id, content, reason))
+        .await
+}
+
+/// Discard a Result, if it's an error, log it as error prepended with the given message.
+fn log_err<A, E>(msg: &str, err: Result<A, E>)
+where
+    E: std::fmt::Debug,
+{
+    match err {
+        Ok(_) => (),
+        Err(err) => log::error!("{msg} - {err:?}"),
+    }
+}
+
+/// what is known about a file once all its content has been received.
+struct WrittenFile {
+    size: ByteUnit,
+    /// hex encoded
+    sha256: String,
+    detected_content_type: String,
+    /// how the content is compressed on disk
+    compression: Option<db::Compression>,
+    /// see [`sanitize_upload`]
+    sanitized: bool,
+    original_path: Option<String>,
+    original_wrapped_key: Option<String>,
+    /// what's inside, for zip and tar files
+    archive_entries: Vec<archive::Entry>,
+}
+
+/// computes what's needed for a [`WrittenFile`] as the content goes through
+struct ContentInspector {
+    size: usize,
+    hasher: Sha256,
+    head: Vec<u8>,
+}
+
+impl ContentInspector {
+    fn new() -> Self {
+        ContentInspector {
+            size: 0,
+            hasher: Sha256::new(),
+            head: Vec::with_capacity(content_type::HEAD_SIZE),
+        }
+    }
+
+    fn update(&mut self, chunk: &[u8]) {
+        self.size += chunk.len();
+        self.hasher.update(chunk);
+        let missing = content_type::HEAD_SIZE - self.head.len();
+        self.head
+            .extend_from_slice(&chunk[..std::cmp::min(missing, chunk.len())]);
+    }
+
+    fn finish(self) -> WrittenFile {
+        WrittenFile {
+            size: self.size.bytes(),
+            sha256: format!("{:x}", self.hasher.finalize()),
+            detected_content_type: content_type::detect(&self.head),
+            compression: None,
+            sanitized: false,
+            original_path: None,
+            original_wrapped_key: None,
+            archive_entries: Vec::new(),
+        }
+    }
+}
+
+/// read the content of a file, like a field in a multipart body, and
+/// attempt to write it to disk, going through the given encoder.
+/// The content is synced to disk before returning.
+async fn write_file<S, B, E>(
+    storage: &Storage,
+    mut content: S,
+    file_path: PathBuf,
+    mut encoder: encoding::Encoder,
+    progress: &ProgressGuard<'_>,
+) -> errors::Result<WrittenFile>
+where
+    S: futures::Stream<Item = Result<B, E>> + Unpin,
+    B: AsRef<[u8]>,
+    E: Into<errors::VracError>,
+{
+    use futures::StreamExt;
+    let file_path_string = file_path.to_string_lossy().to_string();
+
+    let mut writer = storage
+        .create(&file_path)
+        .map(fs::File::from_std)
+        .with_context(|| format!("Error opening file {} for write", file_path_string))?;
+
+    let mut inspector = ContentInspector::new();
+    let mut encoded = Vec::new();
+    while let Some(chunk) = content.next().await {
+        let chunk = match chunk {
+            Ok(c) => c,
+            Err(err) => {
+                let err = err.into();
+                log::error!("got an error while reading a chunk: {:?}", err);
+                return Err(err);
+            }
+        };
+        let chunk = chunk.as_ref();
+
+        inspector.update(chunk);
+        log::debug!(
+            "written so far: {}  (wrote {})",
+            inspector.size.bytes(),
+            chunk.len().bytes()
+        );
+        encoder.update(chunk, &mut encoded)?;
+        writer
+            .write_all(&encoded)
+            .await
+            .with_context(|| format!("Error writing to file {}", file_path_string))?;
+        encoded.clear();
+        progress.set_written(inspector.size as u64);
+        writer.flush().await.unwrap();
+    }
+    let compression = encoder.finish(&mut encoded)?;
+    writer
+        .write_all(&encoded)
+        .await
+        .with_context(|| format!("Error writing to file {}", file_path_string))?;
+    writer
+        .sync_all()
+        .await
+        .with_context(|| format!("Error syncing file {}", file_path_string))?;
+    writer
+        .shutdown()
+        .await
+        .with_context(|| format!("Error writing to file {}", file_path_string))?;
+    Ok(WrittenFile {
+        compression,
+        ..inspector.finish()
+    })
+}
+
+/// inspect the content of a file already on disk
+/// like [`write_file`] for content already on disk, written as is or
+/// encrypted with `key`
+async fn digest_file(
+    storage: &Storage,
+    file_path: &Path,
+    key: Option<DataKey>,
+) -> errors::Result<WrittenFile> {
+    let storage = storage.clone();
+    let path = file_path.to_path_buf();
+    let written = tokio::task::spawn_blocking(move || {
+        let mut reader = encoding::decoder(storage.open(&path)?, key.as_ref(), None)?;
+        let mut inspector = ContentInspector::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = std::io::Read::read(&mut reader, &mut buf)?;
+            if n == 0 {
+                break;
+            }
+            inspector.update(&buf[..n]);
+        }
+        Ok::<_, std::io::Error>(inspector.finish())
+    })
+    .await
+    .context("Digest task failed")?
+    .with_context(|| format!("Error reading file {}", file_path.to_string_lossy()))?;
+    Ok(written)
+}
+
+const TUS_VERSION: &str = "1.0.0";
+
+/// Gives access to the raw headers of a request, the tus protocol relies on
+/// a bunch of custom headers.
+struct RequestHeaders<'r>(&'r http::HeaderMap<'r>);
+
+#[rocket::async_trait]
+impl<'r> request::FromRequest<'r> for RequestHeaders<'r> {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> request::Outcome<Self, Self::Error> {
+        request::Outcome::Success(RequestHeaders(request.headers()))
+    }
+}
+
+impl<'r> RequestHeaders<'r> {
+    fn get_u64(&self, name: &str) -> Option<u64> {
+        self.0.get_one(name).and_then(|v| v.trim().parse().ok())
+    }
+
+    /// the full url for a path on this server, as seen by the client
+    fn absolute_url(&self, path: impl std::fmt::Display) -> String {
+        match self.0.get_one("Host") {
+            Some(host) => {
+                let scheme = self.0.get_one("X-Forwarded-Proto").unwrap_or("http");
+                format!("{scheme}://{host}{path}")
+            }
+            None => path.to_string(),
+        }
+    }
+
+    /// whether the client can decode a `Content-Encoding`
+    fn accepts_encoding(&self, encoding: &str) -> bool {
+        self.0
+            .get("Accept-Encoding")
+            .flat_map(|v| v.split(','))
+            .any(|accepted| {
+                let mut params = accepted.split(';').map(str::trim);
+                let name = params.next().unwrap_or_default();
+                let refused = params.any(|p| p == "q=0" || p == "q=0.0" || p == "q=0.00");
+                name.eq_ignore_ascii_case(encoding) && !refused
+            })
+    }
+
+    /// tus requires every request except OPTIONS to announce the version
+    /// of the protocol used by the client.
+    fn is_tus_supported(&self) -> bool {
+        self.0.get_one("Tus-Resumable") == Some(TUS_VERSION)
+    }
+}
+
+/// Response to a tus request, always carrying the version of the protocol
+/// used by the server.
+struct TusResponse {
+    status: http::Status,
+    headers: Vec<http::Header<'static>>,
+}
+
+impl TusResponse {
+    fn new(status: http::Status) -> Self {
+        TusResponse {
+            status,
+            headers: Vec::new(),
+        }
+    }
+
+    fn header(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.headers
+            .push(http::Header::new(name, value.to_string()));
+        self
+    }
+
+    fn unsupported_version() -> Self {
+        TusResponse::new(http::Status::PreconditionFailed).header("Tus-Version", TUS_VERSION)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for TusResponse {
+    fn respond_to(self, _request: &'r rocket::Request<'_>) -> response::Result<'static> {
+        let mut builder = response::Response::build();
+        builder
+            .status(self.status)
+            .raw_header("Tus-Resumable", TUS_VERSION);
+        for hdr in self.headers {
+            builder.header_adjoin(hdr);
+        }
+        builder.ok()
+    }
+}
+
+/// parse the Upload-Metadata header, a comma separated list of key and
+/// base64 encoded values: `filename d29ybGRfZG9taW5hdGlvbl9wbGFuLnBkZg==,is_confidential`
+fn parse_upload_metadata(raw: &str) -> std::collections::HashMap<String, String> {
+    raw.split(',')
+        .filter_map(|pair| {
+            let mut it = pair.trim().splitn(2, ' ');
+            let key = it.next().filter(|k| !k.is_empty())?;
+            let value = match it.next() {
+                Some(v) => String::from_utf8(base64::decode(v.trim()).ok()?).ok()?,
+                None => String::new(),
+            };
+            Some((key.to_string(), value))
+        })
+        .collect()
+}
+
+#[rocket::options("/f/<tok>/tus")]
+async fn tus_options(tok: &str, conn: VracDbConn) -> errors::Result<Option<TusResponse>> {
+    let tokstr = tok.to_string();
+    let dbtoken = match conn.run(|c| db::get_valid_token(c, tokstr)).await? {
+        None => return Ok(None),
+        Some(tok) => tok,
+    };
+    let mut resp = TusResponse::new(http::Status::NoContent)
+        .header("Tus-Version", TUS_VERSION)
+        .header("Tus-Extension", "creation,termination");
+    if let Some(max_size) = dbtoken.max_size_in_mib {
+        resp = resp.header("Tus-Max-Size", max_size.mebibytes().as_u64());
+    }
+    Ok(Some(resp))
+}
+
+/// tus creation extension: register a new upload of a known length, the
+/// content is then sent with one or more PATCH requests.
+#[rocket::post("/f/<tok>/tus")]
+async fn tus_create(
+    tok: &str,
+    conn: VracDbConn,
+    headers: RequestHeaders<'_>,
+    write_lock: &rocket::State<WriteLock>,
+    vrac_config: &rocket::State<VracConfig>,
+    storage: &rocket::State<Storage>,
+) -> errors::Result<Option<TusResponse>> {
+    if !headers.is_tus_supported() {
+        return Ok(Some(TusResponse::unsupported_version()));
+    }
+
+    let tokstr = tok.to_string();
+    let dbtoken = match conn.run(|c| db::get_valid_token(c, tokstr)).await? {
+        Some(tok) if tok.accepts_uploads() => tok,
+        _ => return Ok(None),
+    };
+
+    let upload_length = match headers.get_u64("Upload-Length") {
+        Some(l) => l,
+        None => return Ok(Some(TusResponse::new(http::Status::BadRequest))),
+    };
+    if let Some(max_size) = dbtoken.max_size_in_mib {
+        if upload_length > max_size.mebibytes().as_u64() {
+            return Ok(Some(TusResponse::new(http::Status::PayloadTooLarge)));
+        }
+    }
 
-            return Err(err);
+    let metadata = headers
+        .0
+        .get_one("Upload-Metadata")
+        .map(parse_upload_metadata)
+        .unwrap_or_default();
+    let name = metadata.get("filename").or_else(|| metadata.get("name"));
+    let content_type = metadata.get("filetype").or_else(|| metadata.get("type"));
+    check_file_type(
+        vrac_config,
+        &dbtoken,
+        name.map(|n| n.as_str()).unwrap_or_default(),
+        content_type.map(|ct| ct.as_str()),
+    )?;
+
+    let dest_path = PathBuf::from(&dbtoken.path);
+    storage
+        .create_dir_all(&dest_path)
+        .context("Cannot create token directory")?;
+
+    // the max size of a single upload link is for all its files, and the
+    // link is only used once the first upload completes. Open tokens limit
+    // each upload on its own.
+    let max_total_size = dbtoken
+        .max_size_in_mib
+        .filter(|_| dbtoken.status == db::TokenStatus::Fresh)
+        .map(|max_size| max_size.mebibytes().as_u64());
+    // the content is encrypted as it's received, so it's never on disk
+    // in plaintext, even when the upload isn't finished
+    let (file_key, wrapped_key) = vrac_config.new_file_key()?.unzip();
+    let db_file = {
+        let _guard = write_lock.0.lock().await;
+        let create_file = db::CreateFile {
+            token_id: dbtoken.id,
+            name: name.cloned(),
+            dir: dest_path,
+            content_type: content_type.cloned(),
+            upload_length: Some(upload_length as _),
+            encrypted: false,
+            wrapped_key,
+        };
+        conn.run(move |c| {
+            if let Some(max_size) = max_total_size {
+                let reserved = db::reserved_upload_size(c, create_file.token_id)? as u64;
+                if reserved + upload_length > max_size {
+                    return Err(errors::VracError::FileSizeExceeded);
+                }
+            }
+            db::create_file(c, create_file)
+        })
+        .await?
+    };
+
+    // create the file right away, so that an empty upload is complete
+    // and a PATCH can always append to an existing file.
+    let mut header = Vec::new();
+    if let Some(key) = &file_key {
+        let mut encryptor = Encryptor::new(key);
+        if upload_length == 0 {
+            encryptor.finish(&mut header)?;
+        } else {
+            encryptor.flush(&mut header)?;
+        }
+    }
+    let partial_path = db_file.partial_path();
+    storage
+        .create(&partial_path)
+        .and_then(|mut f| std::io::Write::write_all(&mut f, &header))
+        .with_context(|| format!("Cannot create file {}", partial_path.display()))?;
+    if upload_length == 0 {
+        complete_resumable_upload(&conn, write_lock, vrac_config, storage, &db_file).await?;
+    }
+
+    let location = rocket::uri!(tus_head(&dbtoken.path, db_file.id));
+    Ok(Some(
+        TusResponse::new(http::Status::Created).header("Location", location),
+    ))
+}
+
+#[rocket::head("/f/<tok>/tus/<f_id>")]
+async fn tus_head(
+    tok: &str,
+    f_id: i32,
+    conn: VracDbConn,
+    headers: RequestHeaders<'_>,
+) -> errors::Result<Option<TusResponse>> {
+    if !headers.is_tus_supported() {
+        return Ok(Some(TusResponse::unsupported_version()));
+    }
+
+    let file = match get_resumable_upload(&conn, tok, f_id).await? {
+        Some(f) => f,
+        None => return Ok(None),
+    };
+    let offset = match file.file_upload_status {
+        db::FileUploadStatus::Completed => file.upload_length,
+        _ => file.upload_offset,
+    };
+    Ok(Some(
+        TusResponse::new(http::Status::Ok)
+            .header("Upload-Offset", offset.unwrap_or(0))
+            .header("Upload-Length", file.upload_length.unwrap_or(0))
+            .header("Cache-Control", "no-store"),
+    ))
+}
+
+/// append a chunk of data to a resumable upload. The upload is completed
+/// once all the bytes announced at creation have been received.
+#[rocket::patch("/f/<tok>/tus/<f_id>", data = "<data>")]
+#[allow(clippy::too_many_arguments)]
+async fn tus_patch(
+    tok: &str,
+    f_id: i32,
+    conn: VracDbConn,
+    data: Data<'_>,
+    headers: RequestHeaders<'_>,
+    write_lock: &rocket::State<WriteLock>,
+    resumables: &rocket::State<ResumableUploads>,
+    progress: &rocket::State<UploadProgress>,
+    vrac_config: &rocket::State<VracConfig>,
+    storage: &rocket::State<Storage>,
+) -> errors::Result<Option<TusResponse>> {
+    if !headers.is_tus_supported() {
+        return Ok(Some(TusResponse::unsupported_version()));
+    }
+    if headers.0.get_one("Content-Type") != Some("application/offset+octet-stream") {
+        return Ok(Some(TusResponse::new(http::Status::UnsupportedMediaType)));
+    }
+
+    let file = match get_resumable_upload(&conn, tok, f_id).await? {
+        Some(f) => f,
+        None => return Ok(None),
+    };
+    if let db::FileUploadStatus::Completed = file.file_upload_status {
+        // the response to the last PATCH was lost, nothing more to append
+        let upload_length = file.upload_length.unwrap_or(0) as u64;
+        return Ok(Some(
+            if headers.get_u64("Upload-Offset") == Some(upload_length) {
+                TusResponse::new(http::Status::NoContent).header("Upload-Offset", upload_length)
+            } else {
+                TusResponse::new(http::Status::Conflict)
+            },
+        ));
+    }
+    let _patch_guard = match ResumableGuard::acquire(resumables, f_id) {
+        Some(g) => g,
+        None => return Ok(Some(TusResponse::new(http::Status::Locked))),
+    };
+    let upload_length = file.upload_length.unwrap_or(0) as u64;
+    let current_offset = file.upload_offset.unwrap_or(0) as u64;
+    if headers.get_u64("Upload-Offset") != Some(current_offset) {
+        return Ok(Some(TusResponse::new(http::Status::Conflict)));
+    }
+    if current_offset == upload_length {
+        // everything was received but the upload couldn't be completed,
+        // an encrypted upload can't be resumed after its last chunk.
+        complete_resumable_upload(&conn, write_lock, vrac_config, storage, &file).await?;
+        return Ok(Some(
+            TusResponse::new(http::Status::NoContent).header("Upload-Offset", upload_length),
+        ));
+    }
+
+    let partial_path = file.partial_path();
+    let partial_path_string = partial_path.to_string_lossy().to_string();
+    let key = file
+        .wrapped_key
+        .as_deref()
+        .map(|k| vrac_config.file_key(k))
+        .transpose()?;
+    // Encrypted uploads can only be resumed after a whole chunk, the bytes
+    // received after the last one are dropped and the client sends them
+    // again, starting from the returned offset.
+    let mut encryptor = match &key {
+        Some(key) => Some(
+            storage
+                .open(&partial_path)
+                .and_then(|f| Encryptor::resume(key, f, current_offset))
+                .with_context(|| format!("Cannot resume encryption of {}", partial_path_string))?,
+        ),
+        None => None,
+    };
+    let stored_size = match encryptor {
+        Some(_) => encryption::encrypted_size(current_offset),
+        None => current_offset,
+    };
+    let mut writer = storage
+        .open_for_write(&partial_path)
+        .map(fs::File::from_std)
+        .with_context(|| format!("Error opening file {} for write", partial_path_string))?;
+    // a previous request may have written some bytes to disk without
+    // recording them in the DB, only the recorded offset can be trusted.
+    writer
+        .set_len(stored_size)
+        .await
+        .with_context(|| format!("Cannot truncate {}", partial_path_string))?;
+    writer.seek(std::io::SeekFrom::End(0)).await?;
+
+    let remaining = upload_length - current_offset;
+    let mut reader = data.open(remaining.bytes());
+    let mut buf = vec![0; 64 * 1024];
+    let mut encrypted = Vec::new();
+    let mut received = current_offset;
+    let tracker = ProgressGuard::start(progress, f_id, current_offset, Some(upload_length));
+    let write_result: errors::Result<()> = async {
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            let content = match &mut encryptor {
+                Some(encryptor) => {
+                    encrypted.clear();
+                    encryptor.update(&buf[..n], &mut encrypted)?;
+                    &encrypted[..]
+                }
+                None => &buf[..n],
+            };
+            writer
+                .write_all(content)
+                .await
+                .with_context(|| format!("Error writing to file {}", partial_path_string))?;
+            received += n as u64;
+            tracker.set_written(received);
+        }
+        encrypted.clear();
+        if received == upload_length {
+            if let Some(encryptor) = encryptor.take() {
+                encryptor.finish(&mut encrypted)?;
+            }
+        } else if let Some(encryptor) = &mut encryptor {
+            encryptor.flush(&mut encrypted)?;
         }
+        writer
+            .write_all(&encrypted)
+            .await
+            .with_context(|| format!("Error writing to file {}", partial_path_string))?;
+        writer
+            .flush()
+            .await
+            .with_context(|| format!("Error writing to file {}", partial_path_string))?;
+        Ok(())
+    }
+    .await;
+    drop(tracker);
+    let offset = match &encryptor {
+        Some(encryptor) => encryptor.encrypted_offset(),
+        None => received,
     };
 
+    // whatever happened, keep track of what has been written so the client
+    // can resume from there.
+    log_err(
+        &format!("Error syncing file {partial_path_string}"),
+        writer.sync_data().await,
+    );
     {
         let _guard = write_lock.0.lock().await;
-        conn.run(move |c| db::complete_upload(c, db_file.id))
+        conn.run(move |c| db::set_upload_offset(c, f_id, offset as _))
             .await?;
     }
+    write_result?;
 
-    log::info!(
-        "for file {} wrote {} - {} MiB",
-        file_path_string,
-        file_size,
-        file_size.as_u64()
-    );
+    if offset == upload_length {
+        complete_resumable_upload(&conn, write_lock, vrac_config, storage, &file).await?;
+    }
 
-    Ok(())
+    Ok(Some(
+        TusResponse::new(http::Status::NoContent).header("Upload-Offset", offset),
+    ))
 }
 
-/// Discard a Result, if it's an error, log it as error prepended with the given message.
-fn log_err<A, E>(msg: &str, err: Result<A, E>)
-where
-    E: std::fmt::Debug,
-{
-    match err {
-        Ok(_) => (),
-        Err(err) => log::error!("{msg} - {err:?}"),
+/// tus termination extension: the client gives up on an upload.
+#[rocket::delete("/f/<tok>/tus/<f_id>")]
+async fn tus_delete(
+    tok: &str,
+    f_id: i32,
+    conn: VracDbConn,
+    headers: RequestHeaders<'_>,
+    write_lock: &rocket::State<WriteLock>,
+    storage: &rocket::State<Storage>,
+) -> errors::Result<Option<TusResponse>> {
+    if !headers.is_tus_supported() {
+        return Ok(Some(TusResponse::unsupported_version()));
     }
-}
-
-/// read a given field in the multipart body, and attempt to write it to disk.
-async fn write_file(field: &mut Field<'_>, file_path: PathBuf) -> errors::Result<ByteUnit> {
-    let file_path_string = file_path.to_string_lossy().to_string();
 
-    let mut writer = fs::OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(&file_path)
-        .await
-        .with_context(|| format!("Error opening file {} for write", file_path_string))?;
-
-    // let mut writer = TempFile::new(file_path.to_path_buf())
-    //     .await
-    //     .with_context(|| format!("Error opening file {} for write", file_path_string))?;
+    let file = match get_resumable_upload(&conn, tok, f_id).await? {
+        Some(f) if matches!(f.file_upload_status, db::FileUploadStatus::Started) => f,
+        _ => return Ok(None),
+    };
+    {
+        let _guard = write_lock.0.lock().await;
+        conn.run(move |c| db::abort_upload(c, f_id)).await?;
+    }
+    let partial_path = file.partial_path();
+    log_err(
+        &format!("Error deleting the file at {}", partial_path.display()),
+        storage.remove_file(&partial_path),
+    );
+    Ok(Some(TusResponse::new(http::Status::NoContent)))
+}
 
-    let mut file_size = ByteUnit::Mebibyte(0);
-    while let Some(chunk) = field.chunk().await.transpose() {
-        let mut chunk = match chunk {
-            Ok(c) => c,
-            Err(err) => {
-                // TODO: here I can catch the exact error for size exceeded
-                log::error!("got an error while reading a chunk: {:?}", err);
-                return Err(errors::VracError::FileSizeExceeded);
-            }
+async fn get_resumable_upload(
+    conn: &VracDbConn,
+    tok: &str,
+    f_id: i32,
+) -> errors::Result<Option<db::File>> {
+    let tokstr = tok.to_string();
+    conn.run(move |c| {
+        let token = match db::get_valid_token(c, tokstr)? {
+            Some(t) => t,
+            None => return Ok(None),
         };
+        db::get_resumable_upload(c, &token, f_id)
+    })
+    .await
+}
 
-        file_size = file_size + chunk.len().bytes();
-        log::debug!(
-            "written so far: {}  (wrote {})",
-            file_size,
-            chunk.len().bytes()
-        );
-        writer
-            .write_all_buf(&mut chunk)
-            .await
-            .with_context(|| format!("Error writing to file {}", file_path_string))?;
-        writer.flush().await.unwrap();
+async fn complete_resumable_upload(
+    conn: &VracDbConn,
+    write_lock: &rocket::State<WriteLock>,
+    vrac_config: &VracConfig,
+    storage: &Storage,
+    file: &db::File,
+) -> errors::Result<()> {
+    // the content came in several requests, so the digest is only computed
+    // once everything is on disk.
+    let key = file
+        .wrapped_key
+        .as_deref()
+        .map(|k| vrac_config.file_key(k))
+        .transpose()?;
+    let written = digest_file(storage, &file.partial_path(), key).await?;
+    let token_id = file.token_id;
+    let token = conn.run(move |c| db::get_token(c, token_id)).await?;
+    let mut written = match check_detected_type(vrac_config, &token, file, written) {
+        Ok(written) => written,
+        Err(err) => {
+            let file_id = file.id;
+            {
+                let _guard = write_lock.0.lock().await;
+                let r = conn.run(move |c| db::abort_upload(c, file_id)).await;
+                log_err("Error deleting file in the DB", r);
+            }
+            log_err(
+                "Error deleting rejected upload",
+                storage.remove_file(file.partial_path()),
+            );
+            return Err(err);
+        }
+    };
+    let wrapped_key = encode_upload(vrac_config, storage, file, &mut written).await?;
+    let key = wrapped_key
+        .as_deref()
+        .map(|k| vrac_config.file_key(k))
+        .transpose()?;
+    if let Err(err) = sanitize_upload(
+        vrac_config,
+        storage,
+        &token,
+        file,
+        key.clone(),
+        &mut written,
+    )
+    .await
+    {
+        discard_upload(conn, write_lock, storage, file).await;
+        return Err(err);
     }
-    writer
-        .shutdown()
-        .await
-        .with_context(|| format!("Error writing to file {}", file_path_string))?;
-    Ok(file_size)
+    let path = file.partial_path();
+    if let Some(reason) =
+        scan_upload(vrac_config, storage, path, key.clone(), written.compression).await
+    {
+        let _guard = write_lock.0.lock().await;
+        quarantine_upload(conn, storage, file, written, wrapped_key, reason).await?;
+        let name = file.name.clone().unwrap_or_default();
+        return Err(errors::VracError::Quarantined(name));
+    }
+    index_upload(storage, file, key, &mut written).await;
+    let _guard = write_lock.0.lock().await;
+    finalize_upload(conn, vrac_config, storage, file, written, wrapped_key).await?;
+    conn.run(move |c| {
+        db::consume_token(c, token)?;
+        Ok(())
+    })
+    .await
 }
 
 #[database("sqlite_vrac")]
@@ -528,8 +3223,188 @@ struct VracDbConn(diesel::SqliteConnection);
 // simplify sqlite tx by only supporting one writer at a time.
 struct WriteLock(Mutex<()>);
 
-fn build_app() -> rocket::Rocket<rocket::Build> {
-    rocket::custom(rocket::Config::figment())
+/// ids of the resumable uploads currently receiving data, so that two
+/// concurrent PATCH requests cannot append to the same file.
+#[derive(Default)]
+struct ResumableUploads(std::sync::Mutex<std::collections::HashSet<i32>>);
+
+struct ResumableGuard<'a> {
+    uploads: &'a ResumableUploads,
+    file_id: i32,
+}
+
+impl<'a> ResumableGuard<'a> {
+    fn acquire(uploads: &'a ResumableUploads, file_id: i32) -> Option<Self> {
+        let mut in_progress = uploads.0.lock().unwrap();
+        if in_progress.insert(file_id) {
+            Some(ResumableGuard { uploads, file_id })
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for ResumableGuard<'_> {
+    fn drop(&mut self) {
+        self.uploads.0.lock().unwrap().remove(&self.file_id);
+    }
+}
+
+/// Who's downloading a file. Players fetch a content in many ranges, and
+/// downloads get resumed, so a client is only counted once per file, and
+/// counted again only after [`COUNTED_FOR`].
+struct Downloader<'r> {
+    client: Option<std::net::IpAddr>,
+    counted: &'r CountedDownloads,
+    write_lock: &'r WriteLock,
+}
+
+#[rocket::async_trait]
+impl<'r> request::FromRequest<'r> for Downloader<'r> {
+    type Error = ();
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let counted = rocket::outcome::try_outcome!(
+            request.guard::<&rocket::State<CountedDownloads>>().await
+        );
+        let write_lock =
+            rocket::outcome::try_outcome!(request.guard::<&rocket::State<WriteLock>>().await);
+        request::Outcome::Success(Downloader {
+            client: request.client_ip(),
+            counted: counted.inner(),
+            write_lock: write_lock.inner(),
+        })
+    }
+}
+
+impl Downloader<'_> {
+    /// whether a download of the file was already counted for this client
+    fn is_counted(&self, file_id: i32) -> bool {
+        self.counted.contains(self.client, file_id)
+    }
+
+    /// Count a download of the file, unless it was already counted for
+    /// this client. False if the token reached its `max_downloads`.
+    async fn record(
+        &self,
+        conn: &VracDbConn,
+        token: &db::Token,
+        file_id: i32,
+    ) -> errors::Result<bool> {
+        if self.is_counted(file_id) {
+            return Ok(true);
+        }
+        if !record_download(conn, self.write_lock, token, vec![file_id]).await? {
+            return Ok(false);
+        }
+        self.counted.insert(self.client, file_id);
+        Ok(true)
+    }
+}
+
+/// a client downloading a file again after that long is counted again
+const COUNTED_FOR: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// when the downloads were counted, by client address and file id
+#[derive(Default)]
+struct CountedDownloads(
+    std::sync::Mutex<std::collections::HashMap<(std::net::IpAddr, i32), std::time::Instant>>,
+);
+
+impl CountedDownloads {
+    fn contains(&self, client: Option<std::net::IpAddr>, file_id: i32) -> bool {
+        let client = match client {
+            Some(client) => client,
+            None => return false,
+        };
+        let counted = self.0.lock().unwrap();
+        counted
+            .get(&(client, file_id))
+            .is_some_and(|at| at.elapsed() < COUNTED_FOR)
+    }
+
+    fn insert(&self, client: Option<std::net::IpAddr>, file_id: i32) {
+        if let Some(client) = client {
+            let mut counted = self.0.lock().unwrap();
+            counted.retain(|_, at| at.elapsed() < COUNTED_FOR);
+            counted.insert((client, file_id), std::time::Instant::now());
+        }
+    }
+}
+
+/// how much has been written so far for the uploads receiving data, by file id
+#[derive(Default)]
+struct UploadProgress(std::sync::Mutex<std::collections::HashMap<i32, FileProgress>>);
+
+impl UploadProgress {
+    fn get(&self, file_id: i32) -> Option<FileProgress> {
+        self.0.lock().unwrap().get(&file_id).copied()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FileProgress {
+    written: u64,
+    /// unknown for the files of a multipart form
+    total: Option<u64>,
+    /// already written when the request started, for resumed uploads
+    initial: u64,
+    started_at: std::time::Instant,
+}
+
+impl FileProgress {
+    fn bytes_per_second(&self) -> Option<u64> {
+        let elapsed = self.started_at.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            Some(((self.written - self.initial) as f64 / elapsed) as u64)
+        } else {
+            None
+        }
+    }
+
+    fn eta_seconds(&self) -> Option<u64> {
+        match (self.total, self.bytes_per_second()) {
+            (Some(total), Some(speed)) if speed > 0 => {
+                Some(total.saturating_sub(self.written) / speed)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// tracks the progress of an upload in [`UploadProgress`] until dropped
+struct ProgressGuard<'a> {
+    progress: &'a UploadProgress,
+    file_id: i32,
+}
+
+impl<'a> ProgressGuard<'a> {
+    fn start(progress: &'a UploadProgress, file_id: i32, initial: u64, total: Option<u64>) -> Self {
+        let file_progress = FileProgress {
+            written: initial,
+            total,
+            initial,
+            started_at: std::time::Instant::now(),
+        };
+        progress.0.lock().unwrap().insert(file_id, file_progress);
+        ProgressGuard { progress, file_id }
+    }
+
+    fn set_written(&self, written: u64) {
+        if let Some(p) = self.progress.0.lock().unwrap().get_mut(&self.file_id) {
+            p.written = written;
+        }
+    }
+}
+
+impl Drop for ProgressGuard<'_> {
+    fn drop(&mut self) {
+        self.progress.0.lock().unwrap().remove(&self.file_id);
+    }
+}
+
+fn build_app(figment: figment::Figment) -> rocket::Rocket<rocket::Build> {
+    rocket::custom(figment)
         .mount(
             "/",
             rocket::routes![
@@ -540,30 +3415,72 @@ fn build_app() -> rocket::Rocket<rocket::Build> {
                 gen_token_post_pecore,
                 get_file,
                 upload_files,
-                download_file
+                put_file,
+                get_progress,
+                get_metadata,
+                download_file,
+                head_file,
+                download_zip,
+                download_tar,
+                download_entry,
+                get_thumbnail,
+                view_file,
+                tus_options,
+                tus_create,
+                tus_head,
+                tus_patch,
+                tus_delete,
             ],
         )
         .attach(Template::fairing())
         .attach(VracDbConn::fairing())
         .attach(AdHoc::config::<VracConfig>())
+        .attach(AdHoc::try_on_ignite("Storage", |rocket| async {
+            let root_path = match rocket.state::<VracConfig>() {
+                Some(config) => config.root_path.clone(),
+                None => return Err(rocket),
+            };
+            match Storage::new(&root_path) {
+                Ok(storage) => Ok(rocket.manage(storage)),
+                Err(err) => {
+                    log::error!("Cannot open root path {}: {err:?}", root_path.display());
+                    Err(rocket)
+                }
+            }
+        }))
         .manage(WriteLock(Mutex::new(())))
+        .manage(ResumableUploads::default())
+        .manage(UploadProgress::default())
+        .manage(CountedDownloads::default())
+        .manage(Highlighter::new())
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let app = build_app().ignite().await?;
+    let app = build_app(rocket::Config::figment()).ignite().await?;
 
     let pool = VracDbConn::get_one(&app)
         .await
         .ok_or("Cannot access connection pool")?;
 
+    // no upload can be in progress before the server is launched
+    let storage = app
+        .state::<Storage>()
+        .ok_or("Cannot access storage")?
+        .clone();
+    let sweep_storage = storage.clone();
+    pool.run(move |c| {
+        cleanup::remove_partial_uploads(c, &sweep_storage).map_err(|err| format!("{:?}", err))
+    })
+    .await?;
+
     let web_server = async {
         app.launch().await?;
         Ok::<_, Box<dyn std::error::Error>>(())
     };
 
     let background_job = async {
-        pool.run(|c| cleanup::cleanup_once(c).map_err(|err| format!("{:?}", err)))
+        pool.run(move |c| cleanup::cleanup_once(c, &storage).map_err(|err| format!("{:?}", err)))
             .await?;
         Ok(())
     };
@@ -659,3 +3576,340 @@ where
         None => Ok(None),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diesel::{Connection, ExpressionMethods, QueryDsl, RunQueryDsl};
+    use figment::providers::Serialized;
+    use rocket::local::asynchronous::{Client, LocalRequest};
+
+    /// A server with its own DB and root path, in a directory removed
+    /// once it's dropped.
+    struct TestServer {
+        client: Client,
+        dir: PathBuf,
+    }
+
+    impl TestServer {
+        async fn new() -> Self {
+            Self::with_config(&[]).await
+        }
+
+        /// a server with the given values in its configuration
+        async fn with_config(config: &[(&str, &str)]) -> Self {
+            static COUNT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+            let n = COUNT.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let dir = std::env::temp_dir().join(format!("vrac-test-{}-{n}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let db_path = dir.join("vrac.sqlite").to_string_lossy().to_string();
+            let conn = diesel::SqliteConnection::establish(&db_path).unwrap();
+            let migrations = Path::new(env!("CARGO_MANIFEST_DIR")).join("migrations");
+            diesel_migrations::run_pending_migrations_in_directory(
+                &conn,
+                &migrations,
+                &mut std::io::sink(),
+            )
+            .unwrap();
+
+            let templates = Path::new(env!("CARGO_MANIFEST_DIR")).join("templates");
+            let mut figment = rocket::Config::figment()
+                .merge(Serialized::global("databases.sqlite_vrac.url", &db_path))
+                .merge(Serialized::global("root_path", dir.join("files")))
+                .merge(Serialized::global("template_dir", templates))
+                .merge(Serialized::global("log_level", "off"));
+            for (key, value) in config {
+                figment = figment.merge(Serialized::global(key, value));
+            }
+            let client = Client::tracked(build_app(figment)).await.unwrap();
+            TestServer { client, dir }
+        }
+
+        fn conn(&self) -> diesel::SqliteConnection {
+            let db_path = self.dir.join("vrac.sqlite");
+            diesel::SqliteConnection::establish(&db_path.to_string_lossy()).unwrap()
+        }
+
+        fn create_token(&self, path: &str, max_downloads: Option<u32>) {
+            self.create_link(path, true, max_downloads)
+        }
+
+        /// a link for a single upload, or a drop box when `open`
+        fn create_link(&self, path: &str, open: bool, max_downloads: Option<u32>) {
+            let token = db::CreateToken {
+                path: path.to_string(),
+                max_size_in_mib: None,
+                token_expires_at: (chrono::Utc::now() + chrono::Duration::hours(1)).naive_utc(),
+                content_expires_after_hours: None,
+                allowed_types: Vec::new(),
+                open,
+                max_files: None,
+                strip_metadata: false,
+                max_downloads,
+            };
+            db::create_token(&mut self.conn(), token).unwrap();
+        }
+
+        /// upload a file, and returns its url
+        async fn upload(&self, token: &str, name: &str, content: &str) -> String {
+            let response = self
+                .client
+                .put(format!("/f/{token}/{name}"))
+                .body(content)
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), http::Status::Created);
+            let url = response.into_string().await.unwrap();
+            // without a Host header, it's only the path
+            url.trim().to_string()
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn client_addr(last: u8) -> std::net::SocketAddr {
+        std::net::SocketAddr::from(([192, 0, 2, last], 40000))
+    }
+
+    #[rocket::async_test]
+    async fn counted_client_continues_past_the_download_limit() {
+        let server = TestServer::new().await;
+        server.create_token("limited", Some(1));
+        let url = server.upload("limited", "a.txt", "0123456789").await;
+
+        let range = |range: &'static str, last| {
+            server
+                .client
+                .get(url.clone())
+                .header(http::Header::new("Range", range))
+                .remote(client_addr(last))
+        };
+        // the only download allowed, the player then asks for other ranges
+        let response = range("bytes=0-1", 1).dispatch().await;
+        assert_eq!(response.status(), http::Status::PartialContent);
+        assert_eq!(response.into_string().await.unwrap(), "01");
+        let response = range("bytes=5-", 1).dispatch().await;
+        assert_eq!(response.status(), http::Status::PartialContent);
+        assert_eq!(response.into_string().await.unwrap(), "56789");
+
+        // anyone else is past the limit
+        let response = range("bytes=0-1", 2).dispatch().await;
+        assert_eq!(response.status(), http::Status::NotFound);
+        let response = server
+            .client
+            .get(url.clone())
+            .remote(client_addr(2))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), http::Status::NotFound);
+    }
+
+    #[rocket::async_test]
+    async fn only_drop_boxes_outlive_their_link() {
+        let server = TestServer::new().await;
+        server.create_link("once", false, None);
+        server.create_link("box", true, None);
+        server.upload("once", "a.txt", "a").await;
+        server.upload("box", "b.txt", "b").await;
+
+        // both links expire, their content never does
+        let conn = server.conn();
+        let past = (chrono::Utc::now() - chrono::Duration::minutes(1)).naive_utc();
+        diesel::update(vrac::schema::token::table)
+            .set(vrac::schema::token::token_expires_at.eq(past))
+            .execute(&conn)
+            .unwrap();
+        assert!(db::get_valid_token(&conn, "once".to_string())
+            .unwrap()
+            .is_none());
+        assert!(db::get_valid_token(&conn, "box".to_string())
+            .unwrap()
+            .is_some());
+
+        assert_eq!(db::delete_expired_tokens(&conn).unwrap(), vec!["once"]);
+        let token = db::get_valid_token(&conn, "box".to_string())
+            .unwrap()
+            .unwrap();
+        assert_eq!(token.status, db::TokenStatus::Closed);
+    }
+
+    #[rocket::async_test]
+    async fn completion_of_encrypted_upload_can_be_retried() {
+        let key = encryption::generate_key();
+        let server = TestServer::with_config(&[("master_key", &key)]).await;
+        server.create_token("box", None);
+        fn tus(request: LocalRequest<'_>) -> LocalRequest<'_> {
+            request.header(http::Header::new("Tus-Resumable", TUS_VERSION))
+        }
+        let response = tus(server.client.post("/f/box/tus"))
+            .header(http::Header::new("Upload-Length", "5"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), http::Status::Created);
+        let location = response.headers().get_one("Location").unwrap().to_string();
+        let patch = |offset: &'static str, body: &'static str| {
+            tus(server.client.patch(location.clone()))
+                .header(http::Header::new(
+                    "Content-Type",
+                    "application/offset+octet-stream",
+                ))
+                .header(http::Header::new("Upload-Offset", offset))
+                .body(body)
+        };
+        let response = patch("0", "hello").dispatch().await;
+        assert_eq!(response.status(), http::Status::NoContent);
+
+        // as if completing the upload had failed once everything was written
+        let conn = server.conn();
+        let file: db::File = vrac::schema::file::table.first(&conn).unwrap();
+        let root = server.dir.join("files");
+        std::fs::rename(root.join(&file.path), root.join(file.partial_path())).unwrap();
+        diesel::update(vrac::schema::file::table)
+            .set(vrac::schema::file::file_upload_status.eq(db::FileUploadStatus::Started))
+            .execute(&conn)
+            .unwrap();
+
+        let response = patch("5", "").dispatch().await;
+        assert_eq!(response.status(), http::Status::NoContent);
+        assert_eq!(response.headers().get_one("Upload-Offset"), Some("5"));
+        let response = server
+            .client
+            .get(format!("/f/box/{}", file.id))
+            .dispatch()
+            .await;
+        assert_eq!(response.into_string().await.unwrap(), "hello");
+    }
+
+    #[rocket::async_test]
+    async fn blocked_types_are_refused_whatever_the_name() {
+        let server = TestServer::new().await;
+        server.create_token("box", None);
+        let upload = |name: &'static str, content: &'static str| {
+            server.client.put(format!("/f/box/{name}")).body(content)
+        };
+        let response = upload("notes.txt", "<html><script>alert(1)</script></html>")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), http::Status::UnsupportedMediaType);
+        let response = upload("notes.txt", "hello").dispatch().await;
+        assert_eq!(response.status(), http::Status::Created);
+        let response = upload("notes.txt", "hello")
+            .header(http::ContentType::HTML)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), http::Status::UnsupportedMediaType);
+
+        let files: i64 = vrac::schema::file::table
+            .count()
+            .get_result(&server.conn())
+            .unwrap();
+        assert_eq!(files, 1);
+    }
+
+    #[rocket::async_test]
+    async fn active_types_are_served_as_text() {
+        let server = TestServer::new().await;
+        server.create_token("box", None);
+        let svg = "<?xml version=\"1.0\"?><svg xmlns=\"http://www.w3.org/2000/svg\">\
+                   <script>alert(1)</script></svg>";
+        let url = server.upload("box", "notes.txt", svg).await;
+        let response = server.client.get(url).dispatch().await;
+        assert_eq!(response.status(), http::Status::Ok);
+        assert_eq!(response.content_type(), Some(http::ContentType::Plain));
+        assert_eq!(
+            response.headers().get_one("X-Content-Type-Options"),
+            Some("nosniff")
+        );
+    }
+
+    #[rocket::async_test]
+    async fn failed_upload_removes_the_new_blobs() {
+        let server = TestServer::with_config(&[("storage", "content_addressed")]).await;
+        server.create_token("box", None);
+        let part = |name: &str, content: &str| {
+            format!(
+                "--b\r\nContent-Disposition: form-data; name=\"files\"; filename=\"{name}\"\r\n\
+                 Content-Type: text/plain\r\n\r\n{content}\r\n"
+            )
+        };
+        // the second file is refused once the first one is stored
+        let body = format!(
+            "{}{}--b--\r\n",
+            part("a.txt", "first file"),
+            part("b.html", "second file")
+        );
+        let response = server
+            .client
+            .post("/f/box")
+            .header(http::Header::new(
+                "Content-Type",
+                "multipart/form-data; boundary=b",
+            ))
+            .body(body)
+            .dispatch()
+            .await;
+        assert_ne!(response.status(), http::Status::Created);
+
+        let conn = server.conn();
+        let files: i64 = vrac::schema::file::table.count().get_result(&conn).unwrap();
+        let blobs: i64 = vrac::schema::blob::table.count().get_result(&conn).unwrap();
+        assert_eq!((files, blobs), (0, 0));
+        let blobs_dir = server.dir.join("files").join(storage::BLOBS_DIR);
+        let stored: Vec<_> = std::fs::read_dir(blobs_dir)
+            .into_iter()
+            .flatten()
+            .flat_map(|prefix| std::fs::read_dir(prefix.unwrap().path()).unwrap())
+            .map(|blob| blob.unwrap().path())
+            .collect();
+        assert!(stored.is_empty(), "{stored:?}");
+    }
+
+    #[test]
+    fn ascii_names() {
+        assert_eq!(
+            content_disposition(false, Some("report 2021.pdf")),
+            "inline; filename=\"report 2021.pdf\""
+        );
+        assert_eq!(
+            content_disposition(true, Some("dir/sub\\notes.txt")),
+            "attachment; filename=\"notes.txt\""
+        );
+    }
+
+    #[test]
+    fn no_name() {
+        assert_eq!(content_disposition(false, None), "inline");
+        assert_eq!(content_disposition(true, Some("")), "attachment");
+        assert_eq!(content_disposition(true, Some("dir/")), "attachment");
+    }
+
+    #[test]
+    fn non_ascii_names() {
+        assert_eq!(
+            content_disposition(true, Some("été à Paris.jpg")),
+            "attachment; filename=\"_t_ _ Paris.jpg\"; \
+             filename*=UTF-8''%C3%A9t%C3%A9%20%C3%A0%20Paris.jpg"
+        );
+    }
+
+    #[test]
+    fn quotes_and_backslashes() {
+        assert_eq!(
+            content_disposition(false, Some("say \"hi\".txt")),
+            "inline; filename=\"say _hi_.txt\"; filename*=UTF-8''say%20%22hi%22.txt"
+        );
+        assert_eq!(
+            content_disposition(false, Some("a\r\nb;c.txt")),
+            "inline; filename=\"a__b;c.txt\"; filename*=UTF-8''a%0D%0Ab%3Bc.txt"
+        );
+        // a backslash is taken as a directory separator, like a slash
+        assert_eq!(
+            content_disposition(false, Some("a\\\"b\".txt")),
+            "inline; filename=\"_b_.txt\"; filename*=UTF-8''%22b%22.txt"
+        );
+    }
+}
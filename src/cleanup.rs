@@ -1,20 +1,40 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use std::{error::Error, io::ErrorKind};
 
 use diesel::SqliteConnection;
 
 use crate::db;
+use crate::storage::{self, Storage};
+use crate::thumbnail;
 
 /// checks the DB for expired tokens and remove the associated files, then
 /// delete the tokens.
-pub fn cleanup_once(conn: &SqliteConnection) -> Result<(), Box<dyn Error>> {
+pub fn cleanup_once(conn: &SqliteConnection, storage: &Storage) -> Result<(), Box<dyn Error>> {
     log::debug!("cleaning up files");
     let stuff_to_del = db::get_expired_files(conn)?;
     let n_tok = stuff_to_del.len();
     let mut n = 0;
+    let mut dirs_to_del = Vec::new();
     for (token, files) in stuff_to_del {
         for file in files {
+            if let Some(original_path) = &file.original_path {
+                log::info!("Removing original of file {} at {original_path}", file.id);
+                match storage.remove_file(original_path) {
+                    Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+                    _ => (),
+                }
+            }
+            if file.blob_sha256.is_some() {
+                // the content may be shared with other files, it's removed
+                // below once nothing references it anymore.
+                continue;
+            }
             log::info!("Removing file at {} with id {}", file.path, file.id);
-            match std::fs::remove_file(&file.path) {
+            if let Err(err) = thumbnail::remove(storage, &file.path) {
+                log::error!("Could not remove the thumbnail of {}: {err:?}", file.path);
+            }
+            match storage.remove_file(&file.path) {
                 Ok(_) => (),
                 Err(err) => match err.kind() {
                     ErrorKind::NotFound => log::error!(
@@ -28,13 +48,22 @@ pub fn cleanup_once(conn: &SqliteConnection) -> Result<(), Box<dyn Error>> {
                 },
             }
         }
+        dirs_to_del.push(token.path.clone());
         n += db::delete_files(conn, &[token])?;
     }
     log::info!("deleted a total of {n} files for {} tokens", n_tok);
 
+    remove_unreferenced_blobs(conn, storage)?;
+
     let del_token_paths = db::delete_expired_tokens(conn)?;
-    for path in &del_token_paths {
-        match std::fs::remove_dir_all(&path) {
+    for path in dirs_to_del.iter().chain(&del_token_paths) {
+        // tokens created before paths were validated could be anything,
+        // like `.` or `blobs`, don't remove what isn't their own directory.
+        if !storage::is_valid_token_path(path) {
+            log::error!("Not removing the directory of the token with path {path:?}");
+            continue;
+        }
+        match storage.remove_dir_all(path) {
             Ok(_) => (),
             // if for some reason, the directory isn't there, ignore the error
             Err(err) if err.kind() == ErrorKind::NotFound => (),
@@ -50,3 +79,85 @@ pub fn cleanup_once(conn: &SqliteConnection) -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+/// remove the blobs which aren't used by any file anymore, both on disk and in the DB.
+pub fn remove_unreferenced_blobs(
+    conn: &SqliteConnection,
+    storage: &Storage,
+) -> Result<(), Box<dyn Error>> {
+    let blobs = db::get_unreferenced_blobs(conn)?;
+    for blob in &blobs {
+        log::info!("Removing blob {} at {}", blob.sha256, blob.path);
+        if let Err(err) = thumbnail::remove(storage, &blob.path) {
+            log::error!("Could not remove the thumbnail of {}: {err:?}", blob.path);
+        }
+        match storage.remove_file(&blob.path) {
+            Ok(_) => (),
+            Err(err) if err.kind() == ErrorKind::NotFound => log::error!(
+                "Attempted to delete blob at {} but didn't find anything.",
+                blob.path
+            ),
+            Err(err) => {
+                log::error!("Could not remove blob at {}: {err:?}", blob.path);
+                return Err(err.into());
+            }
+        }
+        db::delete_blob(conn, &blob.sha256)?;
+    }
+    log::info!("deleted {} unreferenced blobs", blobs.len());
+    Ok(())
+}
+
+/// Uploads interrupted by a restart of the server leave a partial file behind.
+/// Resumable uploads can be continued by the client so they are kept, the
+/// other ones are aborted and their partial files are removed.
+/// This must not run while uploads are in progress.
+pub fn remove_partial_uploads(
+    conn: &SqliteConnection,
+    storage: &Storage,
+) -> Result<(), Box<dyn Error>> {
+    let mut to_keep = HashSet::new();
+    for file in db::get_started_uploads(conn)? {
+        if file.upload_length.is_some() {
+            to_keep.insert(storage.relative(&file.partial_path()).to_path_buf());
+        } else {
+            log::info!("Aborting interrupted upload of file {}", file.id);
+            db::abort_upload(conn, file.id)?;
+        }
+    }
+
+    let mut n = 0;
+    for path in find_partial_files(storage, Path::new(""))? {
+        if to_keep.contains(&path) {
+            continue;
+        }
+        log::info!("Removing partial file at {}", path.display());
+        match storage.remove_file(&path) {
+            Ok(_) => n += 1,
+            Err(err) if err.kind() == ErrorKind::NotFound => (),
+            Err(err) => return Err(err.into()),
+        }
+    }
+    log::info!("removed {n} partial files");
+    Ok(())
+}
+
+/// paths of the partial files under `dir`, relative to the root path
+fn find_partial_files(storage: &Storage, dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut result = Vec::new();
+    let entries = match storage.read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(result),
+        Err(err) => return Err(err),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let path = dir.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            result.extend(find_partial_files(storage, &path)?);
+        } else if path.extension().and_then(|e| e.to_str()) == Some(db::PARTIAL_EXTENSION) {
+            result.push(path);
+        }
+    }
+    Ok(result)
+}
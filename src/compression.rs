@@ -0,0 +1,96 @@
+use std::io::{self, Read, Write};
+
+const LEVEL: i32 = zstd::DEFAULT_COMPRESSION_LEVEL;
+
+/// the beginning of the content is compressed first to guess whether
+/// compressing the whole file is worth it.
+const SAMPLE_SIZE: usize = 256 * 1024;
+
+/// not worth the trouble for smaller files
+const MIN_SIZE: usize = 4 * 1024;
+
+/// Pictures, videos and archives are already compressed, and would only
+/// cost cpu time for nothing. Only compress when it saves at least 10%.
+fn is_worth(sample: &[u8]) -> io::Result<bool> {
+    if sample.len() < MIN_SIZE {
+        return Ok(false);
+    }
+    let compressed = zstd::bulk::compress(sample, LEVEL)?;
+    Ok(compressed.len() * 10 <= sample.len() * 9)
+}
+
+enum State {
+    Sampling(Vec<u8>),
+    Compressing(zstd::Encoder<'static, Vec<u8>>),
+    Raw,
+}
+
+/// Compresses content pushed chunk by chunk with zstd, once enough of it
+/// has been seen to know whether that's worth it. Otherwise the content
+/// goes through untouched.
+pub struct Compressor {
+    state: State,
+}
+
+impl Default for Compressor {
+    fn default() -> Self {
+        Compressor {
+            state: State::Sampling(Vec::new()),
+        }
+    }
+}
+
+impl Compressor {
+    /// what comes out, compressed or not, is appended to `out`
+    pub fn update(&mut self, data: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        match &mut self.state {
+            State::Sampling(sample) => {
+                sample.extend_from_slice(data);
+                if sample.len() >= SAMPLE_SIZE {
+                    let sample = std::mem::take(sample);
+                    self.decide(sample, out)?;
+                }
+            }
+            State::Compressing(encoder) => {
+                encoder.write_all(data)?;
+                out.append(encoder.get_mut());
+            }
+            State::Raw => out.extend_from_slice(data),
+        }
+        Ok(())
+    }
+
+    fn decide(&mut self, sample: Vec<u8>, out: &mut Vec<u8>) -> io::Result<()> {
+        if is_worth(&sample)? {
+            let mut encoder = zstd::Encoder::new(Vec::new(), LEVEL)?;
+            encoder.write_all(&sample)?;
+            out.append(encoder.get_mut());
+            self.state = State::Compressing(encoder);
+        } else {
+            out.extend_from_slice(&sample);
+            self.state = State::Raw;
+        }
+        Ok(())
+    }
+
+    /// flush what's left into `out`, and returns whether the content
+    /// was compressed.
+    pub fn finish(mut self, out: &mut Vec<u8>) -> io::Result<bool> {
+        if let State::Sampling(sample) = &mut self.state {
+            let sample = std::mem::take(sample);
+            self.decide(sample, out)?;
+        }
+        match self.state {
+            State::Compressing(encoder) => {
+                out.append(&mut encoder.finish()?);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+/// reads the decompressed content of `reader`
+pub fn decompress<'a, R: Read + Send + 'a>(reader: R) -> io::Result<impl Read + Send + 'a> {
+    zstd::Decoder::new(reader)
+}
@@ -0,0 +1,101 @@
+//! Figure out the type of a file from its content, since the content type
+//! sent by the client cannot be trusted.
+
+/// how many bytes at the start of a file are looked at to detect its type
+pub const HEAD_SIZE: usize = 8 * 1024;
+
+pub const TEXT: &str = "text/plain; charset=utf-8";
+pub const BINARY: &str = "application/octet-stream";
+
+/// detect the content type of a file from its first bytes, using the
+/// magic numbers of the known formats. Anything else is either text
+/// or some unknown binary.
+pub fn detect(head: &[u8]) -> String {
+    if let Some(t) = infer::get(head) {
+        return t.mime_type().to_string();
+    }
+    if looks_like_text(head) { TEXT } else { BINARY }.to_string()
+}
+
+fn looks_like_text(head: &[u8]) -> bool {
+    if head.contains(&0) {
+        return false;
+    }
+    match std::str::from_utf8(head) {
+        Ok(_) => true,
+        // the head may be cut in the middle of a multi bytes character
+        Err(err) => err.error_len().is_none(),
+    }
+}
+
+/// whether the content type matches the given pattern, which is either
+/// a full type like `application/pdf` or a wildcard like `image/*`.
+/// Parameters like `; charset=utf-8` are ignored.
+pub fn matches(pattern: &str, content_type: &str) -> bool {
+    let essence = |ct: &str| {
+        ct.split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase()
+    };
+    let pattern = essence(pattern);
+    let content_type = essence(content_type);
+    match pattern.strip_suffix("/*") {
+        Some(top_level) => content_type.split('/').next() == Some(top_level),
+        None => pattern == content_type,
+    }
+}
+
+/// types a browser runs the scripts of, `infer` detects web pages as
+/// `text/html` and any XML document as `text/xml`.
+const ACTIVE: &[&str] = &[
+    "text/html",
+    "text/xml",
+    "application/xml",
+    "application/xhtml+xml",
+    "image/svg+xml",
+];
+
+/// the type to serve a file with: the active types are served as text,
+/// a web page uploaded by anyone must not run from the site.
+pub fn served(content_type: &str) -> &str {
+    if ACTIVE.iter().any(|active| matches(active, content_type)) {
+        TEXT
+    } else {
+        content_type
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_types() {
+        assert!(matches("application/pdf", "application/pdf"));
+        assert!(matches("text/plain", TEXT));
+        assert!(matches("Text/Plain; charset=latin1", "text/plain"));
+        assert!(!matches("application/pdf", "application/zip"));
+        assert!(!matches("text/plain", "text/plainer"));
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("image/*", "image/png"));
+        assert!(matches("IMAGE/*", "image/jpeg"));
+        assert!(matches("text/*", TEXT));
+        assert!(!matches("image/*", "application/octet-stream"));
+        assert!(!matches("image/*", "imagex/png"));
+    }
+
+    #[test]
+    fn active_types_are_served_as_text() {
+        assert_eq!(served("text/html"), TEXT);
+        assert_eq!(served("Text/HTML; charset=utf-8"), TEXT);
+        assert_eq!(served("text/xml"), TEXT);
+        assert_eq!(served("image/svg+xml"), TEXT);
+        assert_eq!(served("image/png"), "image/png");
+        assert_eq!(served(TEXT), TEXT);
+    }
+}
@@ -12,8 +12,11 @@ use scrypt::{
 };
 use std::collections::HashMap;
 
+use crate::archive;
+use crate::content_type;
 use crate::errors;
-use crate::schema::{auth, file, token};
+use crate::schema::{archive_entry, auth, blob, file, token};
+use crate::storage;
 
 diesel_migrations::embed_migrations!("./migrations/");
 
@@ -32,6 +35,48 @@ pub struct Token {
     /// live for. At token creation, we can't set the expiration date.
     pub content_expires_after_hours: Option<i32>,
     pub deleted_at: Option<NaiveDateTime>,
+    /// comma separated patterns of accepted content types, see
+    /// [`content_type::matches`]. Anything is accepted when `None`.
+    pub allowed_types: Option<String>,
+    /// how many files can be uploaded with this token, no limit if `None`
+    pub max_files: Option<i32>,
+    /// remove the metadata of the pictures uploaded with this token
+    pub strip_metadata: bool,
+    /// the token is unavailable once its files have been downloaded that
+    /// many times, no limit if `None`
+    pub max_downloads: Option<i32>,
+    /// each file, or each archive of all the files, counts once
+    pub download_count: i32,
+}
+
+impl Token {
+    /// whether files can still be uploaded with this token
+    pub fn accepts_uploads(&self) -> bool {
+        matches!(self.status, TokenStatus::Fresh | TokenStatus::Open)
+            && self.token_expires_at >= Utc::now().naive_utc()
+    }
+
+    /// whether the files have been downloaded `max_downloads` times
+    pub fn download_limit_reached(&self) -> bool {
+        self.max_downloads
+            .is_some_and(|max| self.download_count >= max)
+    }
+
+    pub fn allowed_types(&self) -> Vec<&str> {
+        match &self.allowed_types {
+            Some(types) => types.split(',').map(|t| t.trim()).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// whether a file of the given content type can be uploaded with this token
+    pub fn accepts(&self, file_content_type: &str) -> bool {
+        self.allowed_types.is_none()
+            || self
+                .allowed_types()
+                .iter()
+                .any(|pattern| content_type::matches(pattern, file_content_type))
+    }
 }
 
 #[derive(Debug)]
@@ -40,6 +85,14 @@ pub struct CreateToken {
     pub max_size_in_mib: Option<u32>,
     pub token_expires_at: NaiveDateTime,
     pub content_expires_after_hours: Option<chrono::Duration>,
+    /// accept any type of file if empty
+    pub allowed_types: Vec<String>,
+    /// keep accepting uploads until the token expires, instead of
+    /// only one upload
+    pub open: bool,
+    pub max_files: Option<u32>,
+    pub strip_metadata: bool,
+    pub max_downloads: Option<u32>,
 }
 
 #[derive(Debug, Insertable)]
@@ -53,13 +106,24 @@ struct CreateTokenSQLite {
     content_expires_at: Option<NaiveDateTime>,
     content_expires_after_hours: Option<i32>,
     deleted_at: Option<NaiveDateTime>,
+    allowed_types: Option<String>,
+    max_files: Option<i32>,
+    strip_metadata: bool,
+    max_downloads: Option<i32>,
 }
 
 #[derive(Debug, FromSqlRow, AsExpression, Clone, Copy, Hash, PartialEq, Eq)]
 #[sql_type = "Text"]
 pub enum TokenStatus {
+    /// a link for a single upload, nothing uploaded yet
     Fresh,
+    /// a link for a single upload, the files have been uploaded
     Used,
+    /// a drop box, accepting uploads until it expires or holds
+    /// `max_files` files
+    Open,
+    /// a drop box which doesn't accept uploads anymore
+    Closed,
     Deleted,
 }
 
@@ -72,6 +136,8 @@ where
         match &(String::from_sql(bytes)?)[..] {
             "FRESH" => Ok(TokenStatus::Fresh),
             "USED" => Ok(TokenStatus::Used),
+            "OPEN" => Ok(TokenStatus::Open),
+            "CLOSED" => Ok(TokenStatus::Closed),
             "DELETED" => Ok(TokenStatus::Deleted),
             x => Err(format!("Unknown token status: {}", x).into()),
         }
@@ -89,6 +155,8 @@ where
         let tag = match self {
             TokenStatus::Fresh => "FRESH",
             TokenStatus::Used => "USED",
+            TokenStatus::Open => "OPEN",
+            TokenStatus::Closed => "CLOSED",
             TokenStatus::Deleted => "DELETED",
         };
         ToSql::<sql_types::Text, DB>::to_sql(tag, out)
@@ -101,6 +169,8 @@ diesel::no_arg_sql_function!(last_insert_rowid, sql_types::Integer);
 pub enum FileUploadStatus {
     Started,
     Completed,
+    /// flagged by the malware scanner, never served
+    Quarantined,
 }
 
 impl<DB> FromSql<sql_types::Text, DB> for FileUploadStatus
@@ -112,6 +182,7 @@ where
         match &(String::from_sql(bytes)?)[..] {
             "STARTED" => Ok(FileUploadStatus::Started),
             "COMPLETED" => Ok(FileUploadStatus::Completed),
+            "QUARANTINED" => Ok(FileUploadStatus::Quarantined),
             x => Err(format!("Unknown file upload status: {}", x).into()),
         }
     }
@@ -128,6 +199,51 @@ where
         let tag = match self {
             FileUploadStatus::Started => "STARTED",
             FileUploadStatus::Completed => "COMPLETED",
+            FileUploadStatus::Quarantined => "QUARANTINED",
+        };
+        ToSql::<sql_types::Text, DB>::to_sql(tag, out)
+    }
+}
+
+/// how the content of a file is stored on disk
+#[derive(Debug, FromSqlRow, AsExpression, Clone, Copy, PartialEq, Eq)]
+#[sql_type = "Text"]
+pub enum Compression {
+    Zstd,
+}
+
+impl Compression {
+    /// the name of the matching `Content-Encoding`
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Compression::Zstd => "zstd",
+        }
+    }
+}
+
+impl<DB> FromSql<sql_types::Text, DB> for Compression
+where
+    DB: Backend,
+    String: FromSql<sql_types::Text, DB>,
+{
+    fn from_sql(bytes: Option<&DB::RawValue>) -> diesel::deserialize::Result<Self> {
+        match &(String::from_sql(bytes)?)[..] {
+            "ZSTD" => Ok(Compression::Zstd),
+            x => Err(format!("Unknown compression: {}", x).into()),
+        }
+    }
+}
+
+impl<DB> ToSql<sql_types::Text, DB> for Compression
+where
+    DB: Backend,
+{
+    fn to_sql<W: std::io::Write>(
+        &self,
+        out: &mut diesel::serialize::Output<W, DB>,
+    ) -> diesel::serialize::Result {
+        let tag = match self {
+            Compression::Zstd => "ZSTD",
         };
         ToSql::<sql_types::Text, DB>::to_sql(tag, out)
     }
@@ -140,20 +256,79 @@ pub struct File {
     pub id: i32,
     pub token_id: i32,
     pub name: Option<String>,
+    /// relative to the root path, see [`crate::storage::Storage`]
     pub path: String,
     pub content_type: Option<String>,
-    pub size_mib: Option<i32>,
+    /// only known once the upload is completed
+    pub size_bytes: Option<i64>,
     pub created_at: NaiveDateTime,
     pub deleted_at: Option<NaiveDateTime>,
     pub file_upload_status: FileUploadStatus,
+    /// total size in bytes announced by the client for a resumable upload.
+    /// `None` for files uploaded in a single request.
+    pub upload_length: Option<i64>,
+    /// how many bytes of a resumable upload have been received so far.
+    pub upload_offset: Option<i64>,
+    /// hex encoded sha256 digest of the content, set when the upload is completed
+    pub sha256: Option<String>,
+    /// set when the content is stored in a shared blob, `path` is then
+    /// the path of the blob.
+    pub blob_sha256: Option<String>,
+    /// detected from the content once the upload is completed. Unlike
+    /// `content_type`, this can be trusted.
+    pub detected_content_type: Option<String>,
+    /// `None` when the content is stored as is
+    pub compression: Option<Compression>,
+    /// the key encrypting the content, itself encrypted with the master
+    /// key. `None` when the content isn't encrypted.
+    pub wrapped_key: Option<String>,
+    /// encrypted end to end by the client, the content and the name are
+    /// opaque to the server.
+    pub encrypted: bool,
+    /// set when the file is quarantined
+    pub quarantine_reason: Option<String>,
+    /// the metadata of the picture was removed, see [`crate::sanitize`]
+    pub sanitized: bool,
+    /// where the picture is kept as uploaded, with its metadata. Never
+    /// served, only the admin can get it.
+    pub original_path: Option<String>,
+    /// like `wrapped_key`, for the original
+    pub original_wrapped_key: Option<String>,
+    /// how many times the file has been downloaded, on its own or in an
+    /// archive
+    pub download_count: i32,
+}
+
+impl File {
+    /// where the content is written while the upload is in progress. It is
+    /// moved to `path` once the upload is complete.
+    pub fn partial_path(&self) -> std::path::PathBuf {
+        let mut p = std::ffi::OsString::from(&self.path);
+        p.push(".");
+        p.push(PARTIAL_EXTENSION);
+        p.into()
+    }
 }
 
+pub const PARTIAL_EXTENSION: &str = "part";
+
 #[derive(Debug)]
 pub struct CreateFile {
-    pub path: std::path::PathBuf,
+    /// directory where the file will be written, relative to the root path.
+    /// The actual name on disk is derived from the id of the new row, so
+    /// that several files can be uploaded with the same token without
+    /// clobbering each other.
+    pub dir: std::path::PathBuf,
     pub name: Option<String>,
     pub content_type: Option<String>,
     pub token_id: i32,
+    /// set for resumable uploads only
+    pub upload_length: Option<i64>,
+    /// see [`File::encrypted`]
+    pub encrypted: bool,
+    /// set for resumable uploads encrypted at rest, they are encrypted
+    /// as they are received
+    pub wrapped_key: Option<String>,
 }
 
 #[derive(Debug, Insertable)]
@@ -163,10 +338,96 @@ struct CreateFileSQLite {
     name: Option<String>,
     path: String,
     content_type: Option<String>,
-    size_mib: Option<i32>,
     file_upload_status: FileUploadStatus,
     created_at: NaiveDateTime,
     deleted_at: Option<NaiveDateTime>,
+    upload_length: Option<i64>,
+    upload_offset: Option<i64>,
+    encrypted: bool,
+    wrapped_key: Option<String>,
+}
+
+/// Content shared by all the files with the same sha256
+#[derive(Debug, Queryable, Identifiable)]
+#[table_name = "blob"]
+#[primary_key(sha256)]
+pub struct Blob {
+    pub sha256: String,
+    /// relative to the root path
+    pub path: String,
+    pub size_bytes: i64,
+    pub created_at: NaiveDateTime,
+    pub compression: Option<Compression>,
+    pub wrapped_key: Option<String>,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "blob"]
+struct CreateBlobSQLite {
+    sha256: String,
+    path: String,
+    size_bytes: i64,
+    created_at: NaiveDateTime,
+    compression: Option<Compression>,
+    wrapped_key: Option<String>,
+}
+
+/// A file or a directory inside an uploaded zip or tar file
+#[derive(Debug, Queryable, Associations, Identifiable)]
+#[belongs_to(File)]
+#[table_name = "archive_entry"]
+pub struct ArchiveEntry {
+    pub id: i32,
+    pub file_id: i32,
+    /// as found in the archive, not necessarily valid
+    pub path: String,
+    pub size_bytes: i64,
+    pub modified_at: Option<NaiveDateTime>,
+    pub is_dir: bool,
+    /// see [`archive::Entry::offset`]
+    pub entry_offset: i64,
+    pub packed_size: i64,
+    pub method: Option<i32>,
+    pub crc32: Option<i64>,
+}
+
+impl ArchiveEntry {
+    /// what's needed to find the entry in its archive
+    pub fn entry(&self) -> archive::Entry {
+        archive::Entry {
+            path: self.path.clone(),
+            size: self.size_bytes as u64,
+            modified: self.modified_at,
+            is_dir: self.is_dir,
+            offset: self.entry_offset as u64,
+            packed_size: self.packed_size as u64,
+            method: self.method.map(|m| m as u16),
+            crc32: self.crc32.map(|c| c as u32),
+        }
+    }
+
+    /// the name of the entry, without its directories
+    pub fn name(&self) -> &str {
+        self.path
+            .trim_end_matches('/')
+            .rsplit('/')
+            .next()
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "archive_entry"]
+struct CreateArchiveEntrySQLite<'a> {
+    file_id: i32,
+    path: &'a str,
+    size_bytes: i64,
+    modified_at: Option<NaiveDateTime>,
+    is_dir: bool,
+    entry_offset: i64,
+    packed_size: i64,
+    method: Option<i32>,
+    crc32: Option<i64>,
 }
 
 pub fn create_token(
@@ -175,16 +436,19 @@ pub fn create_token(
 ) -> std::result::Result<Token, errors::VracError> {
     use token::dsl;
 
+    if !storage::is_valid_token_path(&tok.path) {
+        return Err(errors::VracError::InvalidTokenPath(tok.path));
+    }
+    // the link would be unusable from the start
+    if tok.max_downloads == Some(0) {
+        return Err(errors::VracError::InvalidMaxDownloads);
+    }
+
     conn.transaction(|| {
         let now = chrono::Utc::now().naive_utc();
-        let existing_count: i64 = token::table
+        let existing_count: i64 = valid_tokens(now)
             .select(diesel::dsl::count_star())
             .filter(dsl::path.eq(&tok.path))
-            .filter(
-                token::token_expires_at
-                    .ge(now)
-                    .or(token::content_expires_at.ge(now)),
-            )
             .first(conn)?;
 
         if existing_count > 0 {
@@ -193,7 +457,11 @@ pub fn create_token(
 
         let sql_tok = CreateTokenSQLite {
             path: tok.path,
-            status: TokenStatus::Fresh,
+            status: if tok.open {
+                TokenStatus::Open
+            } else {
+                TokenStatus::Fresh
+            },
             max_size_mib: tok.max_size_in_mib.map(|s| s as _),
             created_at: Utc::now().naive_utc(),
             token_expires_at: tok.token_expires_at,
@@ -202,6 +470,14 @@ pub fn create_token(
                 .content_expires_after_hours
                 .map(|d| d.num_hours() as _),
             deleted_at: None,
+            allowed_types: if tok.allowed_types.is_empty() {
+                None
+            } else {
+                Some(tok.allowed_types.join(","))
+            },
+            max_files: tok.max_files.map(|n| n as _),
+            strip_metadata: tok.strip_metadata,
+            max_downloads: tok.max_downloads.map(|n| n as _),
         };
 
         let n_inserted = diesel::insert_into(token::table)
@@ -219,32 +495,121 @@ pub fn create_token(
     })
 }
 
-/// returns a token with a status of Fresh or Used, and also ensure
-/// that the associated content hasn't expired yet
+/// tokens which can still be used, either to upload some files, or to
+/// download what has been uploaded before the content expires or is
+/// downloaded `max_downloads` times.
+fn valid_tokens<'a>(now: NaiveDateTime) -> token::BoxedQuery<'a, diesel::sqlite::Sqlite> {
+    unexpired_tokens(now).filter(below_download_limit())
+}
+
+/// like [`valid_tokens`], including the ones downloaded `max_downloads`
+/// times
+fn unexpired_tokens<'a>(now: NaiveDateTime) -> token::BoxedQuery<'a, diesel::sqlite::Sqlite> {
+    use token::dsl;
+    // a link for a single upload is valid until the link and its content
+    // have both expired.
+    let single_upload = dsl::status
+        .eq_any(vec![TokenStatus::Fresh, TokenStatus::Used])
+        .and(
+            dsl::token_expires_at
+                .ge(now)
+                .or(dsl::content_expires_at.ge(now)),
+        );
+    let accepting_uploads = dsl::status
+        .eq(TokenStatus::Open)
+        .and(dsl::token_expires_at.ge(now));
+    // content which never expires, but not an open token which expired
+    // before anything was uploaded.
+    let never_expires = dsl::content_expires_at
+        .is_null()
+        .and(dsl::content_expires_after_hours.is_null());
+    let content_available = dsl::status
+        .eq_any(vec![TokenStatus::Open, TokenStatus::Closed])
+        .and(dsl::content_expires_at.ge(now).or(never_expires));
+    token::table
+        .filter(dsl::deleted_at.is_null())
+        .filter(single_upload.or(accepting_uploads).or(content_available))
+        .into_boxed()
+}
+
+/// tokens which can still be downloaded, see [`Token::max_downloads`].
+/// diesel cannot compare a column with a nullable one.
+fn below_download_limit() -> diesel::expression::SqlLiteral<sql_types::Bool> {
+    diesel::dsl::sql("(token.max_downloads IS NULL OR token.download_count < token.max_downloads)")
+}
+
+/// the opposite of [`below_download_limit`]
+fn download_limit_reached() -> diesel::expression::SqlLiteral<sql_types::Bool> {
+    diesel::dsl::sql("token.download_count >= token.max_downloads")
+}
+
+/// returns a token which can still be used, see [`Token::accepts_uploads`]
+/// to know if it's for uploading or downloading files.
 pub fn get_valid_token(
     conn: &SqliteConnection,
     token_path: String,
 ) -> std::result::Result<Option<Token>, diesel::result::Error> {
-    // there should be at most one token with a given path in status fresh or used.
+    // there should be at most one valid token with a given path.
     let now = chrono::Utc::now().naive_utc();
-    let tok: Vec<Token> = token::table
+    let tok: Vec<Token> = valid_tokens(now)
         .filter(token::path.eq(token_path))
-        .filter(
-            token::token_expires_at
-                .ge(now)
-                .or(token::content_expires_at.ge(now)),
-        )
         .load(conn)?;
     Ok(tok.into_iter().next())
 }
 
-/// Returns a list of expired token and their associated file
+/// Like [`get_valid_token`], but also returns a token which reached its
+/// `max_downloads`: the clients already counted can finish their
+/// download, see [`Token::download_limit_reached`].
+pub fn get_downloadable_token(
+    conn: &SqliteConnection,
+    token_path: String,
+) -> std::result::Result<Option<Token>, diesel::result::Error> {
+    let now = chrono::Utc::now().naive_utc();
+    let tok: Vec<Token> = unexpired_tokens(now)
+        .filter(token::path.eq(token_path))
+        .load(conn)?;
+    Ok(tok.into_iter().next())
+}
+
+/// all the tokens which can still be used, oldest first
+pub fn get_valid_tokens(conn: &SqliteConnection) -> errors::Result<Vec<Token>> {
+    let now = chrono::Utc::now().naive_utc();
+    let tokens = valid_tokens(now).order(token::id).load(conn)?;
+    Ok(tokens)
+}
+
+/// how many files have been or are being uploaded with the given token
+pub fn count_files(
+    conn: &SqliteConnection,
+    token_id: i32,
+) -> std::result::Result<i64, diesel::result::Error> {
+    use crate::schema::file::dsl;
+    dsl::file
+        .filter(dsl::token_id.eq(token_id))
+        .filter(dsl::deleted_at.is_null())
+        .count()
+        .get_result(conn)
+}
+
+pub fn get_token(
+    conn: &SqliteConnection,
+    token_id: i32,
+) -> std::result::Result<Token, diesel::result::Error> {
+    token::table.find(token_id).first(conn)
+}
+
+/// Returns a list of expired token and their associated file. Tokens
+/// whose files have been downloaded `max_downloads` times are expired too.
 pub fn get_expired_files(
     conn: &SqliteConnection,
 ) -> std::result::Result<HashMap<Token, Vec<File>>, Box<dyn std::error::Error>> {
     let now = chrono::Utc::now().naive_utc();
     let expired_tokens: Vec<Token> = token::table
-        .filter(token::content_expires_at.le(now))
+        .filter(
+            token::content_expires_at
+                .le(now)
+                .or(download_limit_reached()),
+        )
         .filter(token::dsl::deleted_at.is_null())
         .load(conn)?;
 
@@ -259,21 +624,40 @@ pub fn get_expired_files(
     Ok(result)
 }
 
-/// mark all expired token as deleted and returns their paths.
+/// mark as deleted the tokens whose content expired, and the upload links
+/// which expired before anything was uploaded, and returns their paths.
+/// Open tokens which expired with some files are closed instead, their
+/// content is kept until it expires.
 pub fn delete_expired_tokens(
     conn: &SqliteConnection,
 ) -> std::result::Result<Vec<String>, Box<dyn std::error::Error>> {
     let now = chrono::Utc::now().naive_utc();
 
-    let to_delete: Vec<Token> = token::table
+    // the links for a single upload are deleted once either the link or
+    // its content expires, the drop boxes once their content expires or
+    // they expire without any file.
+    let expired: Vec<Token> = token::table
         .filter(
-            token::dsl::token_expires_at
-                .le(now)
-                .or(token::dsl::content_expires_at.le(now)),
+            token::dsl::content_expires_at.le(now).or(token::dsl::status
+                .ne(TokenStatus::Closed)
+                .and(token::dsl::token_expires_at.le(now))),
         )
         .filter(token::dsl::deleted_at.is_null())
         .load(conn)?;
 
+    let mut to_delete = Vec::new();
+    for tok in expired {
+        let content_expired = tok.content_expires_at.is_some_and(|t| t <= now);
+        if tok.status == TokenStatus::Open && !content_expired && count_files(conn, tok.id)? > 0 {
+            log::info!("Closing token {} with path {}", tok.id, tok.path);
+            diesel::update(token::dsl::token.find(tok.id))
+                .set(token::dsl::status.eq(TokenStatus::Closed))
+                .execute(conn)?;
+        } else {
+            to_delete.push(tok);
+        }
+    }
+
     let ids_to_del = to_delete.iter().map(|t| t.id);
     diesel::update(token::dsl::token.filter(token::dsl::id.eq_any(ids_to_del)))
         .set((
@@ -298,6 +682,11 @@ pub fn delete_files(
 
         let now = chrono::Utc::now().naive_utc();
         for tok in tokens {
+            let file_ids = file::table
+                .filter(file::token_id.eq(tok.id))
+                .select(file::id);
+            diesel::delete(archive_entry::table.filter(archive_entry::file_id.eq_any(file_ids)))
+                .execute(conn)?;
             deleted_file_count +=
                 diesel::update(file::dsl::file.filter(file::dsl::token_id.eq(tok.id)))
                     .set(file::dsl::deleted_at.eq(now))
@@ -314,7 +703,9 @@ pub fn delete_files(
     Ok(deleted_file_count)
 }
 
-/// Mark the given as Used, all files have been uploaded
+/// Record a successful upload with the given token. A single upload token
+/// is marked as Used, an open token stays open until it holds `max_files`
+/// files. The content expires `content_expires_after_hours` after this upload.
 pub fn consume_token(
     conn: &SqliteConnection,
     tok: Token,
@@ -323,29 +714,72 @@ pub fn consume_token(
     let expires_at = tok
         .content_expires_after_hours
         .map(|h| (chrono::Utc::now() + chrono::Duration::hours(h as _)).naive_utc());
+    let status = match (tok.status, tok.max_files) {
+        (TokenStatus::Open, Some(max_files)) if count_files(conn, tok.id)? >= max_files as i64 => {
+            TokenStatus::Closed
+        }
+        (TokenStatus::Open, _) => TokenStatus::Open,
+        _ => TokenStatus::Used,
+    };
     diesel::update(token::table.find(tok.id))
         .set((
-            dsl::status.eq(TokenStatus::Used),
+            dsl::status.eq(status),
             dsl::content_expires_at.eq(expires_at),
         ))
         .execute(conn)
         .map(|_| ())
 }
 
+/// Count a download of the given files of a token: once for the token,
+/// and once for each file. Returns false, counting nothing, when the
+/// token has already been downloaded `max_downloads` times.
+pub fn record_download(
+    conn: &SqliteConnection,
+    token_id: i32,
+    file_ids: &[i32],
+) -> errors::Result<bool> {
+    conn.transaction(|| {
+        let updated = diesel::update(token::table.find(token_id).filter(below_download_limit()))
+            .set(token::download_count.eq(token::download_count + 1))
+            .execute(conn)?;
+        if updated == 0 {
+            return Ok(false);
+        }
+        diesel::update(file::table.filter(file::id.eq_any(file_ids)))
+            .set(file::download_count.eq(file::download_count + 1))
+            .execute(conn)?;
+        Ok(true)
+    })
+}
+
 pub fn create_file(conn: &SqliteConnection, file: CreateFile) -> errors::Result<File> {
     use crate::schema::file::dsl;
 
     let create_file = CreateFileSQLite {
         token_id: file.token_id,
         name: file.name,
-        path: file.path.to_string_lossy().to_string(),
+        // placeholder, the real path is only known once the row has an id
+        path: String::new(),
         content_type: file.content_type,
-        size_mib: None,
         file_upload_status: FileUploadStatus::Started,
         created_at: Utc::now().naive_utc(),
         deleted_at: None,
+        upload_length: file.upload_length,
+        upload_offset: file.upload_length.map(|_| 0),
+        encrypted: file.encrypted,
+        wrapped_key: file.wrapped_key,
     };
     conn.transaction(move || {
+        let max_files: Option<i32> = token::table
+            .find(file.token_id)
+            .select(token::max_files)
+            .first(conn)?;
+        if let Some(max_files) = max_files {
+            if count_files(conn, file.token_id)? >= max_files as i64 {
+                return Err(errors::VracError::TooManyFiles(max_files));
+            }
+        }
+
         let n_inserted = diesel::insert_into(file::table)
             .values(&create_file)
             // cannot use get_result because sqlite doesn't support RETURNING :(
@@ -354,16 +788,244 @@ pub fn create_file(conn: &SqliteConnection, file: CreateFile) -> errors::Result<
         if n_inserted == 0 {
             Err(anyhow!("Didn't insert file: {:?}", create_file).into())
         } else {
-            let inserted_file = dsl::file.order(file::id.desc()).first(conn)?;
+            let file_id: i32 = diesel::select(last_insert_rowid).get_result(conn)?;
+            let path = file.dir.join(format!("file-{file_id:04}"));
+            diesel::update(dsl::file.find(file_id))
+                .set(dsl::path.eq(path.to_string_lossy().to_string()))
+                .execute(conn)?;
+            let inserted_file = dsl::file.find(file_id).first(conn)?;
             Ok(inserted_file)
         }
     })
 }
 
-pub fn complete_upload(conn: &SqliteConnection, file_id: i32) -> errors::Result<()> {
+/// what is learnt about a file once all its content has been received
+#[derive(Debug, AsChangeset)]
+#[table_name = "file"]
+pub struct UploadedContent {
+    pub size_bytes: i64,
+    pub sha256: String,
+    pub detected_content_type: String,
+    pub compression: Option<Compression>,
+    pub wrapped_key: Option<String>,
+    pub sanitized: bool,
+    pub original_path: Option<String>,
+    pub original_wrapped_key: Option<String>,
+}
+
+pub fn complete_upload(
+    conn: &SqliteConnection,
+    file_id: i32,
+    content: UploadedContent,
+) -> errors::Result<File> {
     use crate::schema::file::dsl;
     diesel::update(dsl::file.find(file_id))
-        .set(dsl::file_upload_status.eq(FileUploadStatus::Completed))
+        .set((
+            dsl::file_upload_status.eq(FileUploadStatus::Completed),
+            &content,
+        ))
+        .execute(conn)?;
+    Ok(dsl::file.find(file_id).first(conn)?)
+}
+
+/// record the entries found in an uploaded archive
+pub fn add_archive_entries(
+    conn: &SqliteConnection,
+    file_id: i32,
+    entries: &[archive::Entry],
+) -> errors::Result<()> {
+    let rows: Vec<_> = entries
+        .iter()
+        .map(|e| CreateArchiveEntrySQLite {
+            file_id,
+            path: &e.path,
+            size_bytes: e.size as i64,
+            modified_at: e.modified,
+            is_dir: e.is_dir,
+            entry_offset: e.offset as i64,
+            packed_size: e.packed_size as i64,
+            method: e.method.map(|m| m as i32),
+            crc32: e.crc32.map(|c| c as i64),
+        })
+        .collect();
+    conn.transaction(|| {
+        diesel::insert_into(archive_entry::table)
+            .values(&rows)
+            .execute(conn)
+    })?;
+    Ok(())
+}
+
+/// the entries of the given archives, in the order they were found
+pub fn get_archive_entries(
+    conn: &SqliteConnection,
+    files: &[File],
+) -> errors::Result<Vec<Vec<ArchiveEntry>>> {
+    let entries = ArchiveEntry::belonging_to(files)
+        .order(archive_entry::id)
+        .load(conn)?;
+    Ok(entries.grouped_by(files))
+}
+
+pub fn get_archive_entry(
+    conn: &SqliteConnection,
+    file: &File,
+    entry_id: i32,
+) -> errors::Result<Option<ArchiveEntry>> {
+    let entry = ArchiveEntry::belonging_to(file)
+        .filter(archive_entry::id.eq(entry_id))
+        .first(conn)
+        .optional()?;
+    Ok(entry)
+}
+
+/// like [`complete_upload`], but the file is quarantined instead
+pub fn quarantine_file(
+    conn: &SqliteConnection,
+    file_id: i32,
+    content: UploadedContent,
+    reason: String,
+) -> errors::Result<()> {
+    use crate::schema::file::dsl;
+    diesel::update(dsl::file.find(file_id))
+        .set((
+            dsl::file_upload_status.eq(FileUploadStatus::Quarantined),
+            dsl::quarantine_reason.eq(reason),
+            &content,
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// the files flagged by the scanner, along their token, for the admin
+pub fn get_quarantined_files(conn: &SqliteConnection) -> errors::Result<Vec<(File, Token)>> {
+    let files = file::table
+        .inner_join(token::table)
+        .filter(file::file_upload_status.eq(FileUploadStatus::Quarantined))
+        .filter(file::deleted_at.is_null())
+        .order(file::id)
+        .load(conn)?;
+    Ok(files)
+}
+
+pub fn get_blob(conn: &SqliteConnection, sha256: &str) -> errors::Result<Option<Blob>> {
+    let b = blob::table.find(sha256).first(conn).optional()?;
+    Ok(b)
+}
+
+/// make the given file use the blob with the given digest as content,
+/// the blob is created if it doesn't exist yet. `path`, `compression` and
+/// `wrapped_key` describe the content of the new blob and are ignored otherwise.
+pub fn link_blob(
+    conn: &SqliteConnection,
+    file_id: i32,
+    sha256: String,
+    path: String,
+    size_bytes: i64,
+    compression: Option<Compression>,
+    wrapped_key: Option<String>,
+) -> errors::Result<Blob> {
+    use crate::schema::file::dsl;
+    conn.transaction(|| {
+        let blob = match get_blob(conn, &sha256)? {
+            Some(b) => b,
+            None => {
+                let create_blob = CreateBlobSQLite {
+                    sha256: sha256.clone(),
+                    path,
+                    size_bytes,
+                    created_at: Utc::now().naive_utc(),
+                    compression,
+                    wrapped_key,
+                };
+                diesel::insert_into(blob::table)
+                    .values(&create_blob)
+                    .execute(conn)?;
+                blob::table.find(&sha256).first(conn)?
+            }
+        };
+        diesel::update(dsl::file.find(file_id))
+            .set((dsl::path.eq(&blob.path), dsl::blob_sha256.eq(&blob.sha256)))
+            .execute(conn)?;
+        Ok(blob)
+    })
+}
+
+/// Replace the wrapped key of every encrypted file, kept original and blob,
+/// a new master key. All or nothing, returns how many keys were replaced.
+pub fn rewrap_keys<F>(conn: &SqliteConnection, rewrap: F) -> errors::Result<usize>
+where
+    F: Fn(&str) -> anyhow::Result<String>,
+{
+    conn.transaction(|| {
+        let files: Vec<(i32, Option<String>)> = file::table
+            .select((file::id, file::wrapped_key))
+            .filter(file::wrapped_key.is_not_null())
+            .load(conn)?;
+        let blobs: Vec<(String, Option<String>)> = blob::table
+            .select((blob::sha256, blob::wrapped_key))
+            .filter(blob::wrapped_key.is_not_null())
+            .load(conn)?;
+        let originals: Vec<(i32, Option<String>)> = file::table
+            .select((file::id, file::original_wrapped_key))
+            .filter(file::original_wrapped_key.is_not_null())
+            .load(conn)?;
+        let count = files.len() + blobs.len() + originals.len();
+
+        for (id, wrapped_key) in files {
+            let new_key = rewrap(&wrapped_key.unwrap_or_default())
+                .with_context(|| format!("Cannot rewrap the key of file {id}"))?;
+            diesel::update(file::table.find(id))
+                .set(file::wrapped_key.eq(new_key))
+                .execute(conn)?;
+        }
+        for (id, wrapped_key) in originals {
+            let new_key = rewrap(&wrapped_key.unwrap_or_default())
+                .with_context(|| format!("Cannot rewrap the key of the original of file {id}"))?;
+            diesel::update(file::table.find(id))
+                .set(file::original_wrapped_key.eq(new_key))
+                .execute(conn)?;
+        }
+        for (sha256, wrapped_key) in blobs {
+            let new_key = rewrap(&wrapped_key.unwrap_or_default())
+                .with_context(|| format!("Cannot rewrap the key of blob {sha256}"))?;
+            diesel::update(blob::table.find(&sha256))
+                .set(blob::wrapped_key.eq(new_key))
+                .execute(conn)?;
+        }
+        Ok(count)
+    })
+}
+
+/// Returns the blobs which aren't used by any file anymore.
+pub fn get_unreferenced_blobs(conn: &SqliteConnection) -> errors::Result<Vec<Blob>> {
+    use crate::schema::file::dsl;
+    let referenced: std::collections::HashSet<String> = dsl::file
+        .select(dsl::blob_sha256)
+        .filter(dsl::blob_sha256.is_not_null())
+        .filter(dsl::deleted_at.is_null())
+        .load::<Option<String>>(conn)?
+        .into_iter()
+        .flatten()
+        .collect();
+    let blobs = blob::table
+        .load::<Blob>(conn)?
+        .into_iter()
+        .filter(|b| !referenced.contains(&b.sha256))
+        .collect();
+    Ok(blobs)
+}
+
+pub fn delete_blob(conn: &SqliteConnection, sha256: &str) -> errors::Result<()> {
+    diesel::delete(blob::table.find(sha256)).execute(conn)?;
+    Ok(())
+}
+
+/// record how many bytes of a resumable upload have been written to disk.
+pub fn set_upload_offset(conn: &SqliteConnection, file_id: i32, offset: i64) -> errors::Result<()> {
+    use crate::schema::file::dsl;
+    diesel::update(dsl::file.find(file_id))
+        .set(dsl::upload_offset.eq(offset))
         .execute(conn)?;
     Ok(())
 }
@@ -384,6 +1046,13 @@ pub fn get_files(conn: &SqliteConnection, token: &Token) -> errors::Result<Vec<F
     Ok(files)
 }
 
+/// the file with the given id, whatever its token and its status
+pub fn get_file_by_id(conn: &SqliteConnection, file_id: i32) -> errors::Result<Option<File>> {
+    let f = file::table.find(file_id).first(conn).optional()?;
+    Ok(f)
+}
+
+/// returns the given file only if its upload is completed
 pub fn get_file(
     conn: &SqliteConnection,
     token: &Token,
@@ -392,6 +1061,65 @@ pub fn get_file(
     use crate::schema::file::dsl;
     let f = File::belonging_to(token)
         .filter(dsl::id.eq(file_id))
+        .filter(dsl::file_upload_status.eq(FileUploadStatus::Completed))
+        .first(conn)
+        .optional()?;
+    Ok(f)
+}
+
+/// returns the uploads still in progress for the given token
+pub fn get_started_files(conn: &SqliteConnection, token: &Token) -> errors::Result<Vec<File>> {
+    use crate::schema::file::dsl;
+    let files = File::belonging_to(token)
+        .filter(dsl::file_upload_status.eq(FileUploadStatus::Started))
+        .load(conn)?;
+    Ok(files)
+}
+
+/// How many bytes the uploads of the given token take, or will once they're
+/// completed: the announced length of the resumable uploads, and the size
+/// of the completed ones.
+pub fn reserved_upload_size(conn: &SqliteConnection, token_id: i32) -> errors::Result<i64> {
+    use crate::schema::file::dsl;
+    let sizes: Vec<(Option<i64>, Option<i64>)> = dsl::file
+        .select((dsl::upload_length, dsl::size_bytes))
+        .filter(dsl::token_id.eq(token_id))
+        .filter(dsl::deleted_at.is_null())
+        .filter(
+            dsl::file_upload_status
+                .eq_any(vec![FileUploadStatus::Started, FileUploadStatus::Completed]),
+        )
+        .load(conn)?;
+    Ok(sizes
+        .into_iter()
+        .map(|(length, size)| size.or(length).unwrap_or(0))
+        .sum())
+}
+
+/// returns all the uploads still in progress
+pub fn get_started_uploads(conn: &SqliteConnection) -> errors::Result<Vec<File>> {
+    use crate::schema::file::dsl;
+    let files = dsl::file
+        .filter(dsl::file_upload_status.eq(FileUploadStatus::Started))
+        .load(conn)?;
+    Ok(files)
+}
+
+/// returns a resumable upload of the given token, still in progress or
+/// completed, so clients can learn that their upload went through.
+pub fn get_resumable_upload(
+    conn: &SqliteConnection,
+    token: &Token,
+    file_id: i32,
+) -> errors::Result<Option<File>> {
+    use crate::schema::file::dsl;
+    let f = File::belonging_to(token)
+        .filter(dsl::id.eq(file_id))
+        .filter(
+            dsl::file_upload_status
+                .eq_any(vec![FileUploadStatus::Started, FileUploadStatus::Completed]),
+        )
+        .filter(dsl::upload_length.is_not_null())
         .first(conn)
         .optional()?;
     Ok(f)
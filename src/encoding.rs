@@ -0,0 +1,144 @@
+//! How the content of a file is transformed on its way to the disk and back:
+//! compressed when it's worth it, then encrypted, both being optional.
+
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::compression::{self, Compressor};
+use crate::db::Compression;
+use crate::encryption::{DataKey, DecryptReader, Encryptor};
+use crate::storage::Storage;
+
+/// Encodes content pushed chunk by chunk
+pub struct Encoder {
+    compressor: Option<Compressor>,
+    encryptor: Option<Encryptor>,
+    compressed: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new(compress: bool, key: Option<&DataKey>) -> Self {
+        Encoder {
+            compressor: compress.then(Compressor::default),
+            encryptor: key.map(Encryptor::new),
+            compressed: Vec::new(),
+        }
+    }
+
+    /// the encoded content is appended to `out` as it becomes available
+    pub fn update(&mut self, data: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        match &mut self.compressor {
+            Some(compressor) => {
+                compressor.update(data, &mut self.compressed)?;
+                encrypt(&mut self.encryptor, &self.compressed, out)?;
+                self.compressed.clear();
+                Ok(())
+            }
+            None => encrypt(&mut self.encryptor, data, out),
+        }
+    }
+
+    /// flush the remaining encoded content into `out`, and returns
+    /// how it was compressed.
+    pub fn finish(mut self, out: &mut Vec<u8>) -> io::Result<Option<Compression>> {
+        let compressed = match self.compressor.take() {
+            Some(compressor) => compressor.finish(&mut self.compressed)?,
+            None => false,
+        };
+        encrypt(&mut self.encryptor, &self.compressed, out)?;
+        if let Some(encryptor) = self.encryptor {
+            encryptor.finish(out)?;
+        }
+        Ok(compressed.then_some(Compression::Zstd))
+    }
+}
+
+fn encrypt(encryptor: &mut Option<Encryptor>, data: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+    match encryptor {
+        Some(encryptor) => encryptor.update(data, out),
+        None => {
+            out.extend_from_slice(data);
+            Ok(())
+        }
+    }
+}
+
+/// Encode the file at `from`, which was written as is or encrypted with
+/// `from_key`, into `to`. This is blocking, and can take a while for big files.
+pub fn encode_file(
+    storage: &Storage,
+    from: &Path,
+    from_key: Option<&DataKey>,
+    to: &Path,
+    compress: bool,
+    key: Option<&DataKey>,
+) -> io::Result<Option<Compression>> {
+    use std::io::Write;
+    let mut out = Vec::new();
+    let mut encoder = Encoder::new(compress, key);
+    let mut reader = decoder(storage.open(from)?, from_key, None)?;
+    let mut writer = storage.create(to)?;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        encoder.update(&buf[..n], &mut out)?;
+        writer.write_all(&out)?;
+        out.clear();
+    }
+    let compression = encoder.finish(&mut out)?;
+    writer.write_all(&out)?;
+    writer.sync_all()?;
+    Ok(compression)
+}
+
+/// Reads back the content of a file. `compression` is the one to undo,
+/// `None` leaves the content compressed if it was.
+pub fn decoder(
+    file: std::fs::File,
+    key: Option<&DataKey>,
+    compression: Option<Compression>,
+) -> io::Result<Box<dyn Read + Send>> {
+    let reader: Box<dyn Read + Send> = match key {
+        Some(key) => {
+            let len = file.metadata()?.len();
+            Box::new(DecryptReader::new(file, key, len)?)
+        }
+        None => Box::new(file),
+    };
+    match compression {
+        Some(Compression::Zstd) => Ok(Box::new(compression::decompress(reader)?)),
+        None => Ok(reader),
+    }
+}
+
+/// Like [`decoder`], but the content starts `offset` bytes in. Content
+/// stored as is is read from there directly, encrypted content from the
+/// chunk holding that offset. Compressed content is decoded from the start
+/// and the first bytes are skipped.
+pub fn decoder_at(
+    mut file: std::fs::File,
+    key: Option<&DataKey>,
+    compression: Option<Compression>,
+    offset: u64,
+) -> io::Result<Box<dyn Read + Send>> {
+    match (key, compression) {
+        (None, None) => {
+            file.seek(SeekFrom::Start(offset))?;
+            return Ok(Box::new(file));
+        }
+        (Some(key), None) => {
+            let len = file.metadata()?.len();
+            return Ok(Box::new(DecryptReader::new_at(file, key, len, offset)?));
+        }
+        (_, Some(_)) => (),
+    }
+    let mut reader = decoder(file, key, compression)?;
+    let skipped = io::copy(&mut (&mut reader).take(offset), &mut io::sink())?;
+    if skipped < offset {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(reader)
+}
@@ -0,0 +1,454 @@
+//! Encryption at rest. Each file is encrypted with its own data key, and
+//! the data keys are stored in the DB, encrypted with the master key of the
+//! server. Rotating the master key only means encrypting the data keys again.
+//!
+//! The content is split in chunks encrypted with XChaCha20Poly1305 following
+//! the STREAM construction, so it can be decrypted as it is read, and a
+//! truncated or reordered file is detected.
+//! On disk, a file is a random nonce prefix followed by the encrypted chunks.
+//! Resumable uploads are encrypted as they're received, chunk by chunk, see
+//! [`Encryptor::resume`].
+
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::stream::{NewStream, StreamBE32, StreamPrimitive};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+
+/// size of the plaintext of every chunk but the last one
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+/// 24 bytes of the XChaCha nonce minus the 5 used by STREAM for
+/// the chunk counter and the last chunk flag
+const NONCE_PREFIX_SIZE: usize = 19;
+const NONCE_SIZE: usize = 24;
+
+type Stream = StreamBE32<XChaCha20Poly1305>;
+
+/// generates a new random key, base64 encoded like the master key is expected
+pub fn generate_key() -> String {
+    base64::encode(XChaCha20Poly1305::generate_key(&mut OsRng))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// The key used to encrypt the data keys. Never shown in logs.
+#[derive(Clone)]
+pub struct MasterKey(Key);
+
+impl fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MasterKey(..)")
+    }
+}
+
+impl MasterKey {
+    /// the key is 32 random bytes, base64 encoded
+    pub fn from_base64(encoded: &str) -> anyhow::Result<Self> {
+        let bytes = base64::decode(encoded.trim())?;
+        if bytes.len() != Key::default().len() {
+            bail!("The master key must be 32 bytes, got {}", bytes.len());
+        }
+        Ok(MasterKey(*Key::from_slice(&bytes)))
+    }
+
+    /// generates a new data key, and returns it along its wrapped form
+    /// which can be stored.
+    pub fn new_data_key(&self) -> anyhow::Result<(DataKey, String)> {
+        let key = DataKey(XChaCha20Poly1305::generate_key(&mut OsRng));
+        let wrapped = self.wrap(&key)?;
+        Ok((key, wrapped))
+    }
+
+    /// the data key encrypted with this key, base64 encoded
+    fn wrap(&self, key: &DataKey) -> anyhow::Result<String> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut wrapped = nonce.to_vec();
+        let encrypted = XChaCha20Poly1305::new(&self.0)
+            .encrypt(&nonce, key.0.as_slice())
+            .map_err(|_| anyhow!("Cannot wrap data key"))?;
+        wrapped.extend_from_slice(&encrypted);
+        Ok(base64::encode(wrapped))
+    }
+
+    pub fn unwrap(&self, wrapped: &str) -> anyhow::Result<DataKey> {
+        let wrapped = base64::decode(wrapped)?;
+        if wrapped.len() < NONCE_SIZE {
+            bail!("Wrapped data key too short");
+        }
+        let (nonce, encrypted) = wrapped.split_at(NONCE_SIZE);
+        let key = XChaCha20Poly1305::new(&self.0)
+            .decrypt(XNonce::from_slice(nonce), encrypted)
+            .map_err(|_| anyhow!("Cannot unwrap data key, wrong master key?"))?;
+        if key.len() != Key::default().len() {
+            bail!("Invalid data key");
+        }
+        Ok(DataKey(*Key::from_slice(&key)))
+    }
+
+    /// the same data key, wrapped with another master key
+    pub fn rewrap(&self, wrapped: &str, new_key: &MasterKey) -> anyhow::Result<String> {
+        new_key.wrap(&self.unwrap(wrapped)?)
+    }
+}
+
+/// The key encrypting the content of a single file
+#[derive(Clone)]
+pub struct DataKey(Key);
+
+/// The size on disk of the first `offset` bytes of a content, when
+/// `offset` is a multiple of the chunk size, see [`Encryptor::resume`].
+pub fn encrypted_size(offset: u64) -> u64 {
+    let chunks = offset / CHUNK_SIZE as u64;
+    NONCE_PREFIX_SIZE as u64 + chunks * (CHUNK_SIZE + TAG_SIZE) as u64
+}
+
+/// Encrypts content pushed chunk by chunk.
+pub struct Encryptor {
+    stream: Stream,
+    /// written before the first chunk
+    header: Option<[u8; NONCE_PREFIX_SIZE]>,
+    position: u32,
+    buf: Vec<u8>,
+}
+
+impl Encryptor {
+    pub fn new(key: &DataKey) -> Self {
+        let mut nonce = [0; NONCE_PREFIX_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        Encryptor {
+            stream: Stream::new(&key.0, &nonce.into()),
+            header: Some(nonce),
+            position: 0,
+            buf: Vec::with_capacity(CHUNK_SIZE + TAG_SIZE),
+        }
+    }
+
+    /// Continue the encryption of a content whose first `offset` bytes have
+    /// already been encrypted into `encrypted`, where only the header is
+    /// read. `offset` must be a multiple of the chunk size, see
+    /// [`Encryptor::encrypted_offset`].
+    pub fn resume<R: Read>(key: &DataKey, mut encrypted: R, offset: u64) -> io::Result<Self> {
+        if !offset.is_multiple_of(CHUNK_SIZE as u64) {
+            return Err(invalid_data(
+                "Cannot resume encryption in the middle of a chunk",
+            ));
+        }
+        let position = u32::try_from(offset / CHUNK_SIZE as u64)
+            .map_err(|_| invalid_data("File too big to be encrypted"))?;
+        let mut nonce = [0; NONCE_PREFIX_SIZE];
+        encrypted.read_exact(&mut nonce)?;
+        Ok(Encryptor {
+            stream: Stream::new(&key.0, &nonce.into()),
+            header: None,
+            position,
+            buf: Vec::with_capacity(CHUNK_SIZE + TAG_SIZE),
+        })
+    }
+
+    /// how much content has been encrypted so far, without what's still
+    /// buffered. Encryption can be resumed from there.
+    pub fn encrypted_offset(&self) -> u64 {
+        self.position as u64 * CHUNK_SIZE as u64
+    }
+
+    /// Encrypt the buffered content if it's a whole chunk, knowing more
+    /// content comes after it. What's left can only be encrypted along the
+    /// content which follows.
+    pub fn flush(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        if let Some(header) = self.header.take() {
+            out.extend_from_slice(&header);
+        }
+        if self.buf.len() == CHUNK_SIZE {
+            self.encrypt_chunk(false, out)?;
+        }
+        Ok(())
+    }
+
+    /// encrypted chunks are appended to `out` as soon as they are complete
+    pub fn update(&mut self, data: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        if let Some(header) = self.header.take() {
+            out.extend_from_slice(&header);
+        }
+        self.buf.extend_from_slice(data);
+        // the last chunk is encrypted differently, so a full chunk is only
+        // encrypted once there is more content after it.
+        while self.buf.len() > CHUNK_SIZE {
+            let rest = self.buf.split_off(CHUNK_SIZE);
+            self.encrypt_chunk(false, out)?;
+            self.buf = rest;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        self.update(&[], out)?;
+        self.encrypt_chunk(true, out)
+    }
+
+    fn encrypt_chunk(&mut self, last: bool, out: &mut Vec<u8>) -> io::Result<()> {
+        if self.position == u32::MAX {
+            return Err(invalid_data("File too big to be encrypted"));
+        }
+        self.stream
+            .encrypt_in_place(self.position, last, b"", &mut self.buf)
+            .map_err(|_| invalid_data("Cannot encrypt chunk"))?;
+        self.position += 1;
+        out.append(&mut self.buf);
+        Ok(())
+    }
+}
+
+/// Decrypts a file written by an [`Encryptor`] as it is read.
+pub struct DecryptReader<R> {
+    inner: R,
+    stream: Stream,
+    position: u32,
+    /// encrypted bytes left to read from `inner`
+    remaining: u64,
+    chunk: Vec<u8>,
+    /// how much of the decrypted chunk has already been read
+    consumed: usize,
+}
+
+impl<R: Read> DecryptReader<R> {
+    /// `len` is the size of the whole encrypted file, so that the last chunk
+    /// is known when it's reached.
+    pub fn new(mut inner: R, key: &DataKey, len: u64) -> io::Result<Self> {
+        // there is always a last chunk, even without any content
+        if len <= NONCE_PREFIX_SIZE as u64 {
+            return Err(invalid_data("Encrypted file truncated"));
+        }
+        let mut nonce = [0; NONCE_PREFIX_SIZE];
+        inner.read_exact(&mut nonce)?;
+        Ok(DecryptReader {
+            inner,
+            stream: Stream::new(&key.0, &nonce.into()),
+            position: 0,
+            remaining: len.saturating_sub(NONCE_PREFIX_SIZE as u64),
+            chunk: Vec::with_capacity(CHUNK_SIZE + TAG_SIZE),
+            consumed: 0,
+        })
+    }
+
+    /// Like [`DecryptReader::new`], but the content starts `offset` bytes
+    /// in: the chunks before the one holding it are skipped, not decrypted.
+    pub fn new_at(inner: R, key: &DataKey, len: u64, offset: u64) -> io::Result<Self>
+    where
+        R: Seek,
+    {
+        let mut reader = Self::new(inner, key, len)?;
+        let chunks = offset / CHUNK_SIZE as u64;
+        let skipped = chunks * (CHUNK_SIZE + TAG_SIZE) as u64;
+        reader.remaining = reader
+            .remaining
+            .checked_sub(skipped)
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        reader.position = u32::try_from(chunks).map_err(|_| invalid_data("Offset too big"))?;
+        reader
+            .inner
+            .seek(SeekFrom::Start(NONCE_PREFIX_SIZE as u64 + skipped))?;
+        let within = (offset % CHUNK_SIZE as u64) as usize;
+        if within > 0 {
+            if reader.remaining == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            reader.decrypt_next_chunk()?;
+            if within > reader.chunk.len() {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            reader.consumed = within;
+        }
+        Ok(reader)
+    }
+
+    fn decrypt_next_chunk(&mut self) -> io::Result<()> {
+        let len = std::cmp::min(self.remaining, (CHUNK_SIZE + TAG_SIZE) as u64) as usize;
+        self.chunk.resize(len, 0);
+        self.inner.read_exact(&mut self.chunk)?;
+        self.remaining -= len as u64;
+        let last = self.remaining == 0;
+        self.stream
+            .decrypt_in_place(self.position, last, b"", &mut self.chunk)
+            .map_err(|_| invalid_data("Cannot decrypt chunk, corrupted file?"))?;
+        self.position += 1;
+        self.consumed = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.consumed == self.chunk.len() {
+            if self.remaining == 0 {
+                return Ok(0);
+            }
+            self.decrypt_next_chunk()?;
+        }
+        let n = std::cmp::min(buf.len(), self.chunk.len() - self.consumed);
+        buf[..n].copy_from_slice(&self.chunk[self.consumed..self.consumed + n]);
+        self.consumed += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn new_key() -> DataKey {
+        DataKey(XChaCha20Poly1305::generate_key(&mut OsRng))
+    }
+
+    fn encrypt(key: &DataKey, content: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut encryptor = Encryptor::new(key);
+        encryptor.update(content, &mut out).unwrap();
+        encryptor.finish(&mut out).unwrap();
+        out
+    }
+
+    fn decrypt_at(key: &DataKey, encrypted: &[u8], offset: u64) -> io::Result<Vec<u8>> {
+        let len = encrypted.len() as u64;
+        let mut reader = DecryptReader::new_at(Cursor::new(encrypted), key, len, offset)?;
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    #[test]
+    fn decrypt_from_an_offset() {
+        let key = new_key();
+        let content: Vec<u8> = (0..CHUNK_SIZE * 7 / 2).map(|i| (i % 251) as u8).collect();
+        let encrypted = encrypt(&key, &content);
+        let len = content.len();
+        for offset in [
+            0,
+            1,
+            CHUNK_SIZE - 1,
+            CHUNK_SIZE,
+            CHUNK_SIZE + 5,
+            3 * CHUNK_SIZE,
+            len - 1,
+            len,
+        ] {
+            let decrypted = decrypt_at(&key, &encrypted, offset as u64).unwrap();
+            assert_eq!(decrypted, &content[offset..], "from {offset}");
+        }
+        let err = decrypt_at(&key, &encrypted, len as u64 + 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let err = decrypt_at(&key, &encrypted, 5 * CHUNK_SIZE as u64).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn truncation_is_detected_from_an_offset() {
+        let key = new_key();
+        let content = vec![7; CHUNK_SIZE * 3];
+        let encrypted = encrypt(&key, &content);
+        // without its last chunk, the one before isn't flagged as the last
+        let truncated = &encrypted[..encrypted.len() - CHUNK_SIZE - TAG_SIZE];
+        let err = decrypt_at(&key, truncated, CHUNK_SIZE as u64 + 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    fn decrypt(key: &DataKey, encrypted: &[u8]) -> io::Result<Vec<u8>> {
+        let len = encrypted.len() as u64;
+        let mut reader = DecryptReader::new(Cursor::new(encrypted), key, len)?;
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    #[test]
+    fn round_trip() {
+        let key = new_key();
+        for size in [
+            0,
+            1,
+            CHUNK_SIZE - 1,
+            CHUNK_SIZE,
+            CHUNK_SIZE + 1,
+            2 * CHUNK_SIZE,
+        ] {
+            let content: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            let encrypted = encrypt(&key, &content);
+            // a chunk at least, even when there is no content
+            let chunks = size.div_ceil(CHUNK_SIZE).max(1);
+            assert_eq!(
+                encrypted.len(),
+                NONCE_PREFIX_SIZE + size + chunks * TAG_SIZE,
+                "{size} bytes"
+            );
+            assert_eq!(decrypt(&key, &encrypted).unwrap(), content, "{size} bytes");
+        }
+    }
+
+    #[test]
+    fn truncation_is_detected() {
+        let key = new_key();
+        let encrypted = encrypt(&key, &vec![7; CHUNK_SIZE * 2]);
+        let truncated = &encrypted[..NONCE_PREFIX_SIZE + CHUNK_SIZE + TAG_SIZE];
+        let err = decrypt(&key, truncated).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // without any chunk, not even the last one of an empty content
+        let err = decrypt(&key, &encrypted[..NONCE_PREFIX_SIZE]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn resumed_encryption() {
+        let key = new_key();
+        let content: Vec<u8> = (0..CHUNK_SIZE * 5 / 2).map(|i| (i % 251) as u8).collect();
+
+        // the first request stops in the middle of the second chunk, only
+        // the first one is kept
+        let mut encrypted = Vec::new();
+        let mut encryptor = Encryptor::new(&key);
+        encryptor
+            .update(&content[..CHUNK_SIZE + 10], &mut encrypted)
+            .unwrap();
+        encryptor.flush(&mut encrypted).unwrap();
+        let offset = encryptor.encrypted_offset();
+        assert_eq!(offset, CHUNK_SIZE as u64);
+        assert_eq!(encrypted.len() as u64, encrypted_size(offset));
+
+        let mut encryptor = Encryptor::resume(&key, &encrypted[..], offset).unwrap();
+        encryptor
+            .update(&content[offset as usize..], &mut encrypted)
+            .unwrap();
+        encryptor.finish(&mut encrypted).unwrap();
+        assert_eq!(decrypt(&key, &encrypted).unwrap(), content);
+
+        let err = Encryptor::resume(&key, &encrypted[..], offset + 1)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn flush_keeps_a_whole_chunk() {
+        // a chunk flushed once the content is a multiple of the chunk size
+        // isn't the last, the content can go on after it
+        let key = new_key();
+        let content = vec![3; CHUNK_SIZE * 2];
+        let mut encrypted = Vec::new();
+        let mut encryptor = Encryptor::new(&key);
+        encryptor.update(&content, &mut encrypted).unwrap();
+        encryptor.flush(&mut encrypted).unwrap();
+        assert_eq!(encryptor.encrypted_offset(), content.len() as u64);
+
+        let offset = encryptor.encrypted_offset();
+        let mut encryptor = Encryptor::resume(&key, &encrypted[..], offset).unwrap();
+        encryptor.update(b"more", &mut encrypted).unwrap();
+        encryptor.finish(&mut encrypted).unwrap();
+        let mut expected = content;
+        expected.extend_from_slice(b"more");
+        assert_eq!(decrypt(&key, &encrypted).unwrap(), expected);
+    }
+}
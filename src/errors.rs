@@ -14,7 +14,7 @@ pub enum VracError {
     DbError(#[from] diesel::result::Error),
 
     #[error("multipart decoding error {0:?}")]
-    MultipartError(#[from] multer::Error),
+    MultipartError(multer::Error),
 
     #[error("IO error")]
     IoError(#[from] std::io::Error),
@@ -22,16 +22,57 @@ pub enum VracError {
     #[error("Token already exists: {0}")]
     TokenAlreadyExists(String),
 
+    #[error("Invalid token path: {0:?}, only letters, digits, - and _ are allowed")]
+    InvalidTokenPath(String),
+
+    #[error("The max number of downloads must be at least 1")]
+    InvalidMaxDownloads,
+
     #[error("User already exists: {0}")]
     UserAlreadyExists(String),
 
     #[error("File size exceeded")]
     FileSizeExceeded,
 
+    #[error("File type not allowed for {0}")]
+    FileTypeNotAllowed(String),
+
+    #[error("No more files can be uploaded, the maximum is {0}")]
+    TooManyFiles(i32),
+
+    #[error("{0} was quarantined by the malware scanner")]
+    Quarantined(String),
+
+    #[error("{0} was refused, its metadata could not be removed")]
+    MetadataNotRemoved(String),
+
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
 
+impl From<multer::Error> for VracError {
+    fn from(err: multer::Error) -> Self {
+        if is_size_exceeded(&err) {
+            VracError::FileSizeExceeded
+        } else {
+            VracError::MultipartError(err)
+        }
+    }
+}
+
+/// the size errors raised by multer while reading a field are wrapped
+/// into a `StreamReadFailed`
+fn is_size_exceeded(err: &multer::Error) -> bool {
+    match err {
+        multer::Error::StreamSizeExceeded { .. } | multer::Error::FieldSizeExceeded { .. } => true,
+        multer::Error::StreamReadFailed(inner) => inner
+            .downcast_ref::<multer::Error>()
+            .map(is_size_exceeded)
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
 impl<'r> response::Responder<'r, 'static> for VracError {
     fn respond_to(self, _: &'r rocket::Request<'_>) -> response::Result<'static> {
         let (err_str, status) = match &self {
@@ -39,6 +80,17 @@ impl<'r> response::Responder<'r, 'static> for VracError {
                 let err_str = format!("Token already exists for path {}", tok);
                 (err_str, Status::BadRequest)
             },
+            VracError::InvalidTokenPath(_) => (format!("{}", self), Status::BadRequest),
+            VracError::InvalidMaxDownloads => (format!("{}", self), Status::BadRequest),
+            VracError::FileSizeExceeded => (format!("{}", self), Status::PayloadTooLarge),
+            VracError::TooManyFiles(_) => (format!("{}", self), Status::Forbidden),
+            VracError::Quarantined(_) => (format!("{}", self), Status::UnprocessableEntity),
+            VracError::MetadataNotRemoved(_) => {
+                (format!("{}", self), Status::UnprocessableEntity)
+            }
+            VracError::FileTypeNotAllowed(_) => {
+                (format!("{}", self), Status::UnsupportedMediaType)
+            }
             _ => {
                 log::error!("got a generic error! {:?}", self);
                 (format!("{:#?}", self), Status::InternalServerError)
@@ -2,7 +2,18 @@
 #[macro_use] extern crate diesel;
 #[macro_use] extern crate diesel_migrations;
 
+pub mod content_type;
 pub mod db;
 pub mod errors;
 pub mod schema;
+pub mod archive;
 pub mod cleanup;
+pub mod compression;
+pub mod encoding;
+pub mod encryption;
+pub mod preview;
+pub mod range;
+pub mod sanitize;
+pub mod scanner;
+pub mod storage;
+pub mod thumbnail;
@@ -0,0 +1,205 @@
+//! Preview of text files in the browser. The content is highlighted on the
+//! server, with the syntax found from the name or the detected type, so the
+//! page doesn't need any javascript. Content which isn't valid UTF-8 is
+//! shown as a hex dump instead.
+
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::html::{styled_line_to_highlighted_html, IncludeBackground};
+use syntect::parsing::{SyntaxReference, SyntaxSet};
+use syntect::util::LinesWithEndings;
+
+use crate::content_type;
+use crate::db::Compression;
+use crate::encoding;
+use crate::encryption::DataKey;
+use crate::storage::Storage;
+
+/// bigger files can only be downloaded
+pub const MAX_SIZE: u64 = 512 * 1024;
+/// only the start of binary content is shown
+const MAX_HEX_SIZE: usize = 64 * 1024;
+const HEX_LINE_SIZE: usize = 16;
+/// highlighting minified files line by line takes forever, they are
+/// shown as they are
+const MAX_HIGHLIGHTED_LINE: usize = 16 * 1024;
+const THEME: &str = "InspiredGitHub";
+
+/// the detected types which are text even when they aren't `text/*`
+const TEXT_TYPES: &[(&str, &str)] = &[
+    ("application/json", "json"),
+    ("application/xml", "xml"),
+    ("application/javascript", "js"),
+    ("application/x-sh", "sh"),
+];
+
+pub enum Preview {
+    /// each line as html, without the line break
+    Text(Vec<String>),
+    Hex {
+        lines: Vec<HexLine>,
+        /// only the start of the content is there
+        truncated: bool,
+    },
+}
+
+pub struct HexLine {
+    pub offset: String,
+    pub hex: String,
+    pub ascii: String,
+}
+
+/// The syntaxes and the theme, loaded once. Cheap to clone.
+#[derive(Clone)]
+pub struct Highlighter {
+    syntaxes: Arc<SyntaxSet>,
+    theme: Arc<Theme>,
+}
+
+impl Default for Highlighter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Highlighter {
+    pub fn new() -> Self {
+        let mut themes = ThemeSet::load_defaults();
+        let theme = themes
+            .themes
+            .remove(THEME)
+            .expect("the default themes have been changed");
+        Highlighter {
+            syntaxes: Arc::new(SyntaxSet::load_defaults_newlines()),
+            theme: Arc::new(theme),
+        }
+    }
+
+    /// Whether a file with the given name and detected content type can be
+    /// previewed: any text, and the source files detected as binary because
+    /// they aren't UTF-8. Their size is checked separately.
+    pub fn supports(&self, content_type: &str, name: &str) -> bool {
+        content_type::matches("text/*", content_type)
+            || TEXT_TYPES
+                .iter()
+                .any(|(ct, _)| content_type::matches(ct, content_type))
+            || self.syntax_for_name(name).is_some()
+    }
+
+    /// The preview of the given content, highlighted as the syntax found
+    /// from the name, then the first line, then the content type. Plain
+    /// text otherwise.
+    pub fn preview(&self, name: &str, content_type: &str, content: &[u8]) -> Preview {
+        let text = match std::str::from_utf8(content) {
+            Ok(text) => text,
+            Err(_) => return hex_dump(content),
+        };
+        let syntax = self
+            .syntax_for_name(name)
+            .or_else(|| self.syntaxes.find_syntax_by_first_line(text))
+            .or_else(|| {
+                TEXT_TYPES
+                    .iter()
+                    .find(|(ct, _)| content_type::matches(ct, content_type))
+                    .and_then(|(_, ext)| self.syntaxes.find_syntax_by_extension(ext))
+            })
+            .unwrap_or_else(|| self.syntaxes.find_syntax_plain_text());
+
+        let mut highlighter = HighlightLines::new(syntax, &self.theme);
+        let mut lines = Vec::new();
+        for line in LinesWithEndings::from(text) {
+            let html = if line.len() > MAX_HIGHLIGHTED_LINE {
+                None
+            } else {
+                highlighter
+                    .highlight_line(line, &self.syntaxes)
+                    .ok()
+                    .and_then(|regions| {
+                        let regions: Vec<_> = regions
+                            .into_iter()
+                            .map(|(style, s)| (style, s.trim_end_matches(['\r', '\n'])))
+                            .collect();
+                        styled_line_to_highlighted_html(&regions, IncludeBackground::No).ok()
+                    })
+            };
+            lines.push(html.unwrap_or_else(|| escape(line.trim_end_matches(['\r', '\n']))));
+        }
+        Preview::Text(lines)
+    }
+
+    fn syntax_for_name(&self, name: &str) -> Option<&SyntaxReference> {
+        let ext = Path::new(name).extension()?.to_str()?;
+        self.syntaxes.find_syntax_by_extension(ext)
+    }
+}
+
+/// The decoded content stored at `path`, at most [`MAX_SIZE`] bytes of it.
+/// This is blocking.
+pub fn read(
+    storage: &Storage,
+    path: &str,
+    key: Option<&DataKey>,
+    compression: Option<Compression>,
+) -> io::Result<Vec<u8>> {
+    let mut content = Vec::new();
+    encoding::decoder(storage.open(path)?, key, compression)?
+        .take(MAX_SIZE)
+        .read_to_end(&mut content)?;
+    Ok(content)
+}
+
+/// offset, bytes in hex and printable ascii, 16 bytes per line
+fn hex_dump(content: &[u8]) -> Preview {
+    let shown = &content[..content.len().min(MAX_HEX_SIZE)];
+    let lines = shown
+        .chunks(HEX_LINE_SIZE)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let mut hex = String::with_capacity(HEX_LINE_SIZE * 3 + 1);
+            for (j, byte) in chunk.iter().enumerate() {
+                if j == HEX_LINE_SIZE / 2 {
+                    hex.push(' ');
+                }
+                hex.push_str(&format!("{byte:02x} "));
+            }
+            let ascii = chunk
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            HexLine {
+                offset: format!("{:08x}", i * HEX_LINE_SIZE),
+                hex: hex.trim_end().to_string(),
+                ascii,
+            }
+        })
+        .collect();
+    Preview::Hex {
+        lines,
+        truncated: shown.len() < content.len(),
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '&' => out.push_str("&amp;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
@@ -0,0 +1,240 @@
+//! `Range` requests, so a video can be seeked before it's downloaded, and
+//! an interrupted download resumed. The ranges are over the content as it
+//! was uploaded, whatever the way it's stored.
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+
+/// more ranges than that in one request is most likely abuse, the whole
+/// content is sent instead
+const MAX_RANGES: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    /// inclusive, like in the headers
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn length(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// the value of the `Content-Range` header for this part of a content
+    /// of the given size
+    pub fn content_range(&self, size: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, size)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Ranges {
+    /// the header is malformed or asks for too much, and must be ignored
+    Full,
+    /// sorted, without overlaps
+    Partial(Vec<ByteRange>),
+    /// none of the ranges is in the content
+    Unsatisfiable,
+}
+
+/// the `Content-Range` header of a response to an unsatisfiable range
+pub fn unsatisfied_range(size: u64) -> String {
+    format!("bytes */{size}")
+}
+
+/// Parse the value of a `Range` header for a content of the given size.
+/// Overlapping and adjacent ranges are merged.
+pub fn parse(header: &str, size: u64) -> Ranges {
+    let specs = match header.trim().split_once('=') {
+        Some((unit, specs)) if unit.trim().eq_ignore_ascii_case("bytes") => specs,
+        _ => return Ranges::Full,
+    };
+    let mut ranges = Vec::new();
+    let specs: Vec<&str> = specs
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .collect();
+    if specs.is_empty() || specs.len() > MAX_RANGES {
+        return Ranges::Full;
+    }
+    for spec in specs {
+        let (first, last) = match spec.split_once('-') {
+            Some((first, last)) => (first.trim(), last.trim()),
+            None => return Ranges::Full,
+        };
+        let range = match (first, last) {
+            // the last bytes
+            ("", suffix) => match suffix.parse::<u64>() {
+                Ok(0) => None,
+                Ok(n) if size > 0 => Some(ByteRange {
+                    start: size.saturating_sub(n),
+                    end: size - 1,
+                }),
+                Ok(_) => None,
+                Err(_) => return Ranges::Full,
+            },
+            (first, last) => {
+                let start = match first.parse::<u64>() {
+                    Ok(start) => start,
+                    Err(_) => return Ranges::Full,
+                };
+                let end = match last {
+                    "" => u64::MAX,
+                    last => match last.parse::<u64>() {
+                        Ok(end) if end >= start => end,
+                        _ => return Ranges::Full,
+                    },
+                };
+                (start < size).then(|| ByteRange {
+                    start,
+                    end: end.min(size - 1),
+                })
+            }
+        };
+        ranges.extend(range);
+    }
+    if ranges.is_empty() {
+        return Ranges::Unsatisfiable;
+    }
+
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => {
+                last.end = last.end.max(range.end);
+            }
+            _ => merged.push(range),
+        }
+    }
+    Ranges::Partial(merged)
+}
+
+/// The framing of a `multipart/byteranges` body: each range comes with its
+/// own headers, and a delimiter closes the body.
+pub struct Multipart {
+    boundary: String,
+}
+
+impl Default for Multipart {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Multipart {
+    /// the boundary is random, so it cannot be guessed and put in a file
+    pub fn new() -> Self {
+        Multipart {
+            boundary: format!("vrac-{:016x}{:016x}", OsRng.next_u64(), OsRng.next_u64()),
+        }
+    }
+
+    pub fn content_type(&self) -> String {
+        format!("multipart/byteranges; boundary={}", self.boundary)
+    }
+
+    /// what comes before the content of a range
+    pub fn part_header(&self, content_type: &str, range: &ByteRange, size: u64) -> String {
+        format!(
+            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+            self.boundary,
+            content_type,
+            range.content_range(size)
+        )
+    }
+
+    /// what comes after the last range
+    pub fn end(&self) -> String {
+        format!("\r\n--{}--\r\n", self.boundary)
+    }
+
+    /// the size of the whole body, for the `Content-Length`
+    pub fn body_size(&self, content_type: &str, ranges: &[ByteRange], size: u64) -> u64 {
+        let parts: u64 = ranges
+            .iter()
+            .map(|r| self.part_header(content_type, r, size).len() as u64 + r.length())
+            .sum();
+        parts + self.end().len() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partial(ranges: &[(u64, u64)]) -> Ranges {
+        Ranges::Partial(
+            ranges
+                .iter()
+                .map(|&(start, end)| ByteRange { start, end })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn single_ranges() {
+        assert_eq!(parse("bytes=0-99", 1000), partial(&[(0, 99)]));
+        assert_eq!(parse("bytes=990-2000", 1000), partial(&[(990, 999)]));
+        assert_eq!(parse(" Bytes = 5 - 5 ", 1000), partial(&[(5, 5)]));
+    }
+
+    #[test]
+    fn suffix_ranges() {
+        assert_eq!(parse("bytes=-100", 1000), partial(&[(900, 999)]));
+        assert_eq!(parse("bytes=-2000", 1000), partial(&[(0, 999)]));
+        assert_eq!(parse("bytes=-0", 1000), Ranges::Unsatisfiable);
+    }
+
+    #[test]
+    fn open_ended_ranges() {
+        assert_eq!(parse("bytes=100-", 1000), partial(&[(100, 999)]));
+        assert_eq!(parse("bytes=0-", 1), partial(&[(0, 0)]));
+        assert_eq!(parse("bytes=1000-", 1000), Ranges::Unsatisfiable);
+    }
+
+    #[test]
+    fn overlapping_ranges_are_merged() {
+        assert_eq!(
+            parse("bytes=500-600,0-99,550-700,100-199,-10", 1000),
+            partial(&[(0, 199), (500, 700), (990, 999)])
+        );
+        // only some of them are in the content
+        assert_eq!(parse("bytes=2000-3000,10-19", 1000), partial(&[(10, 19)]));
+    }
+
+    #[test]
+    fn empty_content() {
+        assert_eq!(parse("bytes=0-", 0), Ranges::Unsatisfiable);
+        assert_eq!(parse("bytes=-10", 0), Ranges::Unsatisfiable);
+    }
+
+    #[test]
+    fn malformed_headers_are_ignored() {
+        for header in [
+            "",
+            "bytes=",
+            "bytes=,",
+            "items=0-10",
+            "bytes=10",
+            "bytes=a-b",
+            "bytes=20-10",
+            "bytes=0-10,x",
+        ] {
+            assert_eq!(parse(header, 1000), Ranges::Full, "{header:?}");
+        }
+    }
+
+    #[test]
+    fn too_many_ranges() {
+        let ranges: Vec<_> = (0..MAX_RANGES)
+            .map(|i| format!("{}-{}", i * 10, i * 10))
+            .collect();
+        let header = format!("bytes={}", ranges.join(","));
+        assert!(matches!(parse(&header, 1000), Ranges::Partial(r) if r.len() == MAX_RANGES));
+        let header = format!("{header},900-999");
+        assert_eq!(parse(&header, 1000), Ranges::Full);
+    }
+}
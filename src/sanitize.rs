@@ -0,0 +1,554 @@
+//! Remove the metadata of pictures: phones store where a photo was taken,
+//! and when, in the EXIF and XMP data. The pictures are rewritten without
+//! it, the pixels are untouched. Only the orientation is kept, otherwise
+//! photos taken sideways would be shown sideways.
+
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use image::metadata::Orientation;
+use sha2::{Digest, Sha256};
+
+use crate::db::{self, Compression};
+use crate::encoding::{self, Encoder};
+use crate::encryption::DataKey;
+use crate::storage::Storage;
+
+/// the whole picture is rewritten in memory, bigger ones are left as is
+const MAX_INPUT_SIZE: u64 = 256 * 1024 * 1024;
+
+/// whether the metadata can be removed from the given detected content type
+pub fn supports(content_type: &str) -> bool {
+    matches!(content_type, "image/jpeg" | "image/png" | "image/webp")
+}
+
+/// The picture without its metadata, `None` if it's not a well formed
+/// picture of the given type.
+pub fn strip(content_type: &str, content: &[u8]) -> Option<Vec<u8>> {
+    match content_type {
+        "image/jpeg" => strip_jpeg(content),
+        "image/png" => strip_png(content),
+        "image/webp" => strip_webp(content),
+        _ => None,
+    }
+}
+
+/// a picture rewritten without its metadata
+pub struct Sanitized {
+    pub size: u64,
+    /// hex encoded
+    pub sha256: String,
+    pub compression: Option<Compression>,
+    /// whether the original was written, it's not when there was no
+    /// metadata to remove.
+    pub kept_original: bool,
+}
+
+/// Rewrite the picture stored at `path` without its metadata, compressed
+/// and encrypted with `key` like it was. When `original` is given, the
+/// picture as uploaded is written there first, encrypted with its own key.
+/// Returns `None`, leaving the file untouched, if it's not a picture which
+/// can be rewritten. This is blocking.
+pub fn sanitize_file(
+    storage: &Storage,
+    path: &Path,
+    content_type: &str,
+    key: Option<&DataKey>,
+    compression: Option<Compression>,
+    compress: bool,
+    original: Option<(&Path, Option<&DataKey>)>,
+) -> io::Result<Option<Sanitized>> {
+    let mut content = Vec::new();
+    encoding::decoder(storage.open(path)?, key, compression)?
+        .take(MAX_INPUT_SIZE + 1)
+        .read_to_end(&mut content)?;
+    if content.len() as u64 > MAX_INPUT_SIZE {
+        log::warn!("{} is too big to remove its metadata", path.display());
+        return Ok(None);
+    }
+    let stripped = match strip(content_type, &content) {
+        Some(stripped) => stripped,
+        None => return Ok(None),
+    };
+    if stripped == content {
+        return Ok(Some(Sanitized {
+            size: content.len() as u64,
+            sha256: format!("{:x}", Sha256::digest(&content)),
+            compression,
+            kept_original: false,
+        }));
+    }
+
+    let kept_original = match original {
+        Some((original_path, original_key)) => {
+            write_encoded(storage, original_path, &content, false, original_key)?;
+            true
+        }
+        None => false,
+    };
+    let compression = write_encoded(storage, path, &stripped, compress, key)?;
+    Ok(Some(Sanitized {
+        size: stripped.len() as u64,
+        sha256: format!("{:x}", Sha256::digest(&stripped)),
+        compression,
+        kept_original,
+    }))
+}
+
+/// Replace the file at `path` with the given content, encoded. It's
+/// written aside and moved in place, the leftovers of a crash end with
+/// the partial extension and are removed on startup.
+fn write_encoded(
+    storage: &Storage,
+    path: &Path,
+    content: &[u8],
+    compress: bool,
+    key: Option<&DataKey>,
+) -> io::Result<Option<Compression>> {
+    let mut out = Vec::new();
+    let mut encoder = Encoder::new(compress, key);
+    encoder.update(content, &mut out)?;
+    let compression = encoder.finish(&mut out)?;
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(format!(".sanitized.{}", db::PARTIAL_EXTENSION));
+    let mut fd = storage.create(&tmp_path)?;
+    fd.write_all(&out)?;
+    fd.sync_all()?;
+    storage.rename(&tmp_path, path)?;
+    Ok(compression)
+}
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+
+/// Keeps what's needed to show the picture: the tables, the color profile
+/// and the Adobe segment. Everything after the end of the picture goes as
+/// well, phones put more pictures there, with their own metadata.
+fn strip_jpeg(content: &[u8]) -> Option<Vec<u8>> {
+    if !content.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut out = Vec::with_capacity(content.len());
+    out.extend_from_slice(&content[..2]);
+    let mut pos = 2;
+    loop {
+        if *content.get(pos)? != 0xFF {
+            return None;
+        }
+        // markers can be padded with any number of 0xFF
+        while *content.get(pos + 1)? == 0xFF {
+            pos += 1;
+        }
+        let marker = content[pos + 1];
+        match marker {
+            // end of image
+            0xD9 => {
+                out.extend_from_slice(&[0xFF, 0xD9]);
+                return Some(out);
+            }
+            // restart markers, without any length
+            0xD0..=0xD7 | 0x01 => {
+                out.extend_from_slice(&content[pos..pos + 2]);
+                pos += 2;
+                continue;
+            }
+            _ => (),
+        }
+        let len = u16::from_be_bytes([*content.get(pos + 2)?, *content.get(pos + 3)?]) as usize;
+        let end = pos + 2 + len;
+        let segment = content.get(pos..end)?;
+        let payload = segment.get(4..)?;
+        match marker {
+            0xE1 if payload.starts_with(EXIF_HEADER) => {
+                if let Some(tiff) = orientation_exif(&payload[EXIF_HEADER.len()..]) {
+                    let len = (2 + EXIF_HEADER.len() + tiff.len()) as u16;
+                    out.extend_from_slice(&[0xFF, 0xE1]);
+                    out.extend_from_slice(&len.to_be_bytes());
+                    out.extend_from_slice(EXIF_HEADER);
+                    out.extend_from_slice(&tiff);
+                }
+            }
+            // the color profile
+            0xE2 if payload.starts_with(b"ICC_PROFILE\0") => out.extend_from_slice(segment),
+            // how the colors are encoded, and the JFIF header
+            0xEE | 0xE0 => out.extend_from_slice(segment),
+            // any other application data and the comments
+            0xE1..=0xEF | 0xFE => (),
+            _ => out.extend_from_slice(segment),
+        }
+        pos = end;
+        if marker == 0xDA {
+            // the compressed data follows the start of scan, until the
+            // next marker which isn't a restart or an escaped 0xFF
+            let start = pos;
+            loop {
+                let byte = *content.get(pos)?;
+                if byte == 0xFF && !matches!(content.get(pos + 1)?, 0x00 | 0xD0..=0xD7) {
+                    break;
+                }
+                pos += 1;
+            }
+            out.extend_from_slice(&content[start..pos]);
+        }
+    }
+}
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Drops the text chunks (where XMP lives too), the modification time and
+/// the EXIF data.
+fn strip_png(content: &[u8]) -> Option<Vec<u8>> {
+    if !content.starts_with(PNG_SIGNATURE) {
+        return None;
+    }
+    let mut out = Vec::with_capacity(content.len());
+    out.extend_from_slice(PNG_SIGNATURE);
+    let mut pos = PNG_SIGNATURE.len();
+    loop {
+        let len = u32::from_be_bytes(content.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        let end = pos + 12 + len;
+        let chunk = content.get(pos..end)?;
+        let kind = &chunk[4..8];
+        match kind {
+            b"eXIf" => {
+                if let Some(tiff) = orientation_exif(&chunk[8..8 + len]) {
+                    out.extend_from_slice(&(tiff.len() as u32).to_be_bytes());
+                    let start = out.len();
+                    out.extend_from_slice(b"eXIf");
+                    out.extend_from_slice(&tiff);
+                    let crc = crc32fast::hash(&out[start..]);
+                    out.extend_from_slice(&crc.to_be_bytes());
+                }
+            }
+            b"tEXt" | b"zTXt" | b"iTXt" | b"tIME" => (),
+            _ => out.extend_from_slice(chunk),
+        }
+        if kind == b"IEND" {
+            return Some(out);
+        }
+        pos = end;
+    }
+}
+
+/// Drops the EXIF and XMP chunks. They can only be there with the extended
+/// format, whose header says which ones are present.
+fn strip_webp(content: &[u8]) -> Option<Vec<u8>> {
+    if content.get(..4)? != b"RIFF" || content.get(8..12)? != b"WEBP" {
+        return None;
+    }
+    let riff_len = u32::from_le_bytes(content[4..8].try_into().ok()?) as usize;
+    let content = content.get(..8 + riff_len)?;
+    let mut out = Vec::with_capacity(content.len());
+    out.extend_from_slice(&content[..12]);
+    let mut header_pos = None;
+    let mut exif = None;
+    let mut pos = 12;
+    while pos < content.len() {
+        let len = u32::from_le_bytes(content.get(pos + 4..pos + 8)?.try_into().ok()?) as usize;
+        // chunks are padded to an even size
+        let end = pos + 8 + len + len % 2;
+        let chunk = content.get(pos..end)?;
+        match &chunk[..4] {
+            b"EXIF" => {
+                let data = chunk.get(8..8 + len)?;
+                // some encoders keep the header of the jpeg segment
+                let data = data.strip_prefix(EXIF_HEADER).unwrap_or(data);
+                exif = orientation_exif(data);
+            }
+            b"XMP " => (),
+            kind => {
+                if kind == b"VP8X" && len >= 10 {
+                    header_pos = Some(out.len());
+                }
+                out.extend_from_slice(chunk);
+            }
+        }
+        pos = end;
+    }
+    if let Some(header_pos) = header_pos {
+        // the metadata chunks come after the picture
+        if let Some(tiff) = &exif {
+            out.extend_from_slice(b"EXIF");
+            out.extend_from_slice(&(tiff.len() as u32).to_le_bytes());
+            out.extend_from_slice(tiff);
+            if tiff.len() % 2 == 1 {
+                out.push(0);
+            }
+        }
+        const XMP_FLAG: u8 = 0x04;
+        const EXIF_FLAG: u8 = 0x08;
+        let flags = &mut out[header_pos + 8];
+        *flags &= !(XMP_FLAG | EXIF_FLAG);
+        if exif.is_some() {
+            *flags |= EXIF_FLAG;
+        }
+    }
+    let riff_len = (out.len() - 8) as u32;
+    out[4..8].copy_from_slice(&riff_len.to_le_bytes());
+    Some(out)
+}
+
+/// A minimal EXIF block with only the orientation found in the given one,
+/// `None` if the picture doesn't need to be rotated.
+fn orientation_exif(tiff: &[u8]) -> Option<Vec<u8>> {
+    let orientation = Orientation::from_exif_chunk(tiff)?;
+    if orientation == Orientation::NoTransforms {
+        return None;
+    }
+    let mut out = Vec::with_capacity(26);
+    // big endian, and the only directory right after the header
+    out.extend_from_slice(b"MM\0\x2A");
+    out.extend_from_slice(&8u32.to_be_bytes());
+    out.extend_from_slice(&1u16.to_be_bytes());
+    // tag, type SHORT, count, and the value padded to 4 bytes
+    out.extend_from_slice(&0x112u16.to_be_bytes());
+    out.extend_from_slice(&3u16.to_be_bytes());
+    out.extend_from_slice(&1u32.to_be_bytes());
+    out.extend_from_slice(&(orientation.to_exif() as u16).to_be_bytes());
+    out.extend_from_slice(&[0, 0]);
+    // no next directory
+    out.extend_from_slice(&0u32.to_be_bytes());
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, Rgb, RgbImage};
+
+    /// a little endian EXIF block, with the maker of the phone and the
+    /// given orientation
+    fn exif(orientation: u16) -> Vec<u8> {
+        let mut out = b"II\x2A\0".to_vec();
+        out.extend_from_slice(&8u32.to_le_bytes());
+        out.extend_from_slice(&2u16.to_le_bytes());
+        out.extend_from_slice(&0x10Fu16.to_le_bytes());
+        out.extend_from_slice(&2u16.to_le_bytes());
+        out.extend_from_slice(&4u32.to_le_bytes());
+        out.extend_from_slice(b"ab\0\0");
+        out.extend_from_slice(&0x112u16.to_le_bytes());
+        out.extend_from_slice(&3u16.to_le_bytes());
+        out.extend_from_slice(&1u32.to_le_bytes());
+        out.extend_from_slice(&orientation.to_le_bytes());
+        out.extend_from_slice(&[0, 0]);
+        out.extend_from_slice(&0u32.to_le_bytes());
+        out
+    }
+
+    fn picture(format: ImageFormat) -> Vec<u8> {
+        let mut out = io::Cursor::new(Vec::new());
+        RgbImage::from_pixel(2, 2, Rgb([200, 10, 10]))
+            .write_to(&mut out, format)
+            .unwrap();
+        out.into_inner()
+    }
+
+    fn jpeg_segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut out = vec![0xFF, marker];
+        out.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        out.extend_from_slice(payload);
+        out
+    }
+
+    fn png_chunk(kind: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut out = (data.len() as u32).to_be_bytes().to_vec();
+        out.extend_from_slice(kind);
+        out.extend_from_slice(data);
+        let crc = crc32fast::hash(&out[4..]);
+        out.extend_from_slice(&crc.to_be_bytes());
+        out
+    }
+
+    fn webp_chunk(kind: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut out = kind.to_vec();
+        out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        out.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            out.push(0);
+        }
+        out
+    }
+
+    fn riff(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let body = chunks.concat();
+        let mut out = b"RIFF".to_vec();
+        out.extend_from_slice(&(body.len() as u32 + 4).to_le_bytes());
+        out.extend_from_slice(b"WEBP");
+        out.extend_from_slice(&body);
+        out
+    }
+
+    fn with_exif_header(tiff: &[u8]) -> Vec<u8> {
+        [EXIF_HEADER, tiff].concat()
+    }
+
+    /// a JPEG with EXIF, XMP, a comment and a thumbnail after its end
+    fn jpeg_with_metadata(orientation: u16, trailer: &[u8]) -> Vec<u8> {
+        let picture = picture(ImageFormat::Jpeg);
+        [
+            &picture[..2],
+            &jpeg_segment(0xE1, &with_exif_header(&exif(orientation))),
+            &jpeg_segment(0xE1, b"http://ns.adobe.com/xap/1.0/\0<x:xmpmeta/>"),
+            &jpeg_segment(0xFE, b"taken at home"),
+            &picture[2..],
+            trailer,
+        ]
+        .concat()
+    }
+
+    /// a PNG with text chunks and EXIF between its header and its data
+    fn png_with_metadata(orientation: u16) -> Vec<u8> {
+        let picture = picture(ImageFormat::Png);
+        // the signature and the header chunk
+        let header_end = PNG_SIGNATURE.len() + 25;
+        [
+            &picture[..header_end],
+            &png_chunk(b"tEXt", b"Author\0someone"),
+            &png_chunk(b"iTXt", b"XML:com.adobe.xmp\0\0\0\0\0<x:xmpmeta/>"),
+            &png_chunk(b"eXIf", &exif(orientation)),
+            &picture[header_end..],
+        ]
+        .concat()
+    }
+
+    fn vp8x(flags: u8) -> Vec<u8> {
+        // the flags, 3 reserved bytes, then the width and height minus one
+        webp_chunk(b"VP8X", &[flags, 0, 0, 0, 1, 0, 0, 1, 0, 0])
+    }
+
+    /// the bitstream chunk of a lossless WebP
+    fn vp8l() -> Vec<u8> {
+        picture(ImageFormat::WebP)[12..].to_vec()
+    }
+
+    #[test]
+    fn orientation_only_exif() {
+        let tiff = orientation_exif(&exif(6)).unwrap();
+        assert_eq!(
+            Orientation::from_exif_chunk(&tiff),
+            Some(Orientation::Rotate90)
+        );
+        assert!(!tiff.windows(2).any(|w| w == b"ab"));
+        assert_eq!(orientation_exif(&exif(1)), None);
+        assert_eq!(orientation_exif(b"not an exif block"), None);
+    }
+
+    #[test]
+    fn jpeg() {
+        let picture = picture(ImageFormat::Jpeg);
+        let tiff = orientation_exif(&exif(6)).unwrap();
+        let expected = [
+            &picture[..2],
+            &jpeg_segment(0xE1, &with_exif_header(&tiff)),
+            &picture[2..],
+        ]
+        .concat();
+        let stripped = strip("image/jpeg", &jpeg_with_metadata(6, b"\xFF\xD8more")).unwrap();
+        assert_eq!(stripped, expected);
+        image::load_from_memory(&stripped).unwrap();
+
+        let stripped = strip("image/jpeg", &jpeg_with_metadata(1, b"")).unwrap();
+        assert_eq!(stripped, picture);
+        assert_eq!(strip("image/jpeg", &picture).unwrap(), picture);
+    }
+
+    #[test]
+    fn png() {
+        let picture = picture(ImageFormat::Png);
+        let tiff = orientation_exif(&exif(3)).unwrap();
+        let header_end = PNG_SIGNATURE.len() + 25;
+        let expected = [
+            &picture[..header_end],
+            &png_chunk(b"eXIf", &tiff),
+            &picture[header_end..],
+        ]
+        .concat();
+        let stripped = strip("image/png", &png_with_metadata(3)).unwrap();
+        assert_eq!(stripped, expected);
+        image::load_from_memory(&stripped).unwrap();
+
+        assert_eq!(strip("image/png", &png_with_metadata(1)).unwrap(), picture);
+    }
+
+    #[test]
+    fn webp() {
+        let tiff = orientation_exif(&exif(8)).unwrap();
+        let xmp = b"<x:xmpmeta/>\n";
+        let webp = riff(&[
+            vp8x(0x0C),
+            vp8l(),
+            webp_chunk(b"EXIF", &with_exif_header(&exif(8))),
+            webp_chunk(b"XMP ", xmp),
+        ]);
+        let expected = riff(&[vp8x(0x08), vp8l(), webp_chunk(b"EXIF", &tiff)]);
+        let stripped = strip("image/webp", &webp).unwrap();
+        assert_eq!(stripped, expected);
+        image::load_from_memory(&stripped).unwrap();
+
+        let webp = riff(&[
+            vp8x(0x0C),
+            vp8l(),
+            webp_chunk(b"EXIF", &exif(1)),
+            webp_chunk(b"XMP ", xmp),
+        ]);
+        let expected = riff(&[vp8x(0), vp8l()]);
+        assert_eq!(strip("image/webp", &webp).unwrap(), expected);
+
+        // the simple format can't have any metadata
+        let picture = picture(ImageFormat::WebP);
+        assert_eq!(strip("image/webp", &picture).unwrap(), picture);
+    }
+
+    #[test]
+    fn truncated() {
+        let webp = riff(&[vp8x(0x08), vp8l(), webp_chunk(b"EXIF", &exif(6))]);
+        for (content_type, content) in [
+            ("image/jpeg", jpeg_with_metadata(6, b"")),
+            ("image/png", png_with_metadata(6)),
+            ("image/webp", webp),
+        ] {
+            assert!(strip(content_type, &content).is_some());
+            for len in 0..content.len() {
+                assert_eq!(
+                    strip(content_type, &content[..len]),
+                    None,
+                    "{content_type} {len}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        let jpeg = picture(ImageFormat::Jpeg);
+        let png = picture(ImageFormat::Png);
+        let webp = picture(ImageFormat::WebP);
+        assert_eq!(strip("image/png", &jpeg), None);
+        assert_eq!(strip("image/jpeg", &png), None);
+        assert_eq!(strip("image/gif", &png), None);
+        assert_eq!(strip("image/webp", b"RIFF\0\0\0\0WEBX"), None);
+
+        // segments and chunks longer than the file, or too short to be read
+        for len in [0u16, 1, u16::MAX] {
+            let mut content = jpeg.clone();
+            content.splice(2..2, [0xFF, 0xE1]);
+            content.splice(4..4, len.to_be_bytes());
+            assert_eq!(strip("image/jpeg", &content), None, "{len}");
+        }
+        let mut content = jpeg.clone();
+        content.splice(2..2, [0x00, 0x01]);
+        assert_eq!(strip("image/jpeg", &content), None);
+
+        let mut content = png.clone();
+        content[PNG_SIGNATURE.len()..PNG_SIGNATURE.len() + 4]
+            .copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(strip("image/png", &content), None);
+
+        let mut content = webp.clone();
+        content[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(strip("image/webp", &content), None);
+        let mut content = webp;
+        content[16..20].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(strip("image/webp", &content), None);
+    }
+}
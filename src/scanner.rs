@@ -0,0 +1,324 @@
+//! Malware scanning of the uploaded files, before they can be downloaded.
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Clean,
+    /// with what the scanner said about it
+    Flagged(String),
+}
+
+pub trait Scanner: fmt::Debug + Send + Sync {
+    /// Blocking. An error, like a timeout, means the content couldn't be checked.
+    fn scan(&self, content: &mut (dyn Read + Send)) -> io::Result<Verdict>;
+}
+
+/// How to scan the files, in the `scanner` table of the configuration
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ScannerConfig {
+    /// see [`CommandScanner`]
+    Command {
+        command: Vec<String>,
+        #[serde(default = "default_timeout_secs")]
+        timeout_secs: u64,
+    },
+    /// see [`ClamdScanner`]
+    Clamd {
+        socket: PathBuf,
+        #[serde(default = "default_timeout_secs")]
+        timeout_secs: u64,
+    },
+}
+
+fn default_timeout_secs() -> u64 {
+    60
+}
+
+impl ScannerConfig {
+    pub fn build(&self) -> io::Result<Box<dyn Scanner>> {
+        match self {
+            ScannerConfig::Command {
+                command,
+                timeout_secs,
+            } => {
+                let (program, args) = command.split_first().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "Empty scanner command")
+                })?;
+                Ok(Box::new(CommandScanner {
+                    program: program.clone(),
+                    args: args.to_vec(),
+                    timeout: Duration::from_secs(*timeout_secs),
+                }))
+            }
+            ScannerConfig::Clamd {
+                socket,
+                timeout_secs,
+            } => Ok(Box::new(ClamdScanner {
+                socket: socket.clone(),
+                timeout: Duration::from_secs(*timeout_secs),
+            })),
+        }
+    }
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "Scan timed out")
+}
+
+/// Runs a local command with the content on its standard input, like
+/// `clamdscan --no-summary -`. Following clamav conventions, the command
+/// exits with 0 when the content is clean and 1 when it's flagged,
+/// anything else is an error.
+/// The command runs in its own process group, killed as a whole once it
+/// exits or times out: what it started could otherwise keep its output
+/// open, and the scan would wait for it forever.
+#[derive(Debug)]
+pub struct CommandScanner {
+    program: String,
+    args: Vec<String>,
+    timeout: Duration,
+}
+
+impl Scanner for CommandScanner {
+    fn scan(&self, content: &mut (dyn Read + Send)) -> io::Result<Verdict> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .process_group(0)
+            .spawn()?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+        let deadline = Instant::now() + self.timeout;
+
+        let (status, output) = std::thread::scope(|s| {
+            // the command may not read all its input, or stop reading when
+            // it's killed, so what happens to the content doesn't matter.
+            s.spawn(move || io::copy(content, &mut stdin));
+            let output = s.spawn(move || {
+                let mut output = String::new();
+                stdout.read_to_string(&mut output).map(|_| output)
+            });
+            let status = loop {
+                match child.try_wait() {
+                    Ok(Some(status)) => {
+                        kill_group(&child);
+                        break Ok(status);
+                    }
+                    Ok(None) if Instant::now() >= deadline => {
+                        log::error!("Killing {} after {:?}", self.program, self.timeout);
+                        kill_group(&child);
+                        let _ = child.wait();
+                        break Err(timed_out());
+                    }
+                    Ok(None) => std::thread::sleep(Duration::from_millis(50)),
+                    Err(err) => break Err(err),
+                }
+            };
+            (status, output.join())
+        });
+
+        let output = match output {
+            Ok(Ok(output)) => output.trim().to_string(),
+            _ => String::new(),
+        };
+        match status?.code() {
+            Some(0) => Ok(Verdict::Clean),
+            Some(1) if output.is_empty() => {
+                Ok(Verdict::Flagged(format!("flagged by {}", self.program)))
+            }
+            Some(1) => Ok(Verdict::Flagged(output)),
+            code => Err(io::Error::other(format!(
+                "{} failed with {code:?}: {output}",
+                self.program
+            ))),
+        }
+    }
+}
+
+/// kill the processes left in the group of the given command
+fn kill_group(child: &Child) {
+    // the group has the id of the command, see `process_group(0)`
+    // SAFETY: kill has no memory safety requirements
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+/// Talks to a clamd compatible daemon over its unix socket, streaming the
+/// content with the `INSTREAM` command.
+#[derive(Debug)]
+pub struct ClamdScanner {
+    socket: PathBuf,
+    timeout: Duration,
+}
+
+impl Scanner for ClamdScanner {
+    fn scan(&self, content: &mut (dyn Read + Send)) -> io::Result<Verdict> {
+        let deadline = Instant::now() + self.timeout;
+        let mut stream = UnixStream::connect(&self.socket)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        stream.write_all(b"zINSTREAM\0")?;
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            if Instant::now() >= deadline {
+                return Err(timed_out());
+            }
+            let n = content.read(&mut buf)?;
+            stream.write_all(&(n as u32).to_be_bytes())?;
+            if n == 0 {
+                break;
+            }
+            stream.write_all(&buf[..n])?;
+        }
+
+        let mut reply = Vec::new();
+        match BufReader::new(stream).read_until(0, &mut reply) {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Err(timed_out()),
+            result => result?,
+        };
+        let reply = String::from_utf8_lossy(&reply);
+        let reply = reply.trim_end_matches('\0');
+        // `stream: OK`, `stream: <signature> FOUND` or `<message> ERROR`
+        let reply = reply.trim().trim_start_matches("stream:").trim();
+        if reply == "OK" {
+            Ok(Verdict::Clean)
+        } else if let Some(signature) = reply.strip_suffix("FOUND") {
+            Ok(Verdict::Flagged(signature.trim().to_string()))
+        } else {
+            Err(io::Error::other(format!("clamd error: {reply}")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+
+    fn command_scanner(script: &str, timeout: Duration) -> CommandScanner {
+        CommandScanner {
+            program: "sh".to_string(),
+            args: vec!["-c".to_string(), script.to_string()],
+            timeout,
+        }
+    }
+
+    fn scan(scanner: &dyn Scanner, content: &[u8]) -> io::Result<Verdict> {
+        scanner.scan(&mut io::Cursor::new(content.to_vec()))
+    }
+
+    #[test]
+    fn command_clean() {
+        let scanner = command_scanner("cat > /dev/null", Duration::from_secs(10));
+        assert_eq!(scan(&scanner, b"hello").unwrap(), Verdict::Clean);
+    }
+
+    #[test]
+    fn command_flagged() {
+        let scanner = command_scanner(
+            "cat > /dev/null; echo 'stdin: Eicar-Signature FOUND'; exit 1",
+            Duration::from_secs(10),
+        );
+        assert_eq!(
+            scan(&scanner, b"X5O!P%@AP").unwrap(),
+            Verdict::Flagged("stdin: Eicar-Signature FOUND".to_string())
+        );
+    }
+
+    #[test]
+    fn command_error() {
+        let scanner = command_scanner("exit 2", Duration::from_secs(10));
+        assert!(scan(&scanner, b"hello").is_err());
+    }
+
+    #[test]
+    fn command_timeout() {
+        // the pipe makes sh start the commands in their own processes,
+        // holding the output open after sh is killed
+        let scanner = command_scanner("sleep 30 | cat", Duration::from_millis(500));
+        let start = Instant::now();
+        let err = scan(&scanner, b"hello").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn command_leaves_a_process_behind() {
+        let scanner = command_scanner("sleep 30 & exit 0", Duration::from_secs(10));
+        let start = Instant::now();
+        assert_eq!(scan(&scanner, b"hello").unwrap(), Verdict::Clean);
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    /// A fake clamd answering every `INSTREAM` with the given reply, once
+    /// the whole content is received. Returns what it received.
+    fn fake_clamd(name: &str, reply: &'static str) -> (PathBuf, std::thread::JoinHandle<Vec<u8>>) {
+        let socket = std::env::temp_dir().join(format!("vrac-{}-{name}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+        let handle = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut command = [0; 10];
+            stream.read_exact(&mut command).unwrap();
+            assert_eq!(&command, b"zINSTREAM\0");
+            let mut content = Vec::new();
+            loop {
+                let mut len = [0; 4];
+                stream.read_exact(&mut len).unwrap();
+                let len = u32::from_be_bytes(len) as usize;
+                if len == 0 {
+                    break;
+                }
+                let mut chunk = vec![0; len];
+                stream.read_exact(&mut chunk).unwrap();
+                content.extend_from_slice(&chunk);
+            }
+            stream.write_all(reply.as_bytes()).unwrap();
+            stream.write_all(b"\0").unwrap();
+            content
+        });
+        (socket, handle)
+    }
+
+    #[test]
+    fn clamd_clean() {
+        let (socket, handle) = fake_clamd("clean", "stream: OK");
+        let scanner = ClamdScanner {
+            socket: socket.clone(),
+            timeout: Duration::from_secs(10),
+        };
+        let content = vec![42; 100_000];
+        assert_eq!(scan(&scanner, &content).unwrap(), Verdict::Clean);
+        assert_eq!(handle.join().unwrap(), content);
+        std::fs::remove_file(socket).unwrap();
+    }
+
+    #[test]
+    fn clamd_flagged() {
+        let (socket, handle) = fake_clamd("flagged", "stream: Eicar-Signature FOUND");
+        let scanner = ClamdScanner {
+            socket: socket.clone(),
+            timeout: Duration::from_secs(10),
+        };
+        assert_eq!(
+            scan(&scanner, b"X5O!P%@AP").unwrap(),
+            Verdict::Flagged("Eicar-Signature".to_string())
+        );
+        handle.join().unwrap();
+        std::fs::remove_file(socket).unwrap();
+    }
+}
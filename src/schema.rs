@@ -1,3 +1,18 @@
+table! {
+    archive_entry (id) {
+        id -> Integer,
+        file_id -> Integer,
+        path -> Text,
+        size_bytes -> BigInt,
+        modified_at -> Nullable<Timestamp>,
+        is_dir -> Bool,
+        entry_offset -> BigInt,
+        packed_size -> BigInt,
+        method -> Nullable<Integer>,
+        crc32 -> Nullable<BigInt>,
+    }
+}
+
 table! {
     auth (id) {
         id -> Text,
@@ -6,6 +21,17 @@ table! {
     }
 }
 
+table! {
+    blob (sha256) {
+        sha256 -> Text,
+        path -> Text,
+        size_bytes -> BigInt,
+        created_at -> Timestamp,
+        compression -> Nullable<Text>,
+        wrapped_key -> Nullable<Text>,
+    }
+}
+
 table! {
     file (id) {
         id -> Integer,
@@ -13,10 +39,23 @@ table! {
         name -> Nullable<Text>,
         path -> Text,
         content_type -> Nullable<Text>,
-        size_mib -> Nullable<Integer>,
+        size_bytes -> Nullable<BigInt>,
         created_at -> Timestamp,
         deleted_at -> Nullable<Timestamp>,
         file_upload_status -> Text,
+        upload_length -> Nullable<BigInt>,
+        upload_offset -> Nullable<BigInt>,
+        sha256 -> Nullable<Text>,
+        blob_sha256 -> Nullable<Text>,
+        detected_content_type -> Nullable<Text>,
+        compression -> Nullable<Text>,
+        wrapped_key -> Nullable<Text>,
+        encrypted -> Bool,
+        quarantine_reason -> Nullable<Text>,
+        sanitized -> Bool,
+        original_path -> Nullable<Text>,
+        original_wrapped_key -> Nullable<Text>,
+        download_count -> Integer,
     }
 }
 
@@ -31,13 +70,22 @@ table! {
         content_expires_at -> Nullable<Timestamp>,
         content_expires_after_hours -> Nullable<Integer>,
         deleted_at -> Nullable<Timestamp>,
+        allowed_types -> Nullable<Text>,
+        max_files -> Nullable<Integer>,
+        strip_metadata -> Bool,
+        max_downloads -> Nullable<Integer>,
+        download_count -> Integer,
     }
 }
 
+joinable!(archive_entry -> file (file_id));
+joinable!(file -> blob (blob_sha256));
 joinable!(file -> token (token_id));
 
 allow_tables_to_appear_in_same_query!(
+    archive_entry,
     auth,
+    blob,
     file,
     token,
 );
@@ -0,0 +1,130 @@
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use cap_std::ambient_authority;
+use cap_std::fs::{Dir, OpenOptions, ReadDir};
+
+/// directory under the root path where content addressed blobs are stored
+pub const BLOBS_DIR: &str = "blobs";
+
+/// names which cannot be used for a token since vrac uses them itself
+const RESERVED_TOKEN_PATHS: &[&str] = &[BLOBS_DIR];
+
+const MAX_TOKEN_PATH_LEN: usize = 64;
+
+/// Token paths end up as directory names under the root path and in urls,
+/// so only a boring set of characters is accepted.
+pub fn is_valid_token_path(path: &str) -> bool {
+    !path.is_empty()
+        && path.len() <= MAX_TOKEN_PATH_LEN
+        && path
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        && !RESERVED_TOKEN_PATHS.contains(&path)
+}
+
+/// Access to the files stored under the root path. Everything goes through
+/// a capability handle on the root directory, so whatever path is stored in
+/// the DB, nothing outside of the root can be read or deleted: absolute
+/// paths, `..` or symlinks leading outside are rejected by the OS.
+#[derive(Debug, Clone)]
+pub struct Storage {
+    root_path: PathBuf,
+    dir: Arc<Dir>,
+}
+
+impl Storage {
+    /// open the root directory, creating it if needed
+    pub fn new(root_path: &Path) -> io::Result<Self> {
+        std::fs::create_dir_all(root_path)?;
+        let dir = Dir::open_ambient_dir(root_path, ambient_authority())?;
+        Ok(Self {
+            root_path: root_path.to_path_buf(),
+            dir: Arc::new(dir),
+        })
+    }
+
+    pub fn root_path(&self) -> &Path {
+        &self.root_path
+    }
+
+    /// Paths are stored relative to the root path, but older rows have
+    /// it as a prefix, strip it so they can be opened from the root.
+    pub fn relative<'a>(&self, path: &'a Path) -> &'a Path {
+        path.strip_prefix(&self.root_path).unwrap_or(path)
+    }
+
+    pub fn create_dir_all(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.dir.create_dir_all(self.relative(path.as_ref()))
+    }
+
+    pub fn open(&self, path: impl AsRef<Path>) -> io::Result<File> {
+        let file = self.dir.open(self.relative(path.as_ref()))?;
+        Ok(file.into_std())
+    }
+
+    /// open a file for writing, creating it or truncating it if it exists
+    pub fn create(&self, path: impl AsRef<Path>) -> io::Result<File> {
+        let file = self.dir.create(self.relative(path.as_ref()))?;
+        Ok(file.into_std())
+    }
+
+    /// open an existing file for writing, without truncating it
+    pub fn open_for_write(&self, path: impl AsRef<Path>) -> io::Result<File> {
+        let mut options = OpenOptions::new();
+        options.write(true);
+        let file = self.dir.open_with(self.relative(path.as_ref()), &options)?;
+        Ok(file.into_std())
+    }
+
+    pub fn rename(&self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> io::Result<()> {
+        self.dir.rename(
+            self.relative(from.as_ref()),
+            &self.dir,
+            self.relative(to.as_ref()),
+        )
+    }
+
+    pub fn remove_file(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.dir.remove_file(self.relative(path.as_ref()))
+    }
+
+    pub fn remove_dir_all(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.dir.remove_dir_all(self.relative(path.as_ref()))
+    }
+
+    /// list a directory, the root itself is listed with an empty path
+    pub fn read_dir(&self, path: impl AsRef<Path>) -> io::Result<ReadDir> {
+        let path = self.relative(path.as_ref());
+        if path.as_os_str().is_empty() {
+            self.dir.entries()
+        } else {
+            self.dir.read_dir(path)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_token_paths() {
+        for path in ["a", "holidays-2021", "Z_9", &"a".repeat(MAX_TOKEN_PATH_LEN)] {
+            assert!(is_valid_token_path(path), "{path:?}");
+        }
+    }
+
+    #[test]
+    fn invalid_token_paths() {
+        let too_long = "a".repeat(MAX_TOKEN_PATH_LEN + 1);
+        for path in [
+            "", ".", "..", "../etc", "a/b", "a\\b", "/tmp", "a b", "a.b", "a\0", "été", "blobs",
+            &too_long,
+        ] {
+            assert!(!is_valid_token_path(path), "{path:?}");
+        }
+    }
+}
@@ -0,0 +1,120 @@
+//! Small previews of the uploaded pictures, for the list of files. They are
+//! cached on disk next to the content they come from, and stored like it:
+//! encrypted with the same key if it is.
+
+use std::ffi::OsString;
+use std::io::{self, Cursor, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, Rgb, RgbImage};
+
+use crate::db::{self, Compression};
+use crate::encoding::{self, Encoder};
+use crate::encryption::DataKey;
+use crate::storage::Storage;
+
+/// thumbnails fit in a square of this size, in pixels
+const SIZE: u32 = 256;
+const JPEG_QUALITY: u8 = 80;
+/// the whole picture is decoded in memory, bigger ones get no thumbnail
+const MAX_INPUT_SIZE: u64 = 64 * 1024 * 1024;
+
+/// whether a thumbnail can be made for the given detected content type
+pub fn supports(content_type: &str) -> bool {
+    ImageFormat::from_mime_type(content_type).is_some_and(|f| f.reading_enabled())
+}
+
+/// where the thumbnail of the content stored at `path` is cached
+pub fn path_for(path: &str) -> PathBuf {
+    let mut p = OsString::from(path);
+    p.push(".thumb");
+    p.into()
+}
+
+/// Cache the thumbnail of the picture stored at `path`, if it isn't there
+/// already. This is blocking.
+pub fn generate(
+    storage: &Storage,
+    path: &str,
+    key: Option<&DataKey>,
+    compression: Option<Compression>,
+) -> io::Result<()> {
+    let thumb_path = path_for(path);
+    if storage.open(&thumb_path).is_ok() {
+        return Ok(());
+    }
+
+    let mut content = Vec::new();
+    encoding::decoder(storage.open(path)?, key, compression)?
+        .take(MAX_INPUT_SIZE + 1)
+        .read_to_end(&mut content)?;
+    if content.len() as u64 > MAX_INPUT_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Picture too big for a thumbnail",
+        ));
+    }
+    let jpeg = thumbnail(content).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    let mut out = Vec::new();
+    let mut encoder = Encoder::new(false, key);
+    encoder.update(&jpeg, &mut out)?;
+    encoder.finish(&mut out)?;
+
+    // the thumbnail may be requested while it's generated after the upload,
+    // each one is written to its own file before being moved in place.
+    // Leftovers are removed with the other partial files on startup.
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut tmp_path = thumb_path.clone().into_os_string();
+    tmp_path.push(format!(
+        ".{}.{}",
+        COUNTER.fetch_add(1, Ordering::Relaxed),
+        db::PARTIAL_EXTENSION
+    ));
+    let mut fd = storage.create(&tmp_path)?;
+    fd.write_all(&out)?;
+    fd.sync_all()?;
+    storage.rename(&tmp_path, &thumb_path)
+}
+
+/// the cached thumbnail of the content stored at `path`, a jpeg picture
+pub fn read(storage: &Storage, path: &str, key: Option<&DataKey>) -> io::Result<Vec<u8>> {
+    let mut jpeg = Vec::new();
+    encoding::decoder(storage.open(path_for(path))?, key, None)?.read_to_end(&mut jpeg)?;
+    Ok(jpeg)
+}
+
+/// remove the cached thumbnail of the content stored at `path`, if any
+pub fn remove(storage: &Storage, path: &str) -> io::Result<()> {
+    match storage.remove_file(path_for(path)) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        r => r,
+    }
+}
+
+fn thumbnail(content: Vec<u8>) -> image::ImageResult<Vec<u8>> {
+    let mut decoder = ImageReader::new(Cursor::new(content))
+        .with_guessed_format()?
+        .into_decoder()?;
+    // phones store pictures sideways, with the rotation in the exif data
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+
+    let mut jpeg = Vec::new();
+    flatten(&image.thumbnail(SIZE, SIZE))
+        .write_with_encoder(JpegEncoder::new_with_quality(&mut jpeg, JPEG_QUALITY))?;
+    Ok(jpeg)
+}
+
+/// jpeg has no transparency, transparent pixels become white
+fn flatten(image: &DynamicImage) -> RgbImage {
+    let rgba = image.to_rgba8();
+    RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let blend = |c: u8| ((c as u16 * a as u16 + 255 * (255 - a as u16)) / 255) as u8;
+        Rgb([blend(r), blend(g), blend(b)])
+    })
+}